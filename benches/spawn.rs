@@ -0,0 +1,44 @@
+//! Compares the time to start a proc with each spawn backend, as the parent's
+//! memory grows.  Run with `cargo bench --bench spawn`.
+
+use ir::environ;
+use ir::err_pipe::new_err_pipe;
+use ir::spawn::{spawn, Backend, Plan};
+use ir::spec;
+use ir::sys;
+use std::time::{Duration, Instant};
+
+const NUM_SPAWNS: u32 = 200;
+
+fn time_spawns(backend: Backend, plan: &Plan) -> Duration {
+    let (_err_read, err_write) = new_err_pipe().unwrap();
+    let start = Instant::now();
+    for _ in 0 .. NUM_SPAWNS {
+        let pid = spawn(backend, plan, &err_write).unwrap();
+        sys::wait4(pid, true).unwrap();
+    }
+    start.elapsed() / NUM_SPAWNS
+}
+
+fn main() {
+    let env = environ::build(std::env::vars(), &spec::Env::default());
+    let plan = Plan::new(0, &["/bin/true".to_string()], &env).unwrap();
+
+    let mut backends = vec![Backend::Fork];
+    #[cfg(target_os = "linux")]
+    backends.push(Backend::Vfork);
+
+    println!("{:>10} {:>8} {:>12}", "memory", "backend", "per spawn");
+    for &mb in &[0usize, 64, 512, 2048] {
+        // Stands in for large capture buffers.  Fill it, so the pages are
+        // actually mapped.
+        let ballast = vec![1u8; mb << 20];
+        for &backend in &backends {
+            let elapsed = time_spawns(backend, &plan);
+            println!(
+                "{:>7} MB {:>8} {:>9} µs",
+                mb, format!("{:?}", backend), elapsed.as_micros());
+        }
+        drop(ballast);
+    }
+}
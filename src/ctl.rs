@@ -0,0 +1,276 @@
+//! A control server on a Unix domain socket, for querying and signaling procs
+//! while they run.
+//!
+//! A client sends requests, each a JSON object on its own line, and receives a
+//! response to each, also a JSON object on its own line.  The `cmd` key selects
+//! the request:
+//!
+//! - `{"cmd": "list"}` returns a snapshot of all procs, as in a state file
+//! - `{"cmd": "output", "proc": proc, "fd": fd}` returns the output captured
+//!   from an fd of a proc so far; `fd` defaults to "stdout"
+//! - `{"cmd": "signal", "proc": proc, "signal": signal}` sends a signal to a
+//!   proc; `signal` defaults to "SIGTERM"
+//! - `{"cmd": "cancel", "signal": signal}` cancels the run
+//!
+//! A proc is given by index or by name, and a signal by number or by name.
+//! Each response has `"ok": true` and other keys, or `"ok": false` and an
+//! `error` message.
+
+use crate::spec::Signal;
+use crate::sys::fd_t;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+//------------------------------------------------------------------------------
+
+/// A proc, by index or by name.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum ProcRef {
+    Index(usize),
+    Name(String),
+}
+
+fn default_fd() -> String {
+    "stdout".to_string()
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Request {
+    List,
+    Output {
+        proc: ProcRef,
+        #[serde(default = "default_fd")]
+        fd: String,
+    },
+    Signal {
+        proc: ProcRef,
+        #[serde(default)]
+        signal: Signal,
+    },
+    Cancel {
+        #[serde(default)]
+        signal: Signal,
+    },
+}
+
+/// Returns a successful response, with `value`'s keys added.
+pub fn ok_response(value: Value) -> Value {
+    let mut response = json!({"ok": true});
+    if let (Value::Object(response), Value::Object(value)) = (&mut response, value) {
+        response.extend(value);
+    }
+    response
+}
+
+pub fn err_response(msg: &str) -> Value {
+    json!({"ok": false, "error": msg})
+}
+
+//------------------------------------------------------------------------------
+
+/// Bytes of responses a client may leave unread, before it's disconnected.
+const MAX_QUEUED: usize = 16 << 20;
+
+struct Conn {
+    stream: UnixStream,
+    /// Input not yet ending in a newline.
+    buf: Vec<u8>,
+    /// Responses not yet written.
+    out: Vec<u8>,
+    /// True if the client isn't reading responses, and should be dropped.
+    stalled: bool,
+}
+
+pub struct Server {
+    path: PathBuf,
+    listener: UnixListener,
+    conns: BTreeMap<fd_t, Conn>,
+}
+
+impl Server {
+    /// Listens on a new socket at `path`.  The socket is removed when the server
+    /// is dropped.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { path: path.to_path_buf(), listener, conns: BTreeMap::new() })
+    }
+
+    /// Returns the listening fd, which is readable when a client connects.
+    pub fn get_fd(&self) -> fd_t {
+        self.listener.as_raw_fd()
+    }
+
+    /// Accepts pending connections, and returns their fds, to select.
+    pub fn accept(&mut self) -> Vec<fd_t> {
+        let mut fds = Vec::new();
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                let fd = stream.as_raw_fd();
+                self.conns.insert(
+                    fd, Conn { stream, buf: Vec::new(), out: Vec::new(), stalled: false });
+                fds.push(fd);
+            }
+        }
+        fds
+    }
+
+    /// Reads from connection `fd`, and returns complete requests, or error
+    /// messages for those that are invalid.  Also returns true if the client
+    /// closed the connection, after which `close()` should be called.
+    pub fn read(&mut self, fd: fd_t) -> (Vec<Result<Request, String>>, bool) {
+        let conn = match self.conns.get_mut(&fd) {
+            Some(conn) => conn,
+            None => return (Vec::new(), true),
+        };
+
+        let mut closed = false;
+        let mut buf = [0u8; 4096];
+        loop {
+            match conn.stream.read(&mut buf) {
+                Ok(0) => { closed = true; break; },
+                Ok(n) => conn.buf.extend_from_slice(&buf[.. n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => { closed = true; break; },
+            }
+        }
+
+        let mut requests = Vec::new();
+        while let Some(pos) = conn.buf.iter().position(|&b| b == b'\n') {
+            let line = conn.buf.drain(.. pos + 1).collect::<Vec<_>>();
+            let line = &line[.. pos];
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            requests.push(serde_json::from_slice(line).map_err(|err| err.to_string()));
+        }
+        (requests, closed)
+    }
+
+    /// Queues a response on connection `fd`.  Call `flush()` to send it.
+    pub fn respond(&mut self, fd: fd_t, response: &Value) {
+        if let Some(conn) = self.conns.get_mut(&fd) {
+            if conn.out.len() > MAX_QUEUED {
+                // The client isn't keeping up with its own requests.
+                conn.stalled = true;
+                return;
+            }
+            serde_json::to_writer(&mut conn.out, response).unwrap();
+            conn.out.push(b'\n');
+        }
+    }
+
+    /// Writes as much queued output to connection `fd` as it accepts without
+    /// blocking.  Returns true if output remains, in which case this should
+    /// be called again when `fd` is writable.  Returns an error if the client
+    /// has gone away or stalled, after which `close()` should be called.
+    pub fn flush(&mut self, fd: fd_t) -> io::Result<bool> {
+        let conn = self.conns.get_mut(&fd)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        if conn.stalled {
+            return Err(io::Error::other("client stalled"));
+        }
+        let mut written = 0;
+        while written < conn.out.len() {
+            match conn.stream.write(&conn.out[written ..]) {
+                Ok(n) => written += n,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        conn.out.drain(.. written);
+        Ok(!conn.out.is_empty())
+    }
+
+    /// Closes connection `fd`.
+    pub fn close(&mut self, fd: fd_t) {
+        self.conns.remove(&fd);
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_requests() {
+        let parse = |s| serde_json::from_str::<Request>(s).unwrap();
+        assert_eq!(parse(r#"{"cmd": "list"}"#), Request::List);
+        assert_eq!(
+            parse(r#"{"cmd": "output", "proc": 1}"#),
+            Request::Output { proc: ProcRef::Index(1), fd: "stdout".to_string() });
+        assert_eq!(
+            parse(r#"{"cmd": "signal", "proc": "server", "signal": "HUP"}"#),
+            Request::Signal {
+                proc: ProcRef::Name("server".to_string()),
+                signal: Signal::Name("HUP".to_string()),
+            });
+        assert_eq!(
+            parse(r#"{"cmd": "cancel"}"#),
+            Request::Cancel { signal: Signal::Num(libc::SIGTERM) });
+        assert!(serde_json::from_str::<Request>(r#"{"cmd": "reboot"}"#).is_err());
+    }
+
+    #[test]
+    fn slow_client() {
+        let dir = std::env::temp_dir().join(format!("ir-ctl-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ctl.sock");
+        let mut server = Server::bind(&path).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        let fd = server.accept()[0];
+
+        // Responses queue up, rather than blocking, while the client doesn't
+        // read them.
+        let response = json!({"ok": true, "data": "x".repeat(65536)});
+        let mut pending = false;
+        for _ in 0 .. 16 {
+            server.respond(fd, &response);
+            pending = server.flush(fd).unwrap();
+        }
+        assert!(pending);
+
+        // Once the client reads, the rest are flushed.
+        let expected = (response.to_string() + "\n").repeat(16);
+        let reader = std::thread::spawn(move || {
+            let mut buf = vec![0u8; expected.len()];
+            client.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, expected.as_bytes());
+            client
+        });
+        while server.flush(fd).unwrap() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let _client = reader.join().unwrap();
+
+        // A client that never reads is eventually dropped.
+        loop {
+            server.respond(fd, &response);
+            match server.flush(fd) {
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+
+        drop(server);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
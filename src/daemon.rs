@@ -0,0 +1,68 @@
+//! Detaching from the caller, to run procs in the background.
+
+use crate::fdio;
+use crate::sys;
+use libc::pid_t;
+use std::io;
+use std::path::Path;
+
+//------------------------------------------------------------------------------
+
+fn to_io_error(err: crate::err::Error) -> io::Error {
+    match err {
+        crate::err::Error::Io(err) => err,
+        err => io::Error::other(err.to_string()),
+    }
+}
+
+/// Redirects stdin, stdout, and stderr to /dev/null.
+fn detach_stdio() -> io::Result<()> {
+    let fd = sys::open(Path::new("/dev/null"), libc::O_RDWR, 0)?;
+    for std_fd in 0 .. 3 {
+        sys::dup2(fd, std_fd)?;
+    }
+    if fd > 2 {
+        sys::close(fd)?;
+    }
+    Ok(())
+}
+
+/// Starts a daemon process, by forking twice, so that it is in a new session
+/// without a controlling terminal, and isn't a child of the caller.  Its stdin,
+/// stdout, and stderr are /dev/null.
+///
+/// Returns the daemon's pid in the calling process, and `None` in the daemon.
+/// Call before starting threads.
+pub fn daemonize() -> io::Result<Option<pid_t>> {
+    // The daemon sends its pid back on this pipe.
+    let (read_fd, write_fd) = sys::pipe()?;
+
+    let child_pid = sys::fork()?;
+    if child_pid == 0 {
+        // Intermediate child.  Leave the caller's session and terminal, then
+        // fork the daemon, which can't acquire a terminal as it's not a session
+        // leader.
+        let _ = sys::close(read_fd);
+        let daemon_pid = match sys::setsid().and_then(|_| sys::fork()) {
+            Ok(pid) => pid,
+            Err(_) => unsafe { libc::_exit(exitcode::OSERR) },
+        };
+        if daemon_pid != 0 {
+            unsafe { libc::_exit(exitcode::OK) };
+        }
+
+        // The daemon.
+        fdio::write_usize(write_fd, sys::getpid() as usize).map_err(to_io_error)?;
+        sys::close(write_fd)?;
+        detach_stdio()?;
+        Ok(None)
+    }
+    else {
+        sys::close(write_fd)?;
+        let daemon_pid = fdio::read_usize(read_fd);
+        sys::close(read_fd)?;
+        // Clean up the intermediate child, which exits right away.
+        sys::wait4(child_pid, true)?;
+        Ok(Some(daemon_pid.map_err(to_io_error)? as pid_t))
+    }
+}
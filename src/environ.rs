@@ -1,4 +1,5 @@
 use crate::spec;
+use regex::Regex;
 use std::collections::BTreeMap;
 
 //------------------------------------------------------------------------------
@@ -7,16 +8,117 @@ pub type Env = BTreeMap<String, String>;  // FIXME: Use OsString instead?
 
 //------------------------------------------------------------------------------
 
-pub fn build(start_env: std::env::Vars, spec: &spec::Env) -> Env {
-    start_env.filter(|(env_var, _)| {
-        match &spec.inherit {
-            spec::EnvInherit::None => false,
-            spec::EnvInherit::All => true,
-            spec::EnvInherit::Vars(vars) => vars.contains(env_var),
+/// Returns a regex for a glob pattern, in which `*` matches any characters,
+/// `?` any one character, and `[...]` or `[!...]` one in or not in a set.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' if chars.clone().any(|c| c == ']') => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                for c in chars.by_ref().take_while(|&c| c != ']') {
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            },
+            _ => regex.push_str(&regex::escape(&c.to_string())),
         }
-    })
-        .chain((&spec.vars).into_iter().map(|(n, v)| (n.clone(), v.clone())))
-        .collect()
+    }
+    regex.push('$');
+    regex
+}
+
+/// Compiles a pattern for env var names.  A pattern enclosed in slashes is a
+/// regex, which may match any part of a name; otherwise, it's a glob pattern,
+/// which must match the whole name.
+pub fn compile_pattern(pattern: &str) -> Result<Regex, String> {
+    let regex = match pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+        Some(regex) => regex.to_string(),
+        None => glob_to_regex(pattern),
+    };
+    Regex::new(&regex).map_err(|err| format!("bad env var pattern {:?}: {}", pattern, err))
+}
+
+fn compile_patterns(patterns: &[String]) -> Vec<Regex> {
+    // The spec is validated, so the patterns are too.
+    patterns.iter().filter_map(|p| compile_pattern(p).ok()).collect()
+}
+
+/// Substitutes inherited env vars into an env var value; one that isn't
+/// inherited is empty.
+fn resolve(value: &str, inherited: &Env) -> String {
+    crate::vars::substitute(value, |name| Ok(inherited.get(name).cloned().unwrap_or_default()))
+        // Expanding the spec checks syntax.
+        .unwrap_or_else(|_: String| value.to_string())
+}
+
+/// Returns a colon-separated list of `prepend`, the entries of `value`, and
+/// `append`, without duplicate entries.  Of duplicates, the first is kept.  An
+/// empty entry, which in PATH means the current directory, is kept too, but an
+/// empty value or list has no entries.
+fn extend_list(value: Option<&String>, prepend: &[String], append: &[String]) -> String {
+    let mut entries: Vec<&str> = Vec::new();
+    for entry in prepend.iter()
+        .chain(value)
+        .chain(append.iter())
+        .filter(|e| !e.is_empty())
+        .flat_map(|e| e.split(':')) {
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+    entries.join(":")
+}
+
+/// Builds a proc's environment from `start_env`, usually that of this process.
+pub fn build<I>(start_env: I, spec: &spec::Env) -> Env
+where I: IntoIterator<Item = (String, String)>
+{
+    let inherit = match &spec.inherit {
+        spec::EnvInherit::Vars(patterns) => compile_patterns(patterns),
+        _ => Vec::new(),
+    };
+    let exclude = compile_patterns(&spec.exclude);
+    let inherited = start_env.into_iter()
+        .filter(|(env_var, _)| {
+            let inherited = match &spec.inherit {
+                spec::EnvInherit::None => false,
+                spec::EnvInherit::All => true,
+                spec::EnvInherit::Vars(_) =>
+                    inherit.iter().any(|r| r.is_match(env_var)),
+            };
+            inherited && !exclude.iter().any(|r| r.is_match(env_var))
+        })
+        .collect::<Env>();
+    let mut env = inherited.clone();
+    for (name, value) in &spec.vars {
+        match value {
+            Some(value) => env.insert(name.clone(), resolve(value, &inherited)),
+            None => env.remove(name),
+        };
+    }
+
+    // Entries may also refer to inherited env vars, like values.
+    let resolve_all = |entries: Option<&Vec<String>>| entries.into_iter().flatten()
+        .map(|e| resolve(e, &inherited))
+        .collect::<Vec<_>>();
+    for name in spec.prepend.keys().chain(spec.append.keys()) {
+        let prepend = resolve_all(spec.prepend.get(name));
+        let append = resolve_all(spec.append.get(name));
+        let value = extend_list(env.get(name), &prepend, &append);
+        env.insert(name.clone(), value);
+    }
+    env
 }
 
 //------------------------------------------------------------------------------
@@ -66,16 +168,112 @@ mod tests {
     #[test]
     fn vars() {
         assert_json(
-            r#" {"vars": {"FOO": "42", "BAR": "somewhere with drinks"}} "#,
+            r#" {"vars": {"FOO": "42", "BAR": "somewhere with drinks", "BAZ": null}} "#,
             spec::Env {
                 vars: btreemap!{
-                    "FOO".to_string() => "42".to_string(),
-                    "BAR".to_string() => "somewhere with drinks".to_string(),
+                    "FOO".to_string() => Some("42".to_string()),
+                    "BAR".to_string() => Some("somewhere with drinks".to_string()),
+                    "BAZ".to_string() => Option::None,
                 },
                 ..Default::default()
             }
         );
     }
 
+    #[test]
+    fn exclude() {
+        assert_json(
+            r#" {"exclude": ["AWS_*"]} "#,
+            spec::Env { exclude: vec!["AWS_*".to_string()], ..Default::default() }
+        );
+    }
+
+    #[test]
+    fn patterns() {
+        let matches = |pattern, name| compile_pattern(pattern).unwrap().is_match(name);
+        assert!(matches("HOME", "HOME"));
+        assert!(!matches("HOME", "HOMER"));
+        assert!(matches("LC_*", "LC_ALL"));
+        assert!(!matches("LC_*", "XLC_ALL"));
+        assert!(matches("CI_?D", "CI_ID"));
+        assert!(matches("[A-C]_[!0-9]", "B_X"));
+        assert!(!matches("[A-C]_[!0-9]", "B_1"));
+        assert!(matches("A.B+[", "A.B+["));
+        assert!(matches("/^SSH_|TOKEN/", "GITHUB_TOKEN_RO"));
+        assert!(compile_pattern("/(/").is_err());
+    }
+
+    #[test]
+    fn build_env() {
+        let start_env = || vec![
+            ("HOME", "/home/me"),
+            ("LC_ALL", "C"),
+            ("LC_TIME", "C"),
+            ("AWS_SECRET", "xyzzy"),
+            ("PATH", "/bin"),
+        ].into_iter().map(|(n, v)| (n.to_string(), v.to_string()));
+        let names = |env: Env| env.into_keys().collect::<Vec<_>>();
+
+        let spec = spec::Env {
+            vars: btreemap!{
+                "PATH".to_string() => Option::None,
+                "FOO".to_string() => Some("42".to_string()),
+            },
+            exclude: vec!["AWS_*".to_string()],
+            ..Default::default()
+        };
+        let env = build(start_env(), &spec);
+        assert_eq!(names(env.clone()), vec!["FOO", "HOME", "LC_ALL", "LC_TIME"]);
+        assert_eq!(env["FOO"], "42");
+
+        let spec = spec::Env {
+            inherit: Vars(vec!["LC_*".to_string(), "HOME".to_string()]),
+            exclude: vec!["LC_TIME".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(names(build(start_env(), &spec)), vec!["HOME", "LC_ALL"]);
+    }
+
+    #[test]
+    fn build_lists() {
+        let start_env = vec![
+            ("PATH".to_string(), "/usr/bin:/opt/tools/bin::/bin".to_string()),
+            ("HOME".to_string(), "/home/me".to_string()),
+        ];
+        let spec = spec::Env {
+            vars: btreemap!{
+                "HOME".to_string() => Some("${HOME}/work".to_string()),
+                "OLD".to_string() => Some("${PATH} ${NOPE} $${PATH} $PATH".to_string()),
+            },
+            prepend: btreemap!{
+                "PATH".to_string() => vec![
+                    "${HOME}/bin".to_string(),
+                    "/opt/tools/bin".to_string(),
+                    "/usr/bin".to_string(),
+                ],
+            },
+            append: btreemap!{
+                "PATH".to_string() => vec!["/bin:/sbin".to_string()],
+                "LD_LIBRARY_PATH".to_string() => vec!["/opt/lib".to_string()],
+            },
+            ..Default::default()
+        };
+        let env = build(start_env, &spec);
+        // Entries refer to the inherited HOME, not the one set in vars.
+        assert_eq!(env["PATH"], "/home/me/bin:/opt/tools/bin:/usr/bin::/bin:/sbin");
+        assert_eq!(env["LD_LIBRARY_PATH"], "/opt/lib");
+        assert_eq!(env["HOME"], "/home/me/work");
+        assert_eq!(env["OLD"], "/usr/bin:/opt/tools/bin::/bin  ${PATH} $PATH");
+    }
+
+    #[test]
+    fn extend_list_empty() {
+        let path = "/usr/bin::/bin:".to_string();
+        assert_eq!(extend_list(Some(&path), &[], &[]), "/usr/bin::/bin");
+        assert_eq!(extend_list(Some(&path), &[":/sbin".to_string()], &[]), ":/sbin:/usr/bin:/bin");
+        assert_eq!(extend_list(Some(&String::new()), &["/bin".to_string()], &[]), "/bin");
+        assert_eq!(extend_list(Option::None, &[], &["".to_string()]), "");
+    }
+
 }
 
@@ -1,5 +1,9 @@
-/// All the potentially user-visible things that can go wrong while setting up
-/// or running a process.
+//! All the potentially user-visible things that can go wrong while setting up
+//! or running a process.
+
+use crate::sys::fd_t;
+use libc::c_int;
+use serde::Serialize;
 
 //------------------------------------------------------------------------------
 
@@ -50,3 +54,167 @@ impl From<std::num::ParseIntError> for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+
+//------------------------------------------------------------------------------
+
+/// The stage of running a proc at which an error occurred.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Processing the spec.
+    Spec,
+    /// Setting up an fd, in the parent or child.
+    FdSetup,
+    /// Changing to the working directory.
+    Cwd,
+    /// Executing the program.
+    Exec,
+    /// Cleaning up, after the proc terminated.
+    Cleanup,
+}
+
+impl Stage {
+    /// Encodes the stage as an integer, to send over the err pipe.
+    pub fn to_code(self) -> u32 {
+        match self {
+            Stage::Spec => 0,
+            Stage::FdSetup => 1,
+            Stage::Exec => 2,
+            Stage::Cleanup => 3,
+            Stage::Cwd => 4,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<Stage> {
+        match code {
+            0 => Some(Stage::Spec),
+            1 => Some(Stage::FdSetup),
+            2 => Some(Stage::Exec),
+            3 => Some(Stage::Cleanup),
+            4 => Some(Stage::Cwd),
+            _ => None,
+        }
+    }
+}
+
+/// An error running a proc, as reported in results.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ProcError {
+    /// Index of the proc in the spec.
+    pub proc: usize,
+    /// Name of the proc, if it has one.
+    pub name: Option<String>,
+    pub stage: Stage,
+    /// The fd involved, if any.
+    pub fd: Option<fd_t>,
+    /// The errno that caused the error, if any, and its symbolic name.
+    pub errno: Option<c_int>,
+    pub errno_name: Option<&'static str>,
+    /// Human-readable description, including the errno description.
+    pub message: String,
+}
+
+impl ProcError {
+    /// Builds an error.  `msg` is completed with a description of `errno`.
+    pub fn new(
+        proc: usize, stage: Stage, fd: Option<fd_t>, errno: Option<c_int>,
+        msg: &str) -> ProcError
+    {
+        let message = match errno {
+            Some(errno) => format!(
+                "{}: {}", msg, std::io::Error::from_raw_os_error(errno)),
+            None => msg.to_string(),
+        };
+        ProcError {
+            proc, name: None, stage, fd, errno,
+            errno_name: errno.and_then(errno_name),
+            message,
+        }
+    }
+
+    /// Builds an error from an IO error.
+    pub fn from_io(
+        proc: usize, stage: Stage, fd: Option<fd_t>, msg: &str,
+        err: &std::io::Error) -> ProcError
+    {
+        match err.raw_os_error() {
+            Some(errno) => ProcError::new(proc, stage, fd, Some(errno), msg),
+            None => ProcError::new(proc, stage, fd, None, &format!("{}: {}", msg, err)),
+        }
+    }
+}
+
+impl std::fmt::Display for ProcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// An error not attributable to a single proc, as reported in results.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RunError {
+    /// The errno that caused the error, if any, and its symbolic name.
+    pub errno: Option<c_int>,
+    pub errno_name: Option<&'static str>,
+    /// Human-readable description, including the errno description.
+    pub message: String,
+}
+
+impl RunError {
+    /// Builds an error from an IO error.
+    pub fn from_io(msg: &str, err: &std::io::Error) -> RunError {
+        let errno = err.raw_os_error();
+        RunError {
+            errno,
+            errno_name: errno.and_then(errno_name),
+            message: format!("{}: {}", msg, err),
+        }
+    }
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Returns the symbolic name of an errno, for the common ones.
+pub fn errno_name(errno: c_int) -> Option<&'static str> {
+    Some(match errno {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::ESRCH => "ESRCH",
+        libc::EINTR => "EINTR",
+        libc::EIO => "EIO",
+        libc::ENXIO => "ENXIO",
+        libc::E2BIG => "E2BIG",
+        libc::ENOEXEC => "ENOEXEC",
+        libc::EBADF => "EBADF",
+        libc::ECHILD => "ECHILD",
+        libc::EAGAIN => "EAGAIN",
+        libc::ENOMEM => "ENOMEM",
+        libc::EACCES => "EACCES",
+        libc::EFAULT => "EFAULT",
+        libc::EBUSY => "EBUSY",
+        libc::EEXIST => "EEXIST",
+        libc::EXDEV => "EXDEV",
+        libc::ENODEV => "ENODEV",
+        libc::ENOTDIR => "ENOTDIR",
+        libc::EISDIR => "EISDIR",
+        libc::EINVAL => "EINVAL",
+        libc::ENFILE => "ENFILE",
+        libc::EMFILE => "EMFILE",
+        libc::ETXTBSY => "ETXTBSY",
+        libc::EFBIG => "EFBIG",
+        libc::ENOSPC => "ENOSPC",
+        libc::ESPIPE => "ESPIPE",
+        libc::EROFS => "EROFS",
+        libc::EMLINK => "EMLINK",
+        libc::EPIPE => "EPIPE",
+        libc::ERANGE => "ERANGE",
+        libc::ENAMETOOLONG => "ENAMETOOLONG",
+        libc::ENOSYS => "ENOSYS",
+        libc::ELOOP => "ELOOP",
+        _ => return None,
+    })
+}
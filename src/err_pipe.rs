@@ -1,12 +1,30 @@
-use crate::err::{Error, Result};
-use crate::fdio;
+use crate::err::{ProcError, Result, Stage};
 use crate::sel;
 use crate::sys;
 use crate::sys::fd_t;
+use libc::c_int;
+use std::io;
+
+//------------------------------------------------------------------------------
+
+/// Maximum size of an error record.  Each record is sent with a single write no
+/// larger than `PIPE_BUF`, so records from different procs don't interleave.
+const MAX_RECORD_SIZE: usize = 512;
+
+/// Size of the record header: proc index, stage code, fd (or -1), errno (or
+/// 0), and message length, each a NE 32-bit int.  The message follows.
+const HEADER_SIZE: usize = 20;
+
+/// Maximum message length; longer messages are truncated.
+const MAX_MSG_LEN: usize = MAX_RECORD_SIZE - HEADER_SIZE;
+
+//------------------------------------------------------------------------------
 
 pub struct ErrPipeRead {
     fd: fd_t,
-    errs: Vec<String>,
+    /// Data read but not yet parsed, ending with a partial record, if any.
+    buf: Vec<u8>,
+    errs: Vec<ProcError>,
 }
 
 impl ErrPipeRead {
@@ -15,8 +33,44 @@ impl ErrPipeRead {
         Ok(())
     }
 
-    pub fn get_errors(self) -> Vec<String> {
-        self.errs
+    /// Returns errors received since the last call.
+    pub fn take_errors(&mut self) -> Vec<ProcError> {
+        std::mem::take(&mut self.errs)
+    }
+
+    /// Parses the complete records in the buffer.
+    fn parse(&mut self) {
+        let mut pos = 0;
+        while self.buf.len() - pos >= HEADER_SIZE {
+            let record = &self.buf[pos ..];
+            let word = |i: usize| {
+                let mut word = [0u8; 4];
+                word.copy_from_slice(&record[i * 4 .. i * 4 + 4]);
+                u32::from_ne_bytes(word)
+            };
+            let len = word(4) as usize;
+            assert!(len <= MAX_MSG_LEN, "bad message length in err pipe: {}", len);
+            if record.len() < HEADER_SIZE + len {
+                break;
+            }
+
+            let proc = word(0) as usize;
+            let stage = Stage::from_code(word(1)).expect("bad stage in err pipe");
+            let fd = match word(2) as c_int {
+                -1 => None,
+                fd => Some(fd),
+            };
+            let errno = match word(3) as c_int {
+                0 => None,
+                errno => Some(errno),
+            };
+            let msg = String::from_utf8_lossy(&record[HEADER_SIZE .. HEADER_SIZE + len]);
+
+            // The child can't format the error, so do it here.
+            self.errs.push(ProcError::new(proc, stage, fd, errno, &msg));
+            pos += HEADER_SIZE + len;
+        }
+        self.buf.drain(.. pos);
     }
 }
 
@@ -26,13 +80,23 @@ impl sel::Read for ErrPipeRead {
     }
 
     fn read(&mut self) -> bool {
-        let err = match fdio::read_str(self.fd) {
-            Ok(str) => str,
-            Err(Error::Eof) => return true,
+        let mut buf = [0u8; 4096];
+        let eof = match sys::read(self.fd, &mut buf) {
+            Ok(0) => true,
+            Ok(n) => {
+                self.buf.extend_from_slice(&buf[.. n]);
+                false
+            },
+            // Nothing to read, if nonblocking.
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return false,
             Err(err) => panic!("error: {}", err),
         };
-        self.errs.push(err);
-        false
+        self.parse();
+        if eof {
+            // Writes are atomic, so there can't be a partial record.
+            assert!(self.buf.is_empty(), "partial err pipe record");
+        }
+        eof
     }
 }
 
@@ -41,8 +105,38 @@ pub struct ErrPipeWrite {
 }
 
 impl ErrPipeWrite {
-    pub fn send(&self, err: &str) {
-        fdio::write_str(self.fd, err).unwrap();
+    /// Sends an error for proc index `proc`, with the fd involved if any, the
+    /// errno that caused it or 0 if none, and a message that is completed with
+    /// a description of the errno.
+    ///
+    /// Doesn't allocate or panic, and makes only a single raw write, so this is
+    /// safe to call in a child process after fork or vfork.  The write end is
+    /// nonblocking, so that a child never waits for the pipe, as the parent may
+    /// be suspended until it execs or exits.  If the pipe is full, the error is
+    /// dropped; the proc still exits with an error status.  Other errors are
+    /// ignored, as there's nothing the child can do about them.
+    pub fn send(
+        &self, proc: usize, stage: Stage, fd: Option<fd_t>, errno: c_int,
+        msg: &[u8])
+    {
+        let len = std::cmp::min(msg.len(), MAX_MSG_LEN);
+        let header = [
+            proc as u32,
+            stage.to_code(),
+            fd.unwrap_or(-1) as u32,
+            errno as u32,
+            len as u32,
+        ];
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        for (i, word) in header.iter().enumerate() {
+            buf[i * 4 .. i * 4 + 4].copy_from_slice(&word.to_ne_bytes());
+        }
+        buf[HEADER_SIZE .. HEADER_SIZE + len].copy_from_slice(&msg[.. len]);
+        // A write of at most PIPE_BUF bytes is all or nothing, even if
+        // nonblocking, so a failed write leaves no partial record.
+        unsafe {
+            libc::write(self.fd, buf.as_ptr() as *const libc::c_void, HEADER_SIZE + len);
+        }
     }
 
     pub fn close(&self) -> Result<()> {
@@ -51,13 +145,59 @@ impl ErrPipeWrite {
     }
 }
 
-pub fn new_err_pipe() -> (ErrPipeRead, ErrPipeWrite) {
-    let (read_fd, write_fd) = sys::pipe().unwrap_or_else(|err| {
-        eprintln!("failed to create err pipe: {}", err);
-        std::process::exit(exitcode::OSERR);
-    });
-    let err_read = ErrPipeRead {fd: read_fd, errs: Vec::new()};
+/// Creates the error pipe.  Both ends are close-on-exec, so procs don't
+/// inherit them, and the read end sees EOF once every proc has exec'ed.  The
+/// write end is nonblocking; see `ErrPipeWrite::send()`.
+pub fn new_err_pipe() -> io::Result<(ErrPipeRead, ErrPipeWrite)> {
+    let (read_fd, write_fd) = sys::pipe()?;
+    sys::set_cloexec(read_fd)?;
+    sys::set_cloexec(write_fd)?;
+    sys::set_nonblocking(write_fd)?;
+    let err_read = ErrPipeRead {fd: read_fd, buf: Vec::new(), errs: Vec::new()};
     let err_write = ErrPipeWrite {fd: write_fd};
-    (err_read, err_write)
+    Ok((err_read, err_write))
 }
 
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sel::Read;
+
+    #[test]
+    fn records() {
+        let (mut err_read, err_write) = new_err_pipe().unwrap();
+        err_write.send(3, Stage::Exec, None, libc::ENOENT, b"failed to exec");
+        err_write.send(4, Stage::FdSetup, Some(2), 0, &[b'x'; 1000]);
+        err_write.close().unwrap();
+        while !err_read.read() {}
+        err_read.close().unwrap();
+
+        let errs = err_read.take_errors();
+        assert_eq!(errs.len(), 2);
+        assert_eq!((errs[0].proc, errs[0].errno, errs[0].fd), (3, Some(libc::ENOENT), None));
+        assert!(errs[0].message.starts_with("failed to exec: "));
+        assert_eq!((errs[1].proc, errs[1].errno, errs[1].fd), (4, None, Some(2)));
+        assert_eq!(errs[1].message.len(), MAX_MSG_LEN);
+    }
+
+    #[test]
+    fn full_pipe() {
+        // Sending more than the pipe holds neither blocks nor corrupts records.
+        let (mut err_read, err_write) = new_err_pipe().unwrap();
+        let num = 4 * 1024 * 1024 / MAX_RECORD_SIZE;
+        for proc in 0 .. num {
+            err_write.send(proc, Stage::Exec, None, libc::ENOENT, &[b'x'; MAX_MSG_LEN]);
+        }
+        err_write.close().unwrap();
+        while !err_read.read() {}
+        err_read.close().unwrap();
+
+        let errs = err_read.take_errors();
+        assert!(!errs.is_empty() && errs.len() < num);
+        for (proc, err) in errs.iter().enumerate() {
+            assert_eq!(err.proc, proc);
+        }
+    }
+}
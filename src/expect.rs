@@ -0,0 +1,147 @@
+//! Checking a proc's result against its expected outcome.
+
+use crate::res::{ProcRes, ProcState};
+use crate::spec;
+
+//------------------------------------------------------------------------------
+
+fn check_output(
+    expect: &spec::OutputExpect, proc_res: &ProcRes, name: &str,
+    failures: &mut Vec<String>)
+{
+    let output = match proc_res.get_text(name) {
+        Some(output) => output,
+        None => {
+            failures.push(format!("{} is not captured", name));
+            return;
+        },
+    };
+    if let Some(equals) = &expect.equals {
+        if output != equals.as_str() {
+            failures.push(format!("{} is {:?}, expected {:?}", name, output, equals));
+        }
+    }
+    if let Some(contains) = &expect.contains {
+        if !output.contains(contains.as_str()) {
+            failures.push(format!("{} doesn't contain {:?}", name, contains));
+        }
+    }
+    if let Some(matches) = &expect.matches {
+        match regex::Regex::new(matches) {
+            Ok(regex) if regex.is_match(&output) => (),
+            Ok(_) => failures.push(format!("{} doesn't match {:?}", name, matches)),
+            Err(err) => failures.push(format!("bad regex {:?}: {}", matches, err)),
+        }
+    }
+}
+
+/// Checks a proc's result against its expected outcome.  Returns a
+/// description of each expectation that failed.
+pub fn check(expect: &spec::Expect, proc_res: &ProcRes) -> Vec<String> {
+    if proc_res.state != ProcState::Terminated {
+        return vec!["program didn't run".to_string()];
+    }
+
+    let mut failures = Vec::new();
+    let outcome = proc_res.describe_status();
+    match (&expect.signal, expect.exit_code) {
+        (Some(signal), _) => {
+            // The spec is validated, so the signal is too.
+            let signum = signal.to_signum().unwrap_or(0);
+            if proc_res.signum != Some(signum) {
+                failures.push(format!("{}, expected signal {}", outcome, signum));
+            }
+        },
+        (None, exit_code) => {
+            let exit_code = exit_code.unwrap_or(0);
+            if proc_res.exit_code != Some(exit_code) {
+                failures.push(format!("{}, expected code {}", outcome, exit_code));
+            }
+        },
+    }
+
+    if let Some(stdout) = &expect.stdout {
+        check_output(stdout, proc_res, "stdout", &mut failures);
+    }
+    if let Some(stderr) = &expect.stderr {
+        check_output(stderr, proc_res, "stderr", &mut failures);
+    }
+
+    if let (Some(max_elapsed), Some(elapsed)) = (expect.max_elapsed, proc_res.elapsed) {
+        if elapsed > max_elapsed {
+            failures.push(format!("elapsed {:.3} s, expected at most {} s", elapsed, max_elapsed));
+        }
+    }
+    if let Some(max_rss) = expect.max_rss {
+        let rss = proc_res.maxrss() as u64;
+        if rss > max_rss {
+            failures.push(format!("max RSS {} KiB, expected at most {} KiB", rss, max_rss));
+        }
+    }
+
+    failures
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::res::FdRes;
+
+    fn proc_res(status: libc::c_int, stdout: &str) -> ProcRes {
+        let mut proc_res = ProcRes::new(1, status, unsafe { std::mem::zeroed() });
+        proc_res.elapsed = Some(0.5);
+        proc_res.fds.insert(
+            "stdout".to_string(), FdRes::CaptureUtf8 { text: stdout.to_string() });
+        proc_res
+    }
+
+    #[test]
+    fn status() {
+        let expect = spec::Expect::default();
+        assert!(check(&expect, &proc_res(0, "")).is_empty());
+        assert_eq!(
+            check(&expect, &proc_res(1 << 8, "")),
+            vec!["exited with code 1, expected code 0"]);
+
+        let expect = spec::Expect {
+            signal: Some(spec::Signal::Num(libc::SIGKILL)), ..Default::default()
+        };
+        assert!(check(&expect, &proc_res(libc::SIGKILL, "")).is_empty());
+        assert_eq!(check(&expect, &proc_res(0, "")).len(), 1);
+
+        assert_eq!(
+            check(&expect, &ProcRes::not_started(ProcState::ExecFailed)),
+            vec!["program didn't run"]);
+    }
+
+    #[test]
+    fn output() {
+        let expect = spec::Expect {
+            stdout: Some(spec::OutputExpect {
+                equals: Some("hello, world\n".to_string()),
+                contains: Some("world".to_string()),
+                matches: Some("^hel+o,".to_string()),
+            }),
+            max_elapsed: Some(1.),
+            ..Default::default()
+        };
+        assert!(check(&expect, &proc_res(0, "hello, world\n")).is_empty());
+        assert_eq!(check(&expect, &proc_res(0, "goodbye, all\n")), vec![
+            "stdout is \"goodbye, all\\n\", expected \"hello, world\\n\"",
+            "stdout doesn't contain \"world\"",
+            "stdout doesn't match \"^hel+o,\"",
+        ]);
+
+        let expect = spec::Expect {
+            stderr: Some(spec::OutputExpect::default()),
+            max_elapsed: Some(0.1),
+            ..Default::default()
+        };
+        assert_eq!(check(&expect, &proc_res(0, "")), vec![
+            "stderr is not captured",
+            "elapsed 0.500 s, expected at most 0.1 s",
+        ]);
+    }
+}
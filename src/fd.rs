@@ -2,12 +2,16 @@ use crate::err::{Error, Result};
 use crate::fdio;
 use crate::res::FdRes;
 use crate::sel;
+use crate::spawn::Action;
 use crate::spec;
 use crate::sys;
 use crate::sys::fd_t;
 use std::io;
 use std::io::Read;
 use std::io::Seek;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::FromRawFd;
 use std::path::PathBuf;
 use libc;
@@ -55,13 +59,29 @@ pub trait Fd {
     fn get_fd(&self) -> fd_t;
 
     /// Called after fork(), in parent process.
-    fn set_up_in_parent(&mut self) -> io::Result<Option<&mut dyn sel::Read>> {
-        Ok(None)
+    fn set_up_in_parent(&mut self) -> io::Result<()> {
+        Ok(())
     }
 
-    /// Called after fork(), in child process.
-    fn set_up_in_child(&mut self) -> io::Result<()> {
-        Ok(())
+    /// Returns a reader that the parent must call when its fd is readable,
+    /// while the proc runs, if any.
+    fn as_reader(&mut self) -> Option<&mut dyn sel::Read> {
+        None
+    }
+
+    /// Returns actions that set up the fd in the child.  These are prepared in
+    /// the parent, before fork; the child only carries them out with raw
+    /// syscalls.
+    fn get_child_actions(&self) -> Vec<Action>;
+
+    /// Returns the number of bytes captured so far, if the fd is captured.
+    fn get_captured_len(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns the output captured so far, if the fd is captured.
+    fn get_captured(&self) -> io::Result<Option<FdRes>> {
+        Ok(None)
     }
 
     /// Called in parent process after wait().
@@ -79,6 +99,10 @@ struct Inherit {
 
 impl Fd for Inherit {
     fn get_fd(&self) -> fd_t { self.fd }
+
+    fn get_child_actions(&self) -> Vec<Action> {
+        Vec::new()
+    }
 }
 
 impl Inherit {
@@ -100,9 +124,8 @@ impl Close {
 impl Fd for Close {
     fn get_fd(&self) -> fd_t { self.fd }
 
-    fn set_up_in_child(&mut self) -> io::Result<()> {
-        sys::close(self.fd)?;
-        Ok(())
+    fn get_child_actions(&self) -> Vec<Action> {
+        vec![Action::Close(self.fd)]
     }
 }
 
@@ -111,28 +134,32 @@ impl Fd for Close {
 struct File {
     fd: fd_t,
     path: PathBuf,
+    /// The path, prepared for the child.
+    c_path: CString,
     oflags: libc::c_int,
     mode: libc::c_int,
 }
 
 impl File {
     fn new(fd: fd_t, path: PathBuf, flags: spec::OpenFlag, mode: libc::c_int)
-        -> File
+        -> Result<File>
     {
-        File { fd, path, oflags: get_oflags(&flags, fd), mode }
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(io::Error::from)?;
+        Ok(File { fd, path, c_path, oflags: get_oflags(&flags, fd), mode })
     }
 }
-        
 
 impl Fd for File {
     fn get_fd(&self) -> fd_t { self.fd }
 
-    fn set_up_in_child(&mut self) -> io::Result<()>
-    {
-        let file_fd = sys::open(&self.path, self.oflags, self.mode)?;
-        sys::dup2(file_fd, self.fd)?;
-        sys::close(file_fd)?;
-        Ok(())
+    fn get_child_actions(&self) -> Vec<Action> {
+        vec![Action::Open {
+            fd: self.fd,
+            path: self.c_path.clone(),
+            oflags: self.oflags,
+            mode: self.mode,
+        }]
     }
 
     fn clean_up_in_parent(&mut self) -> io::Result<Option<FdRes>> {
@@ -157,9 +184,8 @@ impl Dup {
 impl Fd for Dup {
     fn get_fd(&self) -> fd_t { self.fd }
 
-    fn set_up_in_child(&mut self) -> io::Result<()> {
-        sys::dup2(self.dup_fd, self.fd)?;
-        Ok(())
+    fn get_child_actions(&self) -> Vec<Action> {
+        vec![Action::Dup2 { from: self.dup_fd, fd: self.fd }]
     }
 
     // FIXME: Insert path into result.
@@ -178,6 +204,7 @@ const TMP_TEMPLATE: &str = "/tmp/ir-capture-XXXXXXXXXXXX";
 
 impl TempFileCapture {
     fn new(fd: fd_t, format: spec::CaptureFormat) -> Result<TempFileCapture> {
+        // Close-on-exec, so that other procs don't inherit it.
         let (tmp_path, tmp_fd) = sys::mkstemp(TMP_TEMPLATE)?;
         std::fs::remove_file(tmp_path)?;
         Ok(TempFileCapture { fd, tmp_fd, format })
@@ -187,11 +214,29 @@ impl TempFileCapture {
 impl Fd for TempFileCapture {
     fn get_fd(&self) -> fd_t { self.fd }
 
-    fn set_up_in_child(&mut self) -> io::Result<()> {
-        sys::dup2(self.tmp_fd, self.fd)?;
-        sys::close(self.tmp_fd)?;
-        self.tmp_fd = -1;
-        Ok(())
+    fn get_child_actions(&self) -> Vec<Action> {
+        // The temp file fd is close-on-exec, so needn't be closed.
+        vec![Action::Dup2 { from: self.tmp_fd, fd: self.fd }]
+    }
+
+    fn get_captured_len(&self) -> Option<u64> {
+        // The proc writes directly to the file; ask how big it is.
+        sys::fstat(self.tmp_fd).ok().map(|stat| stat.st_size as u64)
+    }
+
+    fn get_captured(&self) -> io::Result<Option<FdRes>> {
+        // Read without moving the file offset, which the proc shares.
+        let file = std::mem::ManuallyDrop::new(
+            unsafe { std::fs::File::from_raw_fd(self.tmp_fd) });
+        let mut bytes = Vec::new();
+        let mut buf = [0u8; 65536];
+        loop {
+            match file.read_at(&mut buf, bytes.len() as u64)? {
+                0 => break,
+                n => bytes.extend_from_slice(&buf[.. n]),
+            }
+        }
+        Ok(Some(FdRes::from_bytes(self.format, bytes)))
     }
 
     fn clean_up_in_parent(&mut self) -> io::Result<Option<FdRes>> {
@@ -210,6 +255,15 @@ impl Fd for TempFileCapture {
     }
 }
 
+impl Drop for TempFileCapture {
+    /// Closes the temporary file, if the proc didn't finish normally.
+    fn drop(&mut self) {
+        if self.tmp_fd >= 0 {
+            let _ = sys::close(self.tmp_fd);
+        }
+    }
+}
+
 //------------------------------------------------------------------------------
 
 pub struct MemoryCapture {
@@ -231,7 +285,8 @@ pub struct MemoryCapture {
 
 impl MemoryCapture {
     fn new(fd: fd_t, format: spec::CaptureFormat) -> Result<MemoryCapture> {
-        let (read_fd, write_fd) = sys::pipe()?;
+        // Close-on-exec, so that other procs don't inherit it.
+        let (read_fd, write_fd) = sys::pipe_cloexec()?;
         Ok(MemoryCapture {
             fd,
             read_fd,
@@ -262,26 +317,51 @@ impl Fd for MemoryCapture {
         self.fd
     }
 
-    fn set_up_in_child(&mut self) -> io::Result<()> {
-        sys::close(self.read_fd)?;
-        sys::dup2(self.write_fd, self.fd)?;
-        Ok(())
+    fn get_child_actions(&self) -> Vec<Action> {
+        // The pipe fds are close-on-exec, so needn't be closed.
+        vec![Action::Dup2 { from: self.write_fd, fd: self.fd }]
     }
 
-    fn set_up_in_parent(&mut self) -> io::Result<Option<&mut dyn sel::Read>> {
+    fn set_up_in_parent(&mut self) -> io::Result<()> {
         // Close the write end of the pipe.  Only the child writes.
-        sys::close(self.write_fd)?;
-        Ok(Some(self))
+        let write_fd = std::mem::replace(&mut self.write_fd, -1);
+        sys::close(write_fd)?;
+        Ok(())
     }
 
-    /// Called in parent process after wait().
+    fn as_reader(&mut self) -> Option<&mut dyn sel::Read> {
+        Some(self)
+    }
+
+    fn get_captured_len(&self) -> Option<u64> {
+        Some(self.buf.len() as u64)
+    }
+
+    fn get_captured(&self) -> io::Result<Option<FdRes>> {
+        Ok(Some(FdRes::from_bytes(self.format, self.buf.clone())))
+    }
+
+    /// Called in parent process after wait(), and after reading to EOF.
     fn clean_up_in_parent(&mut self) -> io::Result<Option<FdRes>> {
+        let read_fd = std::mem::replace(&mut self.read_fd, -1);
+        sys::close(read_fd)?;
         let mut buf = Vec::new();
         std::mem::swap(&mut buf, &mut self.buf);
         Ok(Some(FdRes::from_bytes(self.format, buf)))
     }
 }
 
+impl Drop for MemoryCapture {
+    /// Closes the pipe, if the proc didn't finish normally.
+    fn drop(&mut self) {
+        for fd in [self.read_fd, self.write_fd] {
+            if fd >= 0 {
+                let _ = sys::close(fd);
+            }
+        }
+    }
+}
+
 //------------------------------------------------------------------------------
 
 pub fn create_fd(fd: fd_t, fd_spec: &spec::Fd) -> Result<Box<dyn Fd>> {
@@ -291,9 +371,9 @@ pub fn create_fd(fd: fd_t, fd_spec: &spec::Fd) -> Result<Box<dyn Fd>> {
         spec::Fd::Close
             => Box::new(Close::new(fd)),
         spec::Fd::Null { flags }
-            => Box::new(File::new(fd, PathBuf::from("/dev/null"), *flags, 0)),
+            => Box::new(File::new(fd, PathBuf::from("/dev/null"), *flags, 0)?),
         spec::Fd::File { path, flags, mode }
-            => Box::new(File::new(fd, path.to_path_buf(), *flags, *mode)),
+            => Box::new(File::new(fd, path.to_path_buf(), *flags, *mode)?),
         spec::Fd::Dup { fd: other_fd }
             => Box::new(Dup::new(fd, *other_fd)),
         spec::Fd::Capture { mode, format }
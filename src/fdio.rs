@@ -3,6 +3,9 @@
 use crate::err::{Error, Result};
 use crate::sys;
 use crate::sys::fd_t;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::string::String;
 
 //------------------------------------------------------------------------------
@@ -63,3 +66,14 @@ pub fn write_usize(fd: fd_t, val: usize) -> Result<()> {
     write(fd, &val.to_ne_bytes())
 }
 
+
+/// Writes `data` to a file at `path`, replacing it atomically: a reader sees
+/// either the old contents or all the new.  Writes to a temporary file in the
+/// same directory, then renames it.
+pub fn write_file_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp_path = OsString::from(path);
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    std::fs::write(&tmp_path, data)?;
+    std::fs::rename(&tmp_path, path)
+}
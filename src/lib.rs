@@ -1,14 +1,24 @@
 #[allow(unused_imports)]  // FIXME: ??
 #[macro_use] extern crate maplit;
 
+pub mod ctl;
+pub mod daemon;
 pub mod environ;
 pub mod err;
 pub mod err_pipe;
+pub mod expect;
 pub mod fd;
 pub mod fdio;
+pub mod procs;
+pub mod report;
 pub mod res;
+pub mod run;
 pub mod sel;
 pub mod sig;
+pub mod spawn;
 pub mod spec;
+pub mod state;
+pub mod template;
 pub mod sys;
+pub mod vars;
 
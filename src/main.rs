@@ -4,266 +4,337 @@ extern crate exitcode;
 #[allow(unused_imports)]
 #[macro_use] extern crate maplit;
 
-use ir::environ;
-use ir::err_pipe::new_err_pipe;
-use ir::fd::parse_fd;
 use ir::res;
-use ir::sel;
-use ir::sig;
+use ir::run::Event;
 use ir::spec;
-use ir::sys;
-use libc::pid_t;
+use serde_json::json;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 //------------------------------------------------------------------------------
 
-// FIXME: Elsewhere.
-fn wait(block: bool) -> Option<sys::WaitInfo> {
-    loop {
-        match sys::wait4(-1, block) {
-            Ok(Some(ti)) =>
-                return Some(ti),
-            Ok(None) =>
-                if block {
-                    panic!("wait4 empty result");
-                }
-                else {
-                    return None;
-                },
-            Err(ref err)if err.kind() == std::io::ErrorKind::Interrupted =>
-                // wait4 interrupted, possibly by SIGCHLD.
-                if block {
-                    // Keep going.
-                    continue;
-                }
-                else {
-                    // Return, as the caller might want to do something.
-                    return None;
-                },
-            Err(err) => panic!("wait4 failed: {}", err),
-        };
-    }
+/// How to print results.
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    /// A single JSON document, when all procs have finished.
+    Json,
+    /// A JSON event per line, as each happens.
+    Ndjson,
+    /// A JUnit XML test suite, when all procs have finished.
+    Junit,
+    /// A TAP stream, when all procs have finished.
+    Tap,
 }
 
-//------------------------------------------------------------------------------
-
-/// A proc we're running, or that has terminated.
-struct Proc {
-    pub pid: pid_t,
-
-    /// None while the proc is running; the result of wait4() once the proc has
-    /// terminated and been cleaned up.
-    pub wait_info: Option<sys::WaitInfo>,
+struct Args {
+    /// Specs, combined into one.
+    spec_paths: Vec<String>,
+    /// If None, chosen by each spec path's extension.
+    spec_format: Option<spec::Format>,
+    format: Format,
+    state_file: Option<PathBuf>,
+    state_interval: Duration,
+    ctl_socket: Option<PathBuf>,
+    output: Option<PathBuf>,
+    /// Paths to which to write reports, besides any output.
+    junit: Option<PathBuf>,
+    tap: Option<PathBuf>,
+    daemon: bool,
+    /// Repeat for procs that don't specify one.
+    repeat: Option<spec::Repeat>,
+    /// Variables, overriding those in the spec.
+    vars: Vec<(String, String)>,
 }
 
-struct Procs {
-    procs: Vec<Proc>,
-    num_running: usize,
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    eprintln!(
+        "usage: ir [--format json|ndjson|junit|tap] [--output PATH] [--daemon]\n\
+         \x20         [--junit PATH] [--tap PATH]\n\
+         \x20         [--state-file PATH [--state-interval SECONDS]]\n\
+         \x20         [--ctl-socket PATH] [--repeat COUNT [--warmup COUNT]]\n\
+         \x20         [--spec-format json|yaml|toml] [-D NAME=VALUE ...] SPEC ...");
+    std::process::exit(exitcode::USAGE);
 }
 
-impl Procs {
-    pub fn new() -> Self {
-        Self { procs: Vec::new(), num_running: 0 }
+fn parse_args() -> Args {
+    let mut spec_paths = Vec::new();
+    let mut spec_format = None;
+    let mut format = Format::Json;
+    let mut state_file = None;
+    let mut state_interval = Duration::from_secs(1);
+    let mut ctl_socket = None;
+    let mut output = None;
+    let mut junit = None;
+    let mut tap = None;
+    let mut daemon = false;
+    let mut repeat = None;
+    let mut warmup = None;
+    let mut vars = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match args.next().as_deref() {
+                    Some("json") => Format::Json,
+                    Some("ndjson") => Format::Ndjson,
+                    Some("junit") => Format::Junit,
+                    Some("tap") => Format::Tap,
+                    Some(f) => usage_error(&format!("unknown format: {}", f)),
+                    None => usage_error("--format requires a value"),
+                };
+            },
+            "--spec-format" => {
+                spec_format = match args.next() {
+                    Some(f) => Some(spec::Format::from_name(&f).unwrap_or_else(
+                        || usage_error(&format!("unknown spec format: {}", f)))),
+                    None => usage_error("--spec-format requires a value"),
+                };
+            },
+            "--state-file" => {
+                state_file = Some(PathBuf::from(args.next().unwrap_or_else(
+                    || usage_error("--state-file requires a path"))));
+            },
+            "--state-interval" => {
+                state_interval = args.next()
+                    .and_then(|s| s.parse::<f64>().ok())
+                    .filter(|&s| s > 0.)
+                    .map(Duration::from_secs_f64)
+                    .unwrap_or_else(|| usage_error(
+                        "--state-interval requires a positive number of seconds"));
+            },
+            "--ctl-socket" => {
+                ctl_socket = Some(PathBuf::from(args.next().unwrap_or_else(
+                    || usage_error("--ctl-socket requires a path"))));
+            },
+            "--output" => {
+                output = Some(PathBuf::from(args.next().unwrap_or_else(
+                    || usage_error("--output requires a path"))));
+            },
+            "--junit" => {
+                junit = Some(PathBuf::from(args.next().unwrap_or_else(
+                    || usage_error("--junit requires a path"))));
+            },
+            "--tap" => {
+                tap = Some(PathBuf::from(args.next().unwrap_or_else(
+                    || usage_error("--tap requires a path"))));
+            },
+            "--daemon" => daemon = true,
+            "-D" => {
+                let var = args.next().unwrap_or_else(|| usage_error("-D requires NAME=VALUE"));
+                match var.split_once('=') {
+                    Some((name, value)) if !name.is_empty() =>
+                        vars.push((name.to_string(), value.to_string())),
+                    _ => usage_error(&format!("bad variable: {}", var)),
+                }
+            },
+            "--repeat" => {
+                repeat = Some(args.next()
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .filter(|&n| n > 0)
+                    .unwrap_or_else(|| usage_error("--repeat requires a positive count")));
+            },
+            "--warmup" => {
+                warmup = Some(args.next()
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .unwrap_or_else(|| usage_error("--warmup requires a count")));
+            },
+            _ if arg.starts_with("--") =>
+                usage_error(&format!("unknown option: {}", arg)),
+            _ => spec_paths.push(arg),
+        }
     }
 
-    pub fn push(&mut self, pid: pid_t) {
-        self.procs.push(Proc { pid, wait_info: None });
-        self.num_running += 1;
+    if spec_paths.is_empty() {
+        usage_error("no spec given");
     }
-
-    fn wait(&mut self, block: bool) {
-        while self.num_running > 0 {
-            if let Some(wait_info) = wait(block) {
-                let pid = wait_info.0;
-                let mut pid_found = false;
-                for proc in &mut self.procs {
-                    if proc.pid == pid {
-                        assert!(proc.wait_info.replace(wait_info).is_none());
-                        self.num_running -= 1;
-                        pid_found = true;
-                        break
-                    }
-                }
-                assert!(pid_found, "wait returned unexpected pid: {}", pid);
-            }
-            else {
-                assert!(! block, "blocking wait returned no pid");
-                break
-            }
-        }
+    if daemon && format == Format::Ndjson {
+        usage_error("--daemon doesn't print events; use --state-file instead");
+    }
+    if daemon && format != Format::Json {
+        usage_error("--daemon doesn't print reports; use --junit or --tap instead");
+    }
+    let repeat = match (repeat, warmup) {
+        (Some(count), warmup) => Some(spec::Repeat { count, warmup: warmup.unwrap_or(0) }),
+        (None, Some(_)) => usage_error("--warmup requires --repeat"),
+        (None, None) => None,
+    };
+    Args {
+        spec_paths, spec_format, format, state_file, state_interval, ctl_socket, output,
+        junit, tap, daemon, repeat, vars,
     }
+}
+
+/// Prints a JSON value on its own line, and flushes, so that a reader sees it
+/// immediately.
+fn print_line(value: &serde_json::Value) {
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    // If stdout is gone, there's no one left to tell.
+    let _ = writeln!(stdout, "{}", value);
+    let _ = stdout.flush();
+}
 
-    /// Waits any procs that terminated and are zombies, and stores their wait
-    /// info.
-    pub fn wait_any(&mut self) { self.wait(false); }
+/// Prints a progress event as an NDJSON line.  `names` are the proc names.
+fn print_event(names: &[Option<String>], event: &Event) {
+    print_line(&match event {
+        Event::Started { index, pid } => json!({
+            "event": "started",
+            "proc": index,
+            "name": names[*index],
+            "pid": pid,
+        }),
+        // The result, when the proc finishes, carries the status.
+        Event::Terminated { .. } => return,
+        Event::Error(err) => json!({
+            "event": "error",
+            "error": err,
+        }),
+        Event::Retry { index, result } => json!({
+            "event": "retry",
+            "proc": index,
+            "name": names[*index],
+            "result": result,
+        }),
+        Event::Repeat { index, result } => json!({
+            "event": "repeat",
+            "proc": index,
+            "name": names[*index],
+            "result": result,
+        }),
+        Event::Finished { index, result } => json!({
+            "event": "finished",
+            "proc": index,
+            "name": names[*index],
+            "result": result,
+        }),
+    });
+}
 
-    /// Blocks and waits for all remaining procs to terminate, and stores their
-    /// wait info.
-    pub fn wait_all(&mut self) { self.wait(true); }
+/// Writes a report file, atomically.
+fn write_report(path: &Path, report: &str) {
+    ir::fdio::write_file_atomic(path, report.as_bytes()).unwrap_or_else(|err| {
+        eprintln!("failed to write {}: {}", path.display(), err);
+        std::process::exit(exitcode::CANTCREAT);
+    });
+}
 
-    pub fn into_iter(self) -> std::vec::IntoIter<Proc> { self.procs.into_iter() }
+/// Where a daemon writes results, if not given.
+fn get_default_output(daemon_pid: libc::pid_t) -> PathBuf {
+    std::env::temp_dir().join(format!("ir-{}.json", daemon_pid))
 }
 
-//------------------------------------------------------------------------------
+/// Detaches into a daemon process.  In the calling process, prints the
+/// daemon's pid and the path to which it'll write results, and exits.  In the
+/// daemon, returns the results path.
+fn daemonize(output: Option<PathBuf>) -> PathBuf {
+    // The daemon doesn't change dir, but the caller should get an absolute
+    // path anyway.
+    let output = output.map(|path| {
+        std::env::current_dir().map(|dir| dir.join(&path)).unwrap_or(path)
+    });
+    match ir::daemon::daemonize() {
+        Ok(Some(daemon_pid)) => {
+            let output = output.unwrap_or_else(|| get_default_output(daemon_pid));
+            print_line(&json!({"pid": daemon_pid, "result": output}));
+            std::process::exit(exitcode::OK);
+        },
+        Ok(None) =>
+            output.unwrap_or_else(|| get_default_output(ir::sys::getpid())),
+        Err(err) => {
+            eprintln!("failed to daemonize: {}", err);
+            std::process::exit(exitcode::OSERR);
+        },
+    }
+}
 
 fn main() {
-    let json_path = match std::env::args().skip(1).next() {
-        Some(p) => p,
-        None => panic!("no file given"),  // FIXME
-    };
+    let mut args = parse_args();
 
-    let input = spec::load_file(&json_path).unwrap_or_else(|err| {
-        eprintln!("failed to load {}: {}", json_path, err);
+    let input = spec::load_files(&args.spec_paths, args.spec_format);
+    let mut input = input.unwrap_or_else(|err| {
+        eprintln!("failed to load {}: {}", args.spec_paths.join(" "), err);
         std::process::exit(exitcode::OSFILE);
     });
+    if let Some(repeat) = &args.repeat {
+        for proc in input.procs.iter_mut().filter(|p| p.repeat.is_none()) {
+            proc.repeat = Some(repeat.clone());
+        }
+    }
     eprintln!("input: {:?}", input);
     eprintln!("");
 
-    let mut result = res::Res::new();
-
-    // Set up the selector, which will manage events while the child runs.
-    let mut select = sel::Select::new();
-
-    // Build pipe for passing errors from child to parent.
-    let (mut err_read, err_write) = new_err_pipe();
-    // Read errors from the error pipe.
-    select.insert_reader(&mut err_read);
-
-    // Build the objects presenting each of the file descriptors in each proc.
-    let mut fds = input.procs.iter().map(|spec| {
-        spec.fds.iter().map(|(fd_str, fd_spec)| {
-            // FIXME: Parse when deserializing, rather than here.
-            let fd_num = parse_fd(fd_str).unwrap_or_else(|err| {
-                eprintln!("failed to parse fd {}: {}", fd_str, err);
-                std::process::exit(exitcode::OSERR);
-            });
-
-            ir::fd::create_fd(fd_num, &fd_spec).unwrap_or_else(|err| {
-                eprintln!("failed to create fd {}: {}", fd_str, err);
-                std::process::exit(exitcode::OSERR);
-            })
-        }).collect::<Vec<_>>()
-    }).collect::<Vec<_>>();
-
-    let mut procs = Procs::new();
-    for (spec, proc_fds) in input.procs.into_iter().zip(fds.iter_mut()) {
-        let env = environ::build(std::env::vars(), &spec.env);
-
-        // Fork the child process.
-        let child_pid = sys::fork().unwrap_or_else(|err| {
-            panic!("failed to fork: {}", err);
-        });
-
-        if child_pid == 0 {
-            // Child process.
-
-            // Close the read end of the error pipe.
-            err_read.close().unwrap();
-
-            let mut ok = true;
-            for fd in &mut *proc_fds {
-                fd.set_up_in_child().unwrap_or_else(|err| {
-                    err_write.send(&format!("failed to set up fd {}: {}", fd.get_fd(), err));
-                    ok = false;
-                });
-            }
-            if !ok {
-                std::process::exit(exitcode::OSERR);
-            }
-
-            let exe = &spec.argv[0];
-            let err = sys::execve(exe.clone(), spec.argv.clone(), env).unwrap_err();
-
-            // If we got here, exec failed; send the error to the parent process.
-            err_write.send(&format!("exec: {}: {}", exe, err));
-            std::process::exit(exitcode::OSERR);
-        }
-
-        else {
-            // Parent process.  Construct the record of this running proc.
-            procs.push(child_pid);
-        }
+    if args.daemon {
+        args.output = Some(daemonize(args.output.take()));
     }
 
-    // Install a SIGCHLD signal handler that sets a flag.  This way we know when
-    // a proc has terminated.
-    let sigchld_flag = sig::SignalFlag::new(libc::SIGCHLD);
-
-    // Close the write end of the error pipe.
-    err_write.close().unwrap();
-
-    // Finish setting up all file descriptors for all procs.
-    for proc_fds in &mut fds {
-        for fd in proc_fds {
-            let f = fd.get_fd();
-            match (*fd).set_up_in_parent() {
-                Err(err) => result.errors.push(format!("failed to set up fd {}: {}", f, err)),
-                Ok(None) => (),
-                Ok(Some(read)) => select.insert_reader(read),
-            };
-        }
+    let names = input.procs.iter().map(|p| p.name.clone()).collect::<Vec<_>>();
+    let mut runner = ir::run::Runner::new(input).unwrap_or_else(|err| {
+        eprintln!("failed to set up: {}", err);
+        std::process::exit(exitcode::OSERR);
+    });
+    if let Some(path) = &args.state_file {
+        runner.set_state_file(path, args.state_interval);
     }
-
-    // Clean up procs that might have completed already.
-    procs.wait_any();
-    // Now we wait for the procs to run.
-    while select.any() {
-        match select.select(None) {
-            Ok(_) => {
-                // select did something.  Keep going.
-            },
-            Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => {
-                // select interrupted, possibly by SIGCHLD.  Keep going.
-            },
-            Err(err) => {
-                panic!("select failed: {}", err)
-            },
-        };
-        // If we received SIGCHLD, clean up any terminated procs.
-        if sigchld_flag.get() {
-            procs.wait_any();
+    if let Some(path) = &args.ctl_socket {
+        runner.set_ctl_socket(path);
+    }
+    for (name, value) in &args.vars {
+        runner.set_var(name, value);
+    }
+    if args.format == Format::Ndjson {
+        let names = names.clone();
+        runner.on_progress(move |event| print_event(&names, event));
+    }
+    let result = runner.run().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        if let Some(path) = &args.output {
+            // Leave the error where the caller will look for results.
+            let _ = ir::fdio::write_file_atomic(
+                path, format!("{}\n", json!({"error": err.to_string()})).as_bytes());
         }
-    };
-    std::mem::drop(select);
-
-    // Wait for all remaining procs to terminate and clean them up.
-    procs.wait_all();
-
-    // Collect proc results.
-    result.procs = procs.into_iter()
-        .zip(fds.into_iter())
-        .map(|(proc, fds)| {
-            let (_, status, rusage) = proc.wait_info.unwrap();
-
-            // Build the proc res.
-            let mut proc_res = res::ProcRes::new(proc.pid, status, rusage);
+        std::process::exit(match err {
+            ir::run::Error::Spec(_) => exitcode::DATAERR,
+            ir::run::Error::Setup(_) => exitcode::OSERR,
+        });
+    });
 
-            // Build fd res's into it.
-            for mut fd in fds {
-                match fd.clean_up_in_parent() {
-                    Ok(Some(fd_result)) => {
-                        proc_res.fds.insert(
-                            ir::fd::get_fd_name(fd.get_fd()), fd_result);
-                    }
-                    Ok(None) => {
-                    },
-                    Err(err) => {
-                        proc_res.fds.insert(
-                            ir::fd::get_fd_name(fd.get_fd()), res::FdRes::Error {});
-                        result.errors.push(
-                            format!("failed to clean up fd {}: {}", fd.get_fd(), err));
-                    },
-                };
+    if let Some(path) = &args.output {
+        res::write_file(path, &result).unwrap_or_else(|err| {
+            eprintln!("failed to write {}: {}", path.display(), err);
+            std::process::exit(exitcode::CANTCREAT);
+        });
+    }
+    if let Some(path) = &args.junit {
+        write_report(path, &ir::report::junit(&result, &names));
+    }
+    if let Some(path) = &args.tap {
+        write_report(path, &ir::report::tap(&result, &names));
+    }
+    match args.format {
+        Format::Json => {
+            if args.output.is_none() {
+                res::print(&result);
+                println!();
             }
+        },
+        Format::Ndjson => {
+            let num_errors = result.errors.len()
+                + result.procs.iter().map(|p| p.errors.len()).sum::<usize>();
+            print_line(&json!({
+                "event": "summary",
+                "num_procs": result.procs.len(),
+                "num_errors": num_errors,
+                "num_failed": result.num_failed(),
+                "errors": result.errors,
+            }));
+        },
+        Format::Junit => print!("{}", ir::report::junit(&result, &names)),
+        Format::Tap => print!("{}", ir::report::tap(&result, &names)),
+    }
 
-            proc_res
-        }).collect();
-
-    // Transfer errors retrieved from the error pipe buffer into results.
-    result.errors.append(&mut err_read.get_errors());
-
-    res::print(&result);
-    println!("");
-
-    std::process::exit(if result.errors.len() > 0 { 1 } else { exitcode::OK });
+    let ok = !result.has_errors() && result.num_failed() == 0;
+    std::process::exit(if ok { exitcode::OK } else { 1 });
 }
-
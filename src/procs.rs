@@ -0,0 +1,234 @@
+use crate::sys;
+use crate::sys::fd_t;
+use libc::{c_int, pid_t};
+use std::io;
+use std::vec::Vec;
+
+//------------------------------------------------------------------------------
+
+/// A proc we're running, or that has terminated.
+pub struct Proc {
+    pub pid: pid_t,
+
+    /// A pidfd referring to the proc, while it's running, if the OS supports
+    /// these.
+    pub pidfd: Option<fd_t>,
+
+    /// None while the proc is running; the result of wait4() once the proc has
+    /// terminated and been cleaned up.
+    pub wait_info: Option<sys::WaitInfo>,
+}
+
+impl Proc {
+    pub fn is_running(&self) -> bool {
+        self.wait_info.is_none()
+    }
+}
+
+/// Opens a pidfd for `pid`, if the OS supports these.
+#[cfg(target_os = "linux")]
+fn open_pidfd(pid: pid_t) -> Option<fd_t> {
+    // Fails on kernels without pidfd support; fall back to waiting by pid.
+    sys::pidfd_open(pid).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_pidfd(_pid: pid_t) -> Option<fd_t> {
+    None
+}
+
+/// Waits for a proc, via its pidfd if it has one, else by its pid.  Never
+/// waits for any other process, so that we don't steal the exit status of
+/// children that aren't ours.
+fn wait(proc: &Proc, block: bool) -> io::Result<Option<sys::WaitInfo>> {
+    loop {
+        let result = match proc.pidfd {
+            #[cfg(target_os = "linux")]
+            Some(pidfd) => sys::waitid_pidfd(pidfd, block),
+            _ => sys::wait4(proc.pid, block),
+        };
+        match result {
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted =>
+                // Interrupted by a signal.  Keep going.
+                continue,
+            result => return result,
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+
+pub struct Procs {
+    procs: Vec<Proc>,
+    num_running: usize,
+}
+
+impl Default for Procs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Procs {
+    pub fn new() -> Self {
+        Self { procs: Vec::new(), num_running: 0 }
+    }
+
+    /// Adds a proc that's been started.  Returns its index, and pidfd if any.
+    /// The pidfd becomes readable when the proc terminates; call
+    /// `wait_pidfd()` then.
+    pub fn push(&mut self, pid: pid_t) -> (usize, Option<fd_t>) {
+        let pidfd = open_pidfd(pid);
+        self.procs.push(Proc { pid, pidfd, wait_info: None });
+        self.num_running += 1;
+        (self.procs.len() - 1, pidfd)
+    }
+
+    pub fn get(&self, index: usize) -> &Proc {
+        &self.procs[index]
+    }
+
+    pub fn len(&self) -> usize { self.procs.len() }
+
+    pub fn is_empty(&self) -> bool { self.procs.is_empty() }
+
+    pub fn any_running(&self) -> bool { self.num_running > 0 }
+
+    /// Number of procs that haven't been waited yet.
+    pub fn num_running(&self) -> usize { self.num_running }
+
+    /// Closes a proc's pidfd, if it has one, for instance if it can't be
+    /// selected.  The proc is then waited by pid, on SIGCHLD.
+    pub fn close_pidfd(&mut self, index: usize) {
+        if let Some(pidfd) = self.procs[index].pidfd.take() {
+            let _ = sys::close(pidfd);
+        }
+    }
+
+    /// Stores the wait info for a proc that has terminated.
+    fn set_wait_info(&mut self, index: usize, wait_info: sys::WaitInfo) {
+        let proc = &mut self.procs[index];
+        assert!(proc.wait_info.replace(wait_info).is_none());
+        self.num_running -= 1;
+    }
+
+    fn wait_one(&mut self, index: usize, block: bool) -> bool {
+        match wait(&self.procs[index], block) {
+            Ok(Some(wait_info)) => {
+                self.set_wait_info(index, wait_info);
+                true
+            },
+            Ok(None) => false,
+            Err(err) => panic!("wait failed: {}", err),
+        }
+    }
+
+    /// Waits for the proc with `pidfd`, which has become readable.  Returns the
+    /// proc's index, or None if the pidfd isn't one of ours or the proc hasn't
+    /// terminated.  Once the proc is waited, the caller should stop selecting
+    /// the pidfd, and then close it with `close_pidfd()`.
+    pub fn wait_pidfd(&mut self, pidfd: fd_t) -> Option<usize> {
+        let index = self.procs.iter().position(|p| p.pidfd == Some(pidfd))?;
+        if self.procs[index].is_running() && self.wait_one(index, false) {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Waits any procs without pidfds that terminated and are zombies, and
+    /// stores their wait info.  Call this on SIGCHLD.  Returns the indices of
+    /// procs that terminated.
+    pub fn wait_any(&mut self) -> Vec<usize> {
+        (0 .. self.procs.len()).filter(|&i| {
+            let proc = &self.procs[i];
+            proc.is_running() && proc.pidfd.is_none() && self.wait_one(i, false)
+        }).collect()
+    }
+
+    /// Sends a signal to a running proc.  Uses the proc's pidfd, if it has
+    /// one, so that we never signal an unrelated process that reused its pid.
+    pub fn send_signal(&self, index: usize, signum: c_int) -> io::Result<()> {
+        let proc = &self.procs[index];
+        if !proc.is_running() {
+            // The proc has been waited, so its pid may already be reused.
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        match proc.pidfd {
+            #[cfg(target_os = "linux")]
+            Some(pidfd) => sys::pidfd_send_signal(pidfd, signum),
+            // Not yet waited, so the pid still refers to our zombie or child.
+            _ => sys::kill(proc.pid, signum),
+        }
+    }
+}
+
+impl IntoIterator for Procs {
+    type Item = Proc;
+    type IntoIter = std::vec::IntoIter<Proc>;
+
+    fn into_iter(self) -> Self::IntoIter { self.procs.into_iter() }
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn(argv: &[&str]) -> pid_t {
+        std::process::Command::new(argv[0]).args(&argv[1 ..])
+            .spawn().unwrap().id() as pid_t
+    }
+
+    /// Waits for the proc at `index` to terminate, and returns its status.
+    fn wait_for(procs: &mut Procs, index: usize) -> c_int {
+        while procs.get(index).is_running() {
+            match procs.get(index).pidfd {
+                Some(pidfd) => if procs.wait_pidfd(pidfd).is_some() {
+                    procs.close_pidfd(index);
+                },
+                None => { procs.wait_any(); },
+            };
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        procs.get(index).wait_info.unwrap().1
+    }
+
+    #[test]
+    fn exit_status() {
+        let mut procs = Procs::new();
+        let (index, _) = procs.push(spawn(&["/bin/sh", "-c", "exit 3"]));
+        let status = wait_for(&mut procs, index);
+        assert!(unsafe { libc::WIFEXITED(status) });
+        assert_eq!(unsafe { libc::WEXITSTATUS(status) }, 3);
+        assert!(! procs.any_running());
+    }
+
+    #[test]
+    fn send_signal() {
+        let mut procs = Procs::new();
+        let (index, _) = procs.push(spawn(&["/bin/sleep", "10"]));
+        procs.send_signal(index, libc::SIGTERM).unwrap();
+        let status = wait_for(&mut procs, index);
+        assert!(unsafe { libc::WIFSIGNALED(status) });
+        assert_eq!(unsafe { libc::WTERMSIG(status) }, libc::SIGTERM);
+
+        // Once waited, the proc can't be signaled.
+        assert!(procs.send_signal(index, libc::SIGTERM).is_err());
+    }
+
+    #[test]
+    fn wait_pidfd_running() {
+        let mut procs = Procs::new();
+        let (index, pidfd) = procs.push(spawn(&["/bin/sleep", "10"]));
+        if let Some(pidfd) = pidfd {
+            // Nothing to wait yet.
+            assert_eq!(procs.wait_pidfd(pidfd), None);
+            assert!(procs.get(index).is_running());
+        }
+        procs.send_signal(index, libc::SIGKILL).unwrap();
+        wait_for(&mut procs, index);
+        assert_eq!(procs.get(index).pidfd, None);
+    }
+}
@@ -0,0 +1,235 @@
+//! Reports of results for CI systems, in JUnit XML and TAP formats.
+//!
+//! Each proc is a test case.  A proc with an expected outcome passes if it met
+//! it; one without passes if it exited with code 0.  A proc with errors is an
+//! error, rather than a failure, and a skipped proc is skipped.
+
+use crate::res::{ProcRes, ProcState, Res};
+
+//------------------------------------------------------------------------------
+
+/// How a proc fared, as a test.
+enum Outcome {
+    Passed,
+    /// Failed, with descriptions of what failed.
+    Failed(Vec<String>),
+    /// Had errors, with their messages.
+    Error(Vec<String>),
+    /// Skipped, with the reason.
+    Skipped(String),
+}
+
+fn get_outcome(proc_res: &ProcRes) -> Outcome {
+    if !proc_res.errors.is_empty() {
+        Outcome::Error(proc_res.errors.iter().map(|e| e.message.clone()).collect())
+    } else if proc_res.state == ProcState::Skipped {
+        Outcome::Skipped(proc_res.skip_reason.clone().unwrap_or_else(|| "skipped".to_string()))
+    } else if proc_res.state != ProcState::Terminated {
+        Outcome::Error(vec![proc_res.describe_status()])
+    } else {
+        match proc_res.passed {
+            Some(true) => Outcome::Passed,
+            Some(false) => Outcome::Failed(proc_res.failures.clone()),
+            None if proc_res.exit_code == Some(0) => Outcome::Passed,
+            None => Outcome::Failed(vec![proc_res.describe_status()]),
+        }
+    }
+}
+
+/// Returns the name of a proc, for its test case.
+fn get_name(names: &[Option<String>], index: usize) -> String {
+    match names.get(index) {
+        Some(Some(name)) => name.clone(),
+        _ => format!("proc {}", index),
+    }
+}
+
+//------------------------------------------------------------------------------
+// JUnit XML
+//------------------------------------------------------------------------------
+
+/// Escapes text for XML content or attribute values.  Characters XML doesn't
+/// allow are replaced.
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            '\u{0}' ..= '\u{1f}' | '\u{fffe}' | '\u{ffff}' => escaped.push('\u{fffd}'),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Formats results as a JUnit XML test suite.  `names` are the proc names.
+pub fn junit(res: &Res, names: &[Option<String>]) -> String {
+    let (mut num_failures, mut num_errors, mut num_skipped) = (0, 0, 0);
+    let mut total_time = 0.;
+    let mut cases = String::new();
+    for (index, proc_res) in res.procs.iter().enumerate() {
+        let time = proc_res.elapsed.unwrap_or(0.);
+        total_time += time;
+        cases.push_str(&format!(
+            "  <testcase name=\"{}\" classname=\"ir\" time=\"{:.3}\">\n",
+            escape_xml(&get_name(names, index)), time));
+        match get_outcome(proc_res) {
+            Outcome::Passed => (),
+            Outcome::Failed(failures) => {
+                num_failures += 1;
+                cases.push_str(&format!(
+                    "    <failure message=\"{}\">{}</failure>\n",
+                    escape_xml(&failures[0]), escape_xml(&failures.join("\n"))));
+            },
+            Outcome::Error(errors) => {
+                num_errors += 1;
+                cases.push_str(&format!(
+                    "    <error message=\"{}\">{}</error>\n",
+                    escape_xml(&errors[0]), escape_xml(&errors.join("\n"))));
+            },
+            Outcome::Skipped(reason) => {
+                num_skipped += 1;
+                cases.push_str(&format!("    <skipped message=\"{}\"/>\n", escape_xml(&reason)));
+            },
+        }
+        for (fd, tag) in &[("stdout", "system-out"), ("stderr", "system-err")] {
+            if let Some(text) = proc_res.get_text(fd) {
+                cases.push_str(&format!("    <{}>{}</{}>\n", tag, escape_xml(&text), tag));
+            }
+        }
+        cases.push_str("  </testcase>\n");
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuite name=\"ir\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" \
+         time=\"{:.3}\">\n",
+        res.procs.len(), num_failures, num_errors, num_skipped, total_time));
+    xml.push_str(&cases);
+    if !res.errors.is_empty() {
+        // Errors not attributable to a proc belong to the suite.
+        let messages = res.errors.iter().map(|e| e.message.as_str()).collect::<Vec<_>>();
+        xml.push_str(&format!("  <system-err>{}</system-err>\n", escape_xml(&messages.join("\n"))));
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+//------------------------------------------------------------------------------
+// TAP
+//------------------------------------------------------------------------------
+
+/// Escapes a test description for TAP, which must be a single line, and in
+/// which `#` starts a directive.
+fn escape_tap(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('#', "\\#").replace(['\n', '\r'], " ")
+}
+
+/// Formats a YAML diagnostic block, with a list of messages under `key`.
+fn tap_diagnostics(key: &str, messages: &[String]) -> String {
+    let mut yaml = format!("  ---\n  {}:\n", key);
+    for message in messages {
+        // A JSON string is a valid YAML double-quoted scalar.
+        yaml.push_str(&format!("    - {}\n", serde_json::Value::from(message.as_str())));
+    }
+    yaml.push_str("  ...\n");
+    yaml
+}
+
+/// Formats results as a TAP version 13 stream.  `names` are the proc names.
+pub fn tap(res: &Res, names: &[Option<String>]) -> String {
+    let mut tap = format!("TAP version 13\n1..{}\n", res.procs.len());
+    for (index, proc_res) in res.procs.iter().enumerate() {
+        let name = escape_tap(&get_name(names, index));
+        let num = index + 1;
+        match get_outcome(proc_res) {
+            Outcome::Passed =>
+                tap.push_str(&format!("ok {} - {}\n", num, name)),
+            Outcome::Failed(failures) => {
+                tap.push_str(&format!("not ok {} - {}\n", num, name));
+                tap.push_str(&tap_diagnostics("failures", &failures));
+            },
+            Outcome::Error(errors) => {
+                tap.push_str(&format!("not ok {} - {}\n", num, name));
+                tap.push_str(&tap_diagnostics("errors", &errors));
+            },
+            Outcome::Skipped(reason) =>
+                tap.push_str(&format!(
+                    "ok {} - {} # SKIP {}\n", num, name, escape_tap(&reason))),
+        }
+    }
+    for error in &res.errors {
+        tap.push_str(&format!("# error: {}\n", escape_tap(&error.message)));
+    }
+    tap
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn res() -> Res {
+        let rusage = unsafe { std::mem::zeroed() };
+        let mut passed = ProcRes::new(1, 0, rusage);
+        passed.elapsed = Some(0.25);
+        let mut failed = ProcRes::new(2, 0, rusage);
+        failed.passed = Some(false);
+        failed.failures = vec!["stdout doesn't contain \"<ok>\"".to_string()];
+        let mut res = Res::new();
+        res.procs = vec![
+            passed,
+            failed,
+            ProcRes::new(3, libc::SIGKILL, rusage),
+            ProcRes::skipped("dependency build didn't succeed"),
+        ];
+        res
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(escape_xml("a<b & 'c'\u{1}\n"), "a&lt;b &amp; &apos;c&apos;\u{fffd}\n");
+        assert_eq!(escape_tap("a # b\nc"), "a \\# b c");
+    }
+
+    #[test]
+    fn junit_suite() {
+        let names = vec![Some("pass".to_string()), Some("fail".to_string())];
+        let xml = junit(&res(), &names);
+        assert!(xml.contains(
+            "<testsuite name=\"ir\" tests=\"4\" failures=\"2\" errors=\"0\" skipped=\"1\" \
+             time=\"0.250\">"));
+        assert!(xml.contains("<testcase name=\"pass\" classname=\"ir\" time=\"0.250\">\n  </testcase>"));
+        assert!(xml.contains(
+            "<failure message=\"stdout doesn&apos;t contain &quot;&lt;ok&gt;&quot;\">"));
+        assert!(xml.contains("<testcase name=\"proc 2\""));
+        assert!(xml.contains("<failure message=\"terminated by signal 9\">"));
+        assert!(xml.contains("<skipped message=\"dependency build didn&apos;t succeed\"/>"));
+    }
+
+    #[test]
+    fn tap_stream() {
+        assert_eq!(tap(&res(), &[]), "\
+TAP version 13
+1..4
+ok 1 - proc 0
+not ok 2 - proc 1
+  ---
+  failures:
+    - \"stdout doesn't contain \\\"<ok>\\\"\"
+  ...
+not ok 3 - proc 2
+  ---
+  failures:
+    - \"terminated by signal 9\"
+  ...
+ok 4 - proc 3 # SKIP dependency build didn't succeed
+");
+    }
+}
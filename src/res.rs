@@ -1,9 +1,12 @@
 /// Named "Res" to avoid confusion with the `Result` types.
 
+use crate::err::{ProcError, RunError};
+use crate::fdio;
 use crate::spec::CaptureFormat;
 use libc::{c_int, pid_t, rusage};
+use std::borrow::Cow;
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use serde::{Serialize};
 
 //------------------------------------------------------------------------------
@@ -130,19 +133,44 @@ impl FdRes {
 
 //------------------------------------------------------------------------------
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcState {
+    /// The program ran and terminated, by exiting or by a signal.
+    Terminated,
+    /// Setting up the proc failed, so the program never ran.
+    SetupFailed,
+    /// Executing the program failed.
+    ExecFailed,
+    /// The proc wasn't started, as its dependencies weren't satisfied, or the
+    /// run was cancelled.
+    Skipped,
+}
+
 #[derive(Serialize)]
 pub struct ProcRes {
-    /// The pid with which the process ran.
-    pub pid: pid_t,
+    /// The pid with which the process ran, if it was started.
+    pub pid: Option<pid_t>,
 
-    /// Pid status, which combines exit code and signum.
-    pub status: c_int,
-    /// Exit code (low 8 bits), if terminated with exit.
+    /// Whether the program ran.
+    pub state: ProcState,
+
+    /// Pid status, which combines exit code and signum, if it was started.
+    pub status: Option<c_int>,
+    /// Exit code (low 8 bits), if the program ran and terminated with exit.
     pub exit_code: Option<i32>,
     /// Signal number, if terminated by signal.
     pub signum: Option<i32>,
     /// Whether the process produced a core dump, if terminated by signal.
     pub core_dump: bool,
+    /// Whether the proc ran past its timeout, and was sent SIGTERM.
+    pub timed_out: bool,
+    /// Whether the proc was to be retried or run again, but the run was
+    /// cancelled first.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cancelled: bool,
+    /// Wall time in s from start until termination, if it was started.
+    pub elapsed: Option<f64>,
 
     /// Fd results.
     /// FIXME: Associative map from fd instead?
@@ -151,30 +179,114 @@ pub struct ProcRes {
     /// Resource usage for the process itself.
     #[serde(with = "libc_serde::Rusage")]
     pub rusage: rusage,
+
+    /// Errors setting up, running, or cleaning up the proc.
+    pub errors: Vec<ProcError>,
+
+    /// If the proc was retried, results of the earlier attempts.  The other
+    /// fields are for the last attempt.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<ProcRes>,
+
+    /// If the proc was repeated, results of the earlier runs, not including
+    /// warmup runs.  The other fields are for the last run.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub runs: Vec<ProcRes>,
+    /// If the proc was repeated, statistics over all its runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Stats>,
+
+    /// If the proc has an expected outcome, whether it was met.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passed: Option<bool>,
+    /// Expectations that weren't met.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<String>,
+
+    /// Why the proc was skipped, if it was.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_reason: Option<String>,
 }
 
 fn time_to_sec(time: libc::timeval) -> f64 {
     time.tv_sec as f64 + 1e-6 * time.tv_usec as f64
 }
 
+/// Splits a pid status into exit code, signum, and whether a core was dumped.
+pub fn split_status(status: c_int) -> (Option<i32>, Option<i32>, bool) {
+    unsafe {
+        if libc::WIFEXITED(status) {
+            (Some(libc::WEXITSTATUS(status)), None, false)
+        } else {
+            (None, Some(libc::WTERMSIG(status)), libc::WCOREDUMP(status))
+        }
+    }
+}
+
 impl ProcRes {
     pub fn new(pid: pid_t, status: c_int, rusage: rusage) -> ProcRes {
-        let (exit_code, signum, core_dump)= unsafe {
-            if libc::WIFEXITED(status) {
-                (Some(libc::WEXITSTATUS(status)), None, false)
-            } else {
-                (None, Some(libc::WTERMSIG(status)), libc::WCOREDUMP(status))
-            }
-        };
+        let (exit_code, signum, core_dump) = split_status(status);
         ProcRes {
-            pid,
-            status,
+            pid: Some(pid),
+            state: ProcState::Terminated,
+            status: Some(status),
             exit_code, signum, core_dump,
+            timed_out: false,
+            cancelled: false,
+            elapsed: None,
             fds: BTreeMap::new(),
             rusage,
+            errors: Vec::new(),
+            attempts: Vec::new(),
+            runs: Vec::new(),
+            stats: None,
+            passed: None,
+            failures: Vec::new(),
+            skip_reason: None,
         }
     }
 
+    /// Returns results for a proc that was never started.
+    pub fn not_started(state: ProcState) -> ProcRes {
+        ProcRes {
+            pid: None,
+            state,
+            status: None,
+            exit_code: None,
+            signum: None,
+            core_dump: false,
+            timed_out: false,
+            cancelled: false,
+            elapsed: None,
+            fds: BTreeMap::new(),
+            // No usage, as there was no process.
+            rusage: unsafe { std::mem::zeroed() },
+            errors: Vec::new(),
+            attempts: Vec::new(),
+            runs: Vec::new(),
+            stats: None,
+            passed: None,
+            failures: Vec::new(),
+            skip_reason: None,
+        }
+    }
+
+    /// Returns results for a proc that was skipped, for `reason`.
+    pub fn skipped(reason: &str) -> ProcRes {
+        let mut proc_res = ProcRes::not_started(ProcState::Skipped);
+        proc_res.skip_reason = Some(reason.to_string());
+        proc_res
+    }
+
+    /// Marks the proc as having failed before its program ran.  Its status is
+    /// then that of the failed child process, not of the program.
+    pub fn set_failed(&mut self, state: ProcState) {
+        self.state = state;
+        self.exit_code = None;
+        self.signum = None;
+        self.core_dump = false;
+    }
+
     /// User time in s.
     pub fn utime(&self) -> f64 {
         time_to_sec(self.rusage.ru_utime)
@@ -184,20 +296,133 @@ impl ProcRes {
     pub fn stime(&self) -> f64 {
         time_to_sec(self.rusage.ru_stime)
     }
+
+    /// Describes how the program terminated, or that it didn't run.
+    pub fn describe_status(&self) -> String {
+        match (self.state, self.exit_code, self.signum) {
+            (ProcState::Terminated, Some(exit_code), _) =>
+                format!("exited with code {}", exit_code),
+            (ProcState::Terminated, None, Some(signum)) =>
+                format!("terminated by signal {}", signum),
+            (ProcState::Terminated, None, None) => "terminated".to_string(),
+            (ProcState::Skipped, _, _) => "skipped".to_string(),
+            _ => "program didn't run".to_string(),
+        }
+    }
+
+    /// Returns the output captured on fd `name`, as text, if it was captured.
+    pub fn get_text(&self, name: &str) -> Option<Cow<'_, str>> {
+        match self.fds.get(name)? {
+            FdRes::CaptureUtf8 { text } => Some(Cow::Borrowed(text)),
+            FdRes::CaptureBase64 { data, .. } =>
+                base64::decode_config(data, base64::STANDARD_NO_PAD).ok()
+                    .map(|data| Cow::Owned(String::from_utf8_lossy(&data).into_owned())),
+            _ => None,
+        }
+    }
+
+    /// Maximum resident set size in KiB.
+    pub fn maxrss(&self) -> f64 {
+        self.rusage.ru_maxrss as f64
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Statistics of a quantity over several runs.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Stat {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    /// Sample standard deviation, or 0 for a single value.
+    pub stddev: f64,
+}
+
+impl Stat {
+    /// Returns statistics of `values`, or None if there are none.
+    pub fn new(values: &[f64]) -> Option<Stat> {
+        let len = values.len();
+        if len == 0 {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let n = len as f64;
+        let mean = sorted.iter().sum::<f64>() / n;
+        let median = if len % 2 == 1 {
+            sorted[len / 2]
+        } else {
+            (sorted[len / 2 - 1] + sorted[len / 2]) / 2.
+        };
+        let stddev = if len > 1 {
+            (sorted.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.)).sqrt()
+        } else {
+            0.
+        };
+        Some(Stat { min: sorted[0], max: sorted[len - 1], mean, median, stddev })
+    }
+}
+
+/// Statistics over the runs of a repeated proc.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Stats {
+    /// Number of runs in which the program ran, over which the others are.
+    pub count: usize,
+    pub elapsed: Stat,
+    pub utime: Stat,
+    pub stime: Stat,
+    pub maxrss: Stat,
+}
+
+impl Stats {
+    /// Returns statistics over those `runs` in which the program ran, or
+    /// None if it never did.
+    pub fn new<'a, I>(runs: I) -> Option<Stats>
+    where I: IntoIterator<Item = &'a ProcRes>
+    {
+        let runs = runs.into_iter()
+            .filter(|r| r.state == ProcState::Terminated && r.elapsed.is_some())
+            .collect::<Vec<_>>();
+        let stat = |get: fn(&ProcRes) -> f64| {
+            Stat::new(&runs.iter().map(|r| get(r)).collect::<Vec<_>>())
+        };
+        Some(Stats {
+            count: runs.len(),
+            elapsed: stat(|r| r.elapsed.unwrap())?,
+            utime: stat(ProcRes::utime)?,
+            stime: stat(ProcRes::stime)?,
+            maxrss: stat(ProcRes::maxrss)?,
+        })
+    }
 }
 
 //------------------------------------------------------------------------------
 
 #[derive(Default, Serialize)]
 pub struct Res {
+    /// The ID of the run, as substituted for `${run.id}`.
+    pub run_id: String,
     pub procs: Vec<ProcRes>,
-    pub errors: Vec<String>,
+    /// Errors not attributable to a single proc.
+    pub errors: Vec<RunError>,
 }
 
 impl Res {
     pub fn new() -> Res {
         Res { ..Default::default() }
     }
+
+    /// True if there were any errors, global or for any proc.
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty() || self.procs.iter().any(|p| !p.errors.is_empty())
+    }
+
+    /// Number of procs that didn't meet their expected outcomes.
+    pub fn num_failed(&self) -> usize {
+        self.procs.iter().filter(|p| p.passed == Some(false)).count()
+    }
 }
 
 //------------------------------------------------------------------------------
@@ -206,3 +431,32 @@ pub fn print(result: &Res) {
     serde_json::to_writer(std::io::stdout(), result).unwrap();
 }
 
+/// Writes results to a file, atomically, so that the file appears only when
+/// complete.
+pub fn write_file(path: &Path, result: &Res) -> std::io::Result<()> {
+    let mut data = serde_json::to_vec(result)?;
+    data.push(b'\n');
+    fdio::write_file_atomic(path, &data)
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stat() {
+        assert_eq!(Stat::new(&[]), None);
+        assert_eq!(
+            Stat::new(&[3.]),
+            Some(Stat { min: 3., max: 3., mean: 3., median: 3., stddev: 0. }));
+        assert_eq!(
+            Stat::new(&[4., 1., 2., 5.]),
+            Some(Stat {
+                min: 1., max: 5., mean: 3., median: 3.,
+                stddev: (10f64 / 3.).sqrt(),
+            }));
+        assert_eq!(Stat::new(&[2., 9., 1.]).unwrap().median, 2.);
+    }
+}
@@ -0,0 +1,1447 @@
+//! Running procs, as described by a spec.
+//!
+//! `Runner` does everything the `ir` command does, except loading the spec and
+//! printing the results, so that it can be embedded in other programs.
+
+use crate::ctl;
+use crate::environ;
+use crate::err::{ProcError, RunError, Stage};
+use crate::err_pipe::{new_err_pipe, ErrPipeRead, ErrPipeWrite};
+use crate::expect;
+use crate::fd::{create_fd, get_fd_name, parse_fd, Fd};
+use crate::procs::Procs;
+use crate::res;
+use crate::sel;
+use crate::sel::Read;
+use crate::sig::SignalFd;
+use crate::spawn;
+use crate::spec;
+use crate::state;
+use crate::sys;
+use crate::sys::fd_t;
+use crate::vars;
+use libc::{c_int, pid_t};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+
+//------------------------------------------------------------------------------
+
+/// An error that prevents running procs at all.  Errors with individual procs
+/// are reported in their results instead.
+#[derive(Debug)]
+pub enum Error {
+    /// The spec is invalid.
+    Spec(String),
+    /// Setting up to run procs failed.
+    Setup(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Spec(msg) => f.write_str(msg),
+            Error::Setup(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Returns a fn that wraps an error as a setup error.
+fn setup_err<E: fmt::Display>(what: &'static str) -> impl Fn(E) -> Error {
+    move |err| Error::Setup(format!("failed to {}: {}", what, err))
+}
+
+//------------------------------------------------------------------------------
+
+/// Progress while procs run.
+pub enum Event<'a> {
+    /// A proc's child process was started.
+    Started { index: usize, pid: pid_t },
+    /// A proc's child process terminated, and was waited.
+    Terminated { index: usize, pid: pid_t, status: c_int },
+    /// An error occurred for a proc.
+    Error(&'a ProcError),
+    /// A proc's attempt failed, and it will be retried.
+    Retry { index: usize, result: &'a res::ProcRes },
+    /// A run of a repeated proc finished, and it will run again.
+    Repeat { index: usize, result: &'a res::ProcRes },
+    /// A proc finished: it terminated, and its results are complete.
+    Finished { index: usize, result: &'a res::ProcRes },
+}
+
+/// Called with progress events.
+pub type ProgressFn = Box<dyn FnMut(&Event)>;
+
+struct Progress(Option<ProgressFn>);
+
+impl Progress {
+    fn emit(&mut self, event: Event) {
+        if let Some(progress) = &mut self.0 {
+            progress(&event);
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+
+struct CancelState {
+    cancelled: AtomicBool,
+    /// Signal to send to running procs, or 0 if none is pending.
+    signum: AtomicI32,
+    /// Self-pipe to wake the runner.
+    read_fd: fd_t,
+    write_fd: fd_t,
+}
+
+impl Drop for CancelState {
+    fn drop(&mut self) {
+        let _ = sys::close(self.read_fd);
+        let _ = sys::close(self.write_fd);
+    }
+}
+
+/// A handle for cancelling a run, for instance from another thread.
+#[derive(Clone)]
+pub struct Cancel(Arc<CancelState>);
+
+impl Cancel {
+    fn new() -> io::Result<Self> {
+        let (read_fd, write_fd) = sys::pipe()?;
+        let state = CancelState {
+            cancelled: AtomicBool::new(false),
+            signum: AtomicI32::new(0),
+            read_fd,
+            write_fd,
+        };
+        for &fd in &[read_fd, write_fd] {
+            sys::set_nonblocking(fd)?;
+            sys::set_cloexec(fd)?;
+        }
+        Ok(Cancel(Arc::new(state)))
+    }
+
+    /// Cancels the run, by sending SIGTERM to all running procs.
+    pub fn cancel(&self) {
+        self.cancel_with_signal(libc::SIGTERM);
+    }
+
+    /// Cancels the run, by sending `signum` to all running procs.  May be
+    /// called again, for instance to follow up with SIGKILL.
+    pub fn cancel_with_signal(&self, signum: c_int) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.signum.store(signum, Ordering::SeqCst);
+        // If the pipe is full, a wakeup is already pending.
+        let _ = sys::write(self.0.write_fd, &[0]);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn get_fd(&self) -> fd_t {
+        self.0.read_fd
+    }
+
+    /// Consumes wakeups.  Returns the signal to send, if any.
+    fn take(&self) -> Option<c_int> {
+        let mut buf = [0u8; 64];
+        while let Ok(n) = sys::read(self.0.read_fd, &mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+        match self.0.signum.swap(0, Ordering::SeqCst) {
+            0 => None,
+            signum => Some(signum),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// What a selected fd belongs to.
+enum Source {
+    ErrPipe,
+    Sigchld,
+    Cancel,
+    Pidfd,
+    /// The control socket, for new connections.
+    CtlListen,
+    /// A control connection.
+    CtlConn,
+    /// A reader for fd `.1` of proc `.0`.
+    Reader(usize, usize),
+}
+
+/// What a timer is for.
+enum Timer {
+    /// Proc `.0` runs out of time.
+    Timeout(usize),
+    /// Proc `.0` is still running, after a timeout's grace period.
+    Kill(usize),
+    /// Proc `.0` is due to be retried.
+    Retry(usize),
+}
+
+impl Source {
+    /// True for fds we read until EOF before finishing.
+    fn is_reader(&self) -> bool {
+        matches!(self, Source::ErrPipe | Source::Reader(_, _))
+    }
+}
+
+/// Runs the procs of a spec.
+pub struct Runner {
+    input: spec::Input,
+    backend: spawn::Backend,
+    progress: Progress,
+    cancel: Cancel,
+    state_file: Option<state::StateFile>,
+    ctl_path: Option<PathBuf>,
+    /// Variables that override those in the spec.
+    vars: BTreeMap<String, String>,
+}
+
+impl Runner {
+    pub fn new(input: spec::Input) -> io::Result<Self> {
+        Ok(Self {
+            input,
+            backend: spawn::Backend::default(),
+            progress: Progress(None),
+            cancel: Cancel::new()?,
+            state_file: None,
+            ctl_path: None,
+            vars: BTreeMap::new(),
+        })
+    }
+
+    /// Sets how procs are started.
+    pub fn set_backend(&mut self, backend: spawn::Backend) {
+        self.backend = backend;
+    }
+
+    /// Sets a fn to call with progress events.  It's called on the thread
+    /// that calls `run()`, and shouldn't block.
+    pub fn on_progress<F>(&mut self, progress: F)
+    where F: FnMut(&Event) + 'static
+    {
+        self.progress = Progress(Some(Box::new(progress)));
+    }
+
+    /// Writes snapshots of the run to a state file at `path`, whenever a proc
+    /// changes state and otherwise every `interval`.
+    pub fn set_state_file(&mut self, path: &Path, interval: Duration) {
+        self.state_file = Some(state::StateFile::new(path, interval));
+    }
+
+    /// Serves control requests on a Unix domain socket at `path`, while procs
+    /// run.  See `ctl` for the protocol.
+    pub fn set_ctl_socket(&mut self, path: &Path) {
+        self.ctl_path = Some(path.to_path_buf());
+    }
+
+    /// Sets a variable to substitute into procs, overriding any in the spec.
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.to_string(), value.to_string());
+    }
+
+    /// Returns a handle to cancel the run.
+    pub fn get_cancel(&self) -> Cancel {
+        self.cancel.clone()
+    }
+
+    /// Runs all procs, waits for them to terminate, and returns their results.
+    pub fn run(self) -> Result<res::Res, Error> {
+        let mut run = Run::start(self)?;
+        run.wait();
+        Ok(run.into_res())
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// A run in progress.
+struct Run {
+    input: spec::Input,
+    backend: spawn::Backend,
+    progress: Progress,
+    cancel: Cancel,
+    run_id: String,
+
+    /// Selects all the fds we're waiting on.  We keep track of what each
+    /// selected fd is for.
+    select: sel::Select<'static>,
+    sources: BTreeMap<fd_t, Source>,
+
+    /// Read end of the pipe for passing errors from child to parent.
+    err_read: ErrPipeRead,
+    /// Write end, which child processes inherit, while procs remain to start.
+    err_write: Option<ErrPipeWrite>,
+    /// Readable on SIGCHLD.
+    sigchld: SignalFd,
+
+    /// Dependencies of each proc, by index.
+    after: Vec<Vec<(usize, spec::Condition)>>,
+    /// Prepared plan for each proc, without fds.
+    plans: Vec<spawn::Plan>,
+
+    /// Child processes, in the order started.
+    procs: Procs,
+    /// Index in `procs` of each proc's child process, once started.
+    child_index: Vec<Option<usize>>,
+    /// Index of the proc of each child process in `procs`.
+    proc_index: Vec<usize>,
+
+    /// Fds of each proc, while it runs.
+    fds: Vec<Vec<Box<dyn Fd>>>,
+    /// Number of each proc's readers not yet at EOF.
+    num_readers: Vec<usize>,
+    /// Errors for each proc, until it finishes.
+    errors: Vec<Vec<ProcError>>,
+    /// Procs whose child processes failed before their programs ran.
+    failed: BTreeMap<usize, res::ProcState>,
+    /// Results for each proc, once it has finished.
+    results: Vec<Option<res::ProcRes>>,
+    /// Errors not attributable to a single proc.
+    run_errors: Vec<RunError>,
+
+    /// Timers other than the state timer, and what each is for.
+    timers: BTreeMap<sel::TimerId, Timer>,
+    /// Timeout or kill timer of each proc, while it runs.
+    timeout_timers: Vec<Option<sel::TimerId>>,
+    /// Procs whose current attempt has timed out.
+    timed_out: Vec<bool>,
+    /// Results of each proc's earlier attempts, if it's been retried.
+    attempts: Vec<Vec<res::ProcRes>>,
+    /// Procs waiting for a retry delay before starting again.
+    retry_wait: Vec<bool>,
+    /// When each proc's current attempt started, and how long it ran.
+    start_times: Vec<Option<Instant>>,
+    elapsed: Vec<Option<f64>>,
+    /// Number of runs of each proc so far, including warmup runs.
+    num_runs: Vec<usize>,
+    /// Results of each proc's earlier runs, if it's repeated.
+    runs: Vec<Vec<res::ProcRes>>,
+    /// Result of each proc's last run, if it was a warmup run.
+    warmup_res: Vec<Option<res::ProcRes>>,
+
+    /// State of each proc, for snapshots.
+    states: Vec<state::Proc>,
+    state_file: Option<state::StateFile>,
+    /// Timer for the next periodic snapshot.
+    state_timer: Option<sel::TimerId>,
+    /// True if a proc's state changed since the last snapshot.
+    state_changed: bool,
+
+    /// The control server, if any.
+    ctl: Option<ctl::Server>,
+}
+
+impl Run {
+    /// Sets up, and starts procs without dependencies.
+    fn start(runner: Runner) -> Result<Self, Error> {
+        let Runner {
+            mut input, backend, progress, cancel, state_file, ctl_path, vars,
+        } = runner;
+
+        let run_id = vars::get_run_id();
+        let mut all_vars = std::mem::take(&mut input.vars);
+        all_vars.extend(vars);
+        input.expand(&vars::Vars::new(all_vars, &run_id))
+            .map_err(|err| Error::Spec(err.to_string()))?;
+        input.validate().map_err(|err| Error::Spec(err.to_string()))?;
+        let after = input.procs.iter().map(|spec| {
+            spec.after.iter()
+                .map(|a| (input.find_proc(&a.proc).unwrap(), a.condition))
+                .collect()
+        }).collect();
+
+        // FIXME: Parse when deserializing, rather than here.
+        for (fd_str, _) in input.procs.iter().flat_map(|spec| spec.fds.iter()) {
+            parse_fd(fd_str).map_err(|err| Error::Spec(
+                format!("failed to parse fd {}: {}", fd_str, err)))?;
+        }
+
+        let mut select = sel::Select::new();
+        let mut sources = BTreeMap::new();
+
+        let (err_read, err_write) = new_err_pipe()
+            .map_err(setup_err("create err pipe"))?;
+        // We drain the err pipe before finishing each proc, so don't block.
+        sys::set_nonblocking(err_read.get_fd())
+            .map_err(setup_err("set up err pipe"))?;
+        select.insert(err_read.get_fd(), sel::Interest::READ)
+            .map_err(setup_err("select err pipe"))?;
+        sources.insert(err_read.get_fd(), Source::ErrPipe);
+
+        // Receive SIGCHLD via an fd, which we select along with the others.
+        // This way we know when a proc has terminated.  Set this up before
+        // starting procs, so we don't miss any.
+        let sigchld = SignalFd::new(libc::SIGCHLD)
+            .map_err(setup_err("set up SIGCHLD"))?;
+        select.insert(sigchld.get_fd(), sel::Interest::READ)
+            .map_err(setup_err("select SIGCHLD"))?;
+        sources.insert(sigchld.get_fd(), Source::Sigchld);
+
+        select.insert(cancel.get_fd(), sel::Interest::READ)
+            .map_err(setup_err("select cancel"))?;
+        sources.insert(cancel.get_fd(), Source::Cancel);
+
+        let ctl = match ctl_path {
+            Some(path) => {
+                let ctl = ctl::Server::bind(&path).map_err(|err| Error::Setup(
+                    format!("failed to bind control socket {:?}: {}", path, err)))?;
+                select.insert(ctl.get_fd(), sel::Interest::READ)
+                    .map_err(setup_err("select control socket"))?;
+                sources.insert(ctl.get_fd(), Source::CtlListen);
+                Some(ctl)
+            },
+            None => None,
+        };
+
+        // Prepare everything each proc needs but its fds before starting any
+        // of them, so that the child process has nothing to do but set up fds
+        // and exec.
+        let plans = input.procs.iter().enumerate()
+            .map(|(i, spec)| {
+                let env = environ::build(std::env::vars(), &spec.env);
+                let mut plan = spawn::Plan::new(i, &spec.argv, &env)
+                    .map_err(|err| Error::Spec(
+                        format!("failed to prepare proc {:?}: {}", spec.argv, err)))?;
+                plan.sigmask = sigchld.get_child_mask();
+                if let Some(cwd) = &spec.cwd {
+                    plan.set_cwd(cwd).map_err(|err| Error::Spec(
+                        format!("bad cwd {:?}: {}", cwd, err)))?;
+                }
+                Ok(plan)
+            }).collect::<Result<Vec<_>, Error>>()?;
+
+        let num_procs = input.procs.len();
+        let states = input.procs.iter().enumerate()
+            .map(|(i, spec)| state::Proc::new(i, spec.name.clone()))
+            .collect();
+        let mut run = Run {
+            input, backend, progress, cancel, run_id, select, sources, err_read,
+            err_write: Some(err_write),
+            sigchld, after, plans,
+            procs: Procs::new(),
+            child_index: vec![None; num_procs],
+            proc_index: Vec::new(),
+            fds: (0 .. num_procs).map(|_| Vec::new()).collect(),
+            num_readers: vec![0; num_procs],
+            errors: (0 .. num_procs).map(|_| Vec::new()).collect(),
+            failed: BTreeMap::new(),
+            results: (0 .. num_procs).map(|_| None).collect(),
+            run_errors: Vec::new(),
+            timers: BTreeMap::new(),
+            timeout_timers: vec![None; num_procs],
+            timed_out: vec![false; num_procs],
+            attempts: (0 .. num_procs).map(|_| Vec::new()).collect(),
+            retry_wait: vec![false; num_procs],
+            start_times: vec![None; num_procs],
+            elapsed: vec![None; num_procs],
+            num_runs: vec![0; num_procs],
+            runs: (0 .. num_procs).map(|_| Vec::new()).collect(),
+            warmup_res: (0 .. num_procs).map(|_| None).collect(),
+            states, state_file, state_timer: None, state_changed: false,
+            ctl,
+        };
+
+        // Write the initial snapshot before starting anything, so that a bad
+        // state file path fails the run early.
+        if let Some(state_file) = &run.state_file {
+            state_file.write(&run.snapshot(false)).map_err(|err| Error::Setup(
+                format!("failed to write state file {:?}: {}",
+                        state_file.get_path(), err)))?;
+        }
+
+        run.start_ready();
+
+        // Clean up procs that might have completed already.
+        run.wait_any();
+        Ok(run)
+    }
+
+    /// Records an error for a proc.
+    fn error(&mut self, mut error: ProcError) {
+        let index = error.proc;
+        error.name = self.input.procs[index].name.clone();
+        self.errors[index].push(error);
+        self.states[index].num_errors += 1;
+        self.state_changed = true;
+        self.progress.emit(Event::Error(self.errors[index].last().unwrap()));
+    }
+
+    /// Stops selecting `fd`.
+    fn remove(&mut self, fd: fd_t) {
+        self.select.remove(fd);
+        self.sources.remove(&fd);
+    }
+
+    /// True if a proc hasn't been started, or is waiting to be retried.
+    fn is_pending(&self, index: usize) -> bool {
+        self.child_index[index].is_none() && self.results[index].is_none()
+    }
+
+    /// True if a proc may yet start, including to be retried or repeated.
+    fn may_start(&self, index: usize) -> bool {
+        if self.is_pending(index) {
+            return true;
+        }
+        if self.results[index].is_some() {
+            return false;
+        }
+        let spec = &self.input.procs[index];
+        spec.retry.as_ref()
+            .is_some_and(|r| self.attempts[index].len() + 1 < r.attempts)
+            || spec.repeat.as_ref()
+            .is_some_and(|r| self.num_runs[index] + 1 < r.warmup + r.count)
+    }
+
+    /// Checks whether a proc's dependencies are satisfied.  Returns None if
+    /// it must wait for them, or Some(Err(dep)) if dependency `dep` never
+    /// will be.
+    fn check_after(&self, index: usize) -> Option<std::result::Result<(), usize>> {
+        let mut satisfied = Some(Ok(()));
+        for &(dep, condition) in &self.after[index] {
+            let result = self.results[dep].as_ref();
+            let dep_satisfied = match condition {
+                spec::Condition::Started => match (self.child_index[dep], result) {
+                    (Some(_), _) => Some(true),
+                    (None, Some(_)) => Some(false),
+                    (None, None) => None,
+                },
+                spec::Condition::Completion => result.map(|_| true),
+                spec::Condition::Success => result.map(|r| {
+                    r.state == res::ProcState::Terminated && r.exit_code == Some(0)
+                }),
+            };
+            match dep_satisfied {
+                Some(false) => return Some(Err(dep)),
+                None => satisfied = None,
+                Some(true) => (),
+            }
+        }
+        satisfied
+    }
+
+    /// Describes why proc `index`'s dependency on `dep` can't be satisfied.
+    fn describe_unsatisfied(&self, index: usize, dep: usize) -> String {
+        let name = self.input.procs[dep].name.clone().unwrap_or_else(|| dep.to_string());
+        let condition = self.after[index].iter()
+            .find(|&&(d, _)| d == dep).map(|&(_, c)| c).unwrap();
+        match condition {
+            spec::Condition::Started => format!("dependency {} didn't start", name),
+            _ => format!("dependency {} didn't succeed", name),
+        }
+    }
+
+    /// True if starting another proc wouldn't exceed `max_parallel`.
+    fn can_start(&self) -> bool {
+        self.input.max_parallel.is_none_or(|max| self.procs.num_running() < max)
+    }
+
+    /// Starts pending procs whose dependencies are satisfied, as long as
+    /// `max_parallel` allows, highest priority first.  Skips those whose
+    /// dependencies never will be.
+    fn start_ready(&mut self) {
+        loop {
+            let mut changed = false;
+            let mut ready = Vec::new();
+            for index in 0 .. self.input.procs.len() {
+                if !self.is_pending(index) {
+                    continue;
+                }
+                if self.cancel.is_cancelled() {
+                    // Don't retry or repeat; the last attempt or run is the
+                    // result.
+                    if let Some(mut proc_res) = self.take_last_res(index) {
+                        self.retry_wait[index] = false;
+                        proc_res.cancelled = true;
+                        self.complete(index, proc_res);
+                        changed = true;
+                        continue;
+                    }
+                }
+                if self.retry_wait[index] {
+                    continue;
+                }
+                let satisfied = if self.cancel.is_cancelled() {
+                    Some(Err("run cancelled".to_string()))
+                } else {
+                    self.check_after(index)
+                        .map(|s| s.map_err(|dep| self.describe_unsatisfied(index, dep)))
+                };
+                match satisfied {
+                    Some(Ok(())) => ready.push(index),
+                    Some(Err(reason)) => {
+                        self.complete(index, res::ProcRes::skipped(&reason));
+                        changed = true;
+                    },
+                    None => (),
+                }
+            }
+
+            // The sort is stable, so procs of equal priority start in order.
+            ready.sort_by_key(|&i| std::cmp::Reverse(self.input.procs[i].priority));
+            for index in ready {
+                if !self.can_start() {
+                    break;
+                }
+                self.start_proc(index);
+                changed = true;
+            }
+            // Starting or skipping a proc may satisfy, or fail, others.
+            if !changed {
+                break;
+            }
+        }
+
+        if !(0 .. self.input.procs.len()).any(|i| self.may_start(i)) {
+            // Nothing more to start, so close the write end of the error pipe.
+            // We see EOF on the read end once all children exec or exit.
+            if let Some(err_write) = self.err_write.take() {
+                let _ = err_write.close();
+            }
+        }
+    }
+
+    /// Creates a proc's fds, and starts its child process.
+    fn start_proc(&mut self, index: usize) {
+        // Build the objects presenting each of the file descriptors.
+        let mut fds = Vec::new();
+        for (fd_str, fd_spec) in &self.input.procs[index].fds {
+            let fd_num = parse_fd(fd_str).unwrap();
+            match create_fd(fd_num, fd_spec) {
+                Ok(fd) => fds.push(fd),
+                Err(err) => {
+                    let msg = format!("failed to create fd {}", fd_str);
+                    self.error(match err {
+                        crate::err::Error::Io(err) => ProcError::from_io(
+                            index, Stage::FdSetup, Some(fd_num), &msg, &err),
+                        err => ProcError::new(
+                            index, Stage::FdSetup, Some(fd_num), None,
+                            &format!("{}: {}", msg, err)),
+                    });
+                    self.finish_not_started(index, res::ProcState::SetupFailed);
+                    return;
+                },
+            }
+        }
+
+        let plan = &mut self.plans[index];
+        plan.clear_fds();
+        for fd in &fds {
+            plan.add_fd(fd.get_fd(), fd.get_child_actions());
+        }
+        let pid = match spawn::spawn(
+                self.backend, plan, self.err_write.as_ref().unwrap()) {
+            Ok(pid) => pid,
+            Err(err) => {
+                self.error(ProcError::from_io(
+                    index, Stage::Exec, None, "failed to start proc", &err));
+                self.finish_not_started(index, res::ProcState::ExecFailed);
+                return;
+            },
+        };
+
+        self.start_times[index] = Some(Instant::now());
+
+        // If it has a pidfd, select it to find out when the proc terminates;
+        // otherwise, we rely on SIGCHLD.
+        let (child, pidfd) = self.procs.push(pid);
+        if let Some(pidfd) = pidfd {
+            match self.select.insert(pidfd, sel::Interest::READ) {
+                Ok(()) => { self.sources.insert(pidfd, Source::Pidfd); },
+                Err(_) => self.procs.close_pidfd(child),
+            }
+        }
+        self.child_index[index] = Some(child);
+        self.proc_index.push(index);
+        self.states[index].set_running(pid);
+        self.state_changed = true;
+        self.progress.emit(Event::Started { index, pid });
+
+        if let Some(timeout) = self.input.procs[index].timeout {
+            let timer = self.select.insert_timer(get_deadline(timeout));
+            self.timers.insert(timer, Timer::Timeout(index));
+            self.timeout_timers[index] = Some(timer);
+        }
+
+        // Finish setting up the file descriptors.
+        self.fds[index] = fds;
+        for j in 0 .. self.fds[index].len() {
+            let fd = &mut self.fds[index][j];
+            let f = fd.get_fd();
+            let error = if let Err(err) = fd.set_up_in_parent() {
+                Some(ProcError::from_io(
+                    index, Stage::FdSetup, Some(f),
+                    &format!("failed to set up fd {}", f), &err))
+            }
+            else if let Some(reader) = fd.as_reader() {
+                let read_fd = reader.get_fd();
+                match self.select.insert(read_fd, sel::Interest::READ) {
+                    Ok(()) => {
+                        self.sources.insert(read_fd, Source::Reader(index, j));
+                        self.num_readers[index] += 1;
+                        None
+                    },
+                    Err(err) => Some(ProcError::from_io(
+                        index, Stage::FdSetup, Some(f),
+                        &format!("failed to select fd {}", f), &err)),
+                }
+            }
+            else {
+                None
+            };
+            if let Some(error) = error {
+                self.error(error);
+            }
+        }
+
+        // If the child failed before exec'ing, it sent errors on the err pipe.
+        // Read them now, so that the pipe doesn't fill up if many procs fail
+        // while we're busy starting others.
+        self.read_err_pipe();
+    }
+
+    /// Reads any errors available on the err pipe.
+    fn read_err_pipe(&mut self) {
+        let fd = self.err_read.get_fd();
+        if !self.sources.contains_key(&fd) {
+            // Already at EOF.
+            return;
+        }
+        loop {
+            let eof = self.err_read.read();
+            let errors = self.err_read.take_errors();
+            let done = eof || errors.is_empty();
+            for error in errors {
+                // Errors on the err pipe are sent by child processes that
+                // failed before exec'ing.
+                self.failed.insert(error.proc, match error.stage {
+                    Stage::Exec => res::ProcState::ExecFailed,
+                    _ => res::ProcState::SetupFailed,
+                });
+                self.error(error);
+            }
+            if eof {
+                self.remove(fd);
+            }
+            if done {
+                break;
+            }
+        }
+    }
+
+    /// Handles termination of the child process at `child` in `procs`.
+    fn terminated(&mut self, child: usize) {
+        let proc = self.procs.get(child);
+        let (_, status, _) = proc.wait_info.unwrap();
+        let pid = proc.pid;
+        let index = self.proc_index[child];
+        if let Some(timer) = self.timeout_timers[index].take() {
+            self.select.remove_timer(timer);
+            self.timers.remove(&timer);
+        }
+        self.elapsed[index] = self.start_times[index].take()
+            .map(|t| t.elapsed().as_secs_f64());
+        self.states[index].set_exited(status);
+        self.state_changed = true;
+        self.progress.emit(Event::Terminated { index, pid, status });
+        // Another proc may start in its place.
+        self.start_ready();
+    }
+
+    /// Handles a timer that has expired.
+    fn handle_timer(&mut self, timer: sel::TimerId) {
+        match self.timers.remove(&timer) {
+            Some(Timer::Timeout(index)) => {
+                self.timeout_timers[index] = None;
+                if let Some(child) = self.child_index[index] {
+                    if self.procs.get(child).is_running() {
+                        self.timed_out[index] = true;
+                        // If it already terminated but hasn't been waited, fine.
+                        let _ = self.procs.send_signal(child, libc::SIGTERM);
+                        if let Some(kill_after) = self.input.procs[index].kill_after {
+                            let timer = self.select.insert_timer(get_deadline(kill_after));
+                            self.timers.insert(timer, Timer::Kill(index));
+                            self.timeout_timers[index] = Some(timer);
+                        }
+                    }
+                }
+            },
+            Some(Timer::Kill(index)) => {
+                self.timeout_timers[index] = None;
+                if let Some(child) = self.child_index[index] {
+                    if self.procs.get(child).is_running() {
+                        let _ = self.procs.send_signal(child, libc::SIGKILL);
+                    }
+                }
+            },
+            Some(Timer::Retry(index)) if self.retry_wait[index] => {
+                self.retry_wait[index] = false;
+                self.start_ready();
+            },
+            _ => (),
+        }
+    }
+
+    /// Waits procs without pidfds.
+    fn wait_any(&mut self) {
+        for child in self.procs.wait_any() {
+            self.terminated(child);
+        }
+    }
+
+    /// Handles an event on a selected fd.
+    fn handle(&mut self, fd: fd_t) {
+        match self.sources.get(&fd) {
+            Some(Source::ErrPipe) => self.read_err_pipe(),
+            Some(Source::Sigchld) if self.sigchld.take() =>
+                // Clean up any terminated procs without pidfds.
+                self.wait_any(),
+            Some(Source::Cancel) => {
+                if let Some(signum) = self.cancel.take() {
+                    for child in 0 .. self.procs.len() {
+                        // Procs that already terminated fail; ignore.
+                        let _ = self.procs.send_signal(child, signum);
+                    }
+                }
+                // Don't start any more procs.
+                self.start_ready();
+            },
+            Some(Source::Pidfd) => {
+                // A pidfd is readable, so its proc terminated.  Once it's
+                // waited, stop selecting the pidfd, and close it.
+                if let Some(child) = self.procs.wait_pidfd(fd) {
+                    self.remove(fd);
+                    self.procs.close_pidfd(child);
+                    self.terminated(child);
+                }
+            },
+            Some(Source::CtlListen) => {
+                for conn_fd in self.ctl.as_mut().unwrap().accept() {
+                    match self.select.insert(conn_fd, sel::Interest::READ) {
+                        Ok(()) => { self.sources.insert(conn_fd, Source::CtlConn); },
+                        Err(err) => {
+                            self.ctl.as_mut().unwrap().close(conn_fd);
+                            self.run_error(RunError::from_io(
+                                "failed to select control connection", &err));
+                        },
+                    }
+                }
+            },
+            Some(Source::CtlConn) => {
+                let (requests, closed) = self.ctl.as_mut().unwrap().read(fd);
+                for request in requests {
+                    let response = match request {
+                        Ok(request) => self.handle_request(request),
+                        Err(msg) => ctl::err_response(&format!("bad request: {}", msg)),
+                    };
+                    self.ctl.as_mut().unwrap().respond(fd, &response);
+                }
+                // Send what responses the client will take now, and the rest
+                // when it's writable.  Once it's closed its end, only finish
+                // sending.
+                let ctl = self.ctl.as_mut().unwrap();
+                let interest = match ctl.flush(fd) {
+                    Ok(pending) if pending || !closed
+                        => Some(sel::Interest { read: !closed, write: pending }),
+                    _ => None,
+                };
+                if let Some(interest) = interest {
+                    if self.select.insert(fd, interest).is_ok() {
+                        return;
+                    }
+                }
+                self.remove(fd);
+                self.ctl.as_mut().unwrap().close(fd);
+            },
+            Some(&Source::Reader(i, j))
+                if self.fds[i][j].as_reader().unwrap().read() => {
+                // At EOF.
+                self.remove(fd);
+                self.num_readers[i] -= 1;
+            },
+            _ => (),
+        }
+    }
+
+    /// Finds the index of a proc given by a control request.
+    fn find_proc(&self, proc: &ctl::ProcRef) -> Result<usize, String> {
+        match proc {
+            ctl::ProcRef::Index(index) if *index < self.input.procs.len() =>
+                Ok(*index),
+            ctl::ProcRef::Name(name) => self.input.find_proc(name)
+                .ok_or_else(|| format!("no proc named {}", name)),
+            ctl::ProcRef::Index(index) => Err(format!("no proc {}", index)),
+        }
+    }
+
+    /// Carries out a control request, and returns the response.
+    fn handle_request(&mut self, request: ctl::Request) -> serde_json::Value {
+        use serde_json::json;
+
+        let result = match request {
+            ctl::Request::List =>
+                Ok(json!({"state": self.snapshot(false)})),
+
+            ctl::Request::Output { proc, fd } => self.find_proc(&proc)
+                .and_then(|index| {
+                    let output = match &self.results[index] {
+                        // Finished, so the output is in the results.
+                        Some(result) => result.fds.get(&fd).map(|r| json!(r)),
+                        None => self.fds[index].iter()
+                            .find(|f| get_fd_name(f.get_fd()) == fd)
+                            .map(|f| f.get_captured()
+                                 .map_err(|err| format!("failed to read output: {}", err)))
+                            .transpose()?
+                            .flatten()
+                            .map(|r| json!(r)),
+                    };
+                    output
+                        .map(|output| json!({"output": output}))
+                        .ok_or_else(|| format!("fd {} is not captured", fd))
+                }),
+
+            ctl::Request::Signal { proc, signal } => self.find_proc(&proc)
+                .and_then(|index| {
+                    let signum = signal.to_signum()
+                        .ok_or_else(|| format!("bad signal: {:?}", signal))?;
+                    let child = self.child_index[index]
+                        .filter(|&c| self.procs.get(c).is_running())
+                        .ok_or_else(|| format!("proc {} is not running", index))?;
+                    self.procs.send_signal(child, signum)
+                        .map_err(|err| format!("failed to send signal: {}", err))?;
+                    Ok(json!({}))
+                }),
+
+            ctl::Request::Cancel { signal } => signal.to_signum()
+                .ok_or_else(|| format!("bad signal: {:?}", signal))
+                .map(|signum| {
+                    self.cancel.cancel_with_signal(signum);
+                    json!({})
+                }),
+        };
+        match result {
+            Ok(value) => ctl::ok_response(value),
+            Err(msg) => ctl::err_response(&msg),
+        }
+    }
+
+    /// True if a proc has terminated and we've read everything from its fds,
+    /// but it hasn't finished yet.
+    fn is_ready(&self, index: usize) -> bool {
+        match self.child_index[index] {
+            Some(child) =>
+                self.results[index].is_none()
+                && !self.procs.get(child).is_running()
+                && self.num_readers[index] == 0,
+            None => false,
+        }
+    }
+
+    /// Finishes a proc that is ready: cleans up its fds, and builds its result.
+    fn finish(&mut self, index: usize) {
+        let proc = self.procs.get(self.child_index[index].unwrap());
+        let (_, status, rusage) = proc.wait_info.unwrap();
+        let mut proc_res = res::ProcRes::new(proc.pid, status, rusage);
+
+        // Build fd res's into it.
+        let mut fds = std::mem::take(&mut self.fds[index]);
+        self.states[index].captured = get_captured_lens(&fds);
+        for fd in &mut fds {
+            let f = fd.get_fd();
+            match fd.clean_up_in_parent() {
+                Ok(Some(fd_result)) => {
+                    proc_res.fds.insert(get_fd_name(f), fd_result);
+                },
+                Ok(None) => {
+                },
+                Err(err) => {
+                    proc_res.fds.insert(get_fd_name(f), res::FdRes::Error {});
+                    self.error(ProcError::from_io(
+                        index, Stage::Cleanup, Some(f),
+                        &format!("failed to clean up fd {}", f), &err));
+                },
+            };
+        }
+
+        if let Some(state) = self.failed.remove(&index) {
+            proc_res.set_failed(state);
+        }
+        proc_res.timed_out = std::mem::take(&mut self.timed_out[index]);
+        proc_res.elapsed = self.elapsed[index].take();
+        if self.should_retry(index, &proc_res) {
+            self.retry(index, proc_res);
+            return;
+        }
+
+        proc_res.errors.append(&mut self.errors[index]);
+        proc_res.attempts = std::mem::take(&mut self.attempts[index]);
+        if self.should_repeat(index, &proc_res) {
+            self.repeat(index, proc_res);
+        } else {
+            self.complete(index, proc_res);
+        }
+    }
+
+    /// True if a proc's attempt failed in a way its retry policy covers, and
+    /// it has attempts left.
+    fn should_retry(&self, index: usize, proc_res: &res::ProcRes) -> bool {
+        let retry = match &self.input.procs[index].retry {
+            Some(retry) => retry,
+            None => return false,
+        };
+        if self.attempts[index].len() + 1 >= retry.attempts
+            || self.cancel.is_cancelled()
+            || proc_res.state != res::ProcState::Terminated {
+            return false;
+        }
+        if proc_res.timed_out {
+            retry.timeout
+        } else if let Some(exit_code) = proc_res.exit_code {
+            match &retry.exit_codes {
+                Some(exit_codes) => exit_codes.contains(&exit_code),
+                None => exit_code != 0,
+            }
+        } else if let Some(signum) = proc_res.signum {
+            retry.signals.iter().any(|s| s.to_signum() == Some(signum))
+        } else {
+            false
+        }
+    }
+
+    /// Records a failed attempt, and sets up the proc to start again, after
+    /// its retry delay.
+    fn retry(&mut self, index: usize, mut proc_res: res::ProcRes) {
+        proc_res.errors = std::mem::take(&mut self.errors[index]);
+        self.progress.emit(Event::Retry { index, result: &proc_res });
+        self.attempts[index].push(proc_res);
+        self.child_index[index] = None;
+        self.states[index].set_pending();
+        self.state_changed = true;
+
+        let retry = self.input.procs[index].retry.as_ref().unwrap();
+        let delay = retry.get_delay(self.attempts[index].len());
+        if delay > 0. {
+            let timer = self.select.insert_timer(get_deadline(delay));
+            self.timers.insert(timer, Timer::Retry(index));
+            self.retry_wait[index] = true;
+        }
+    }
+
+    /// True if a proc is repeated and has runs left, and its last run went.
+    fn should_repeat(&self, index: usize, proc_res: &res::ProcRes) -> bool {
+        match &self.input.procs[index].repeat {
+            Some(repeat) =>
+                self.num_runs[index] + 1 < repeat.warmup + repeat.count
+                && !self.cancel.is_cancelled()
+                && proc_res.state == res::ProcState::Terminated,
+            None => false,
+        }
+    }
+
+    /// Records a run, unless it's a warmup run, and sets up the proc to run
+    /// again.
+    fn repeat(&mut self, index: usize, proc_res: res::ProcRes) {
+        self.progress.emit(Event::Repeat { index, result: &proc_res });
+        self.num_runs[index] += 1;
+        if self.num_runs[index] > self.input.procs[index].repeat.as_ref().unwrap().warmup {
+            self.runs[index].push(proc_res);
+        } else {
+            self.warmup_res[index] = Some(proc_res);
+        }
+        self.child_index[index] = None;
+        self.states[index].set_pending();
+        self.state_changed = true;
+    }
+
+    /// Takes the result of a pending proc's last attempt or run, if it has
+    /// run already.
+    fn take_last_res(&mut self, index: usize) -> Option<res::ProcRes> {
+        if let Some(proc_res) = self.attempts[index].pop() {
+            return Some(proc_res);
+        }
+        if self.num_runs[index] == 0 {
+            return None;
+        }
+        // Count the last run as the current one again, as `complete()` expects.
+        self.num_runs[index] -= 1;
+        if self.num_runs[index] >= self.input.procs[index].repeat.as_ref().unwrap().warmup {
+            self.runs[index].pop()
+        } else {
+            self.warmup_res[index].take()
+        }
+    }
+
+    /// Finishes a proc that won't be started.
+    fn finish_not_started(&mut self, index: usize, state: res::ProcState) {
+        self.complete(index, res::ProcRes::not_started(state));
+    }
+
+    fn complete(&mut self, index: usize, mut proc_res: res::ProcRes) {
+        proc_res.errors.append(&mut self.errors[index]);
+        proc_res.attempts.append(&mut self.attempts[index]);
+        if let Some(repeat) = &self.input.procs[index].repeat {
+            let runs = std::mem::take(&mut self.runs[index]);
+            // The last run is a warmup run if the proc stopped early.
+            let last = Some(&proc_res).filter(|_| self.num_runs[index] >= repeat.warmup);
+            proc_res.stats = res::Stats::new(runs.iter().chain(last));
+            proc_res.runs = runs;
+        }
+        if let Some(expect) = &self.input.procs[index].expect {
+            proc_res.failures = expect::check(expect, &proc_res);
+            proc_res.passed = Some(proc_res.failures.is_empty());
+        }
+        self.states[index].set_finished(&proc_res);
+        self.state_changed = true;
+        self.progress.emit(Event::Finished { index, result: &proc_res });
+        self.results[index] = Some(proc_res);
+    }
+
+    /// Finishes procs that are ready.
+    fn finish_ready(&mut self) {
+        let ready = (0 .. self.input.procs.len())
+            .filter(|&i| self.is_ready(i))
+            .collect::<Vec<_>>();
+        if !ready.is_empty() {
+            // A child process writes any errors before it terminates, so
+            // they're in the err pipe by now.
+            self.read_err_pipe();
+            for index in ready {
+                self.finish(index);
+            }
+            // Finishing procs may satisfy others' dependencies, or retry.
+            self.start_ready();
+        }
+    }
+
+    /// Waits for the procs to run, until all have been started or skipped,
+    /// all have terminated, and we've read everything from their fds.
+    fn wait(&mut self) {
+        self.finish_ready();
+        // Keep going while procs run, or wait for a retry delay.
+        while self.sources.values().any(Source::is_reader)
+            || self.procs.any_running()
+            || self.retry_wait.contains(&true) {
+            let events = match self.select.select(None) {
+                Ok(events) => events,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted =>
+                    // select interrupted by some other signal.  Keep going.
+                    continue,
+                Err(err) => panic!("select failed: {}", err),
+            };
+            for event in events {
+                match event {
+                    sel::Event::Fd(fd, _) => self.handle(fd),
+                    sel::Event::Timer(id) if Some(id) == self.state_timer => {
+                        // Time for a periodic snapshot.
+                        self.state_timer = None;
+                        self.state_changed = true;
+                    },
+                    sel::Event::Timer(id) => self.handle_timer(id),
+                }
+            }
+            self.finish_ready();
+            if self.state_changed {
+                self.write_state(false);
+            }
+        }
+    }
+
+    /// Returns a snapshot of the state of all procs.
+    fn snapshot(&self, done: bool) -> state::State {
+        let procs = self.states.iter().zip(self.fds.iter())
+            .map(|(proc_state, fds)| {
+                let mut proc_state = proc_state.clone();
+                if proc_state.phase != state::Phase::Finished {
+                    proc_state.captured = get_captured_lens(fds);
+                }
+                proc_state
+            })
+            .collect();
+        state::State::new(procs, done)
+    }
+
+    /// Writes a snapshot to the state file, if any, and schedules the next.
+    fn write_state(&mut self, done: bool) {
+        self.state_changed = false;
+        if let Some(state_file) = &self.state_file {
+            // If this fails, the next snapshot will try again.
+            let error = state_file.write(&self.snapshot(done)).err().map(|err| {
+                let msg = format!("failed to write state file {:?}", state_file.get_path());
+                RunError::from_io(&msg, &err)
+            });
+            let interval = state_file.interval;
+            if let Some(error) = error {
+                self.run_error(error);
+            }
+            if let Some(timer) = self.state_timer.take() {
+                self.select.remove_timer(timer);
+            }
+            if !done {
+                self.state_timer = Some(
+                    self.select.insert_timer(Instant::now() + interval));
+            }
+        }
+    }
+
+    /// Records an error not attributable to a single proc, unless the same
+    /// error was already recorded.
+    fn run_error(&mut self, err: RunError) {
+        if !self.run_errors.contains(&err) {
+            self.run_errors.push(err);
+        }
+    }
+
+    fn into_res(mut self) -> res::Res {
+        self.write_state(true);
+        let _ = self.err_read.close();
+        let mut result = res::Res::new();
+        result.run_id = self.run_id;
+        result.procs = self.results.into_iter().map(Option::unwrap).collect();
+        result.errors = self.run_errors;
+        result
+    }
+}
+
+/// Returns the time `secs` from now.  Absurdly long times are capped at a
+/// century, rather than overflowing.
+fn get_deadline(secs: f64) -> Instant {
+    const MAX_SECS: f64 = 100. * 365. * 86400.;
+    Instant::now() + Duration::from_secs_f64(secs.min(MAX_SECS))
+}
+
+/// Returns the number of bytes captured so far for captured fds.
+fn get_captured_lens(fds: &[Box<dyn Fd>]) -> BTreeMap<String, u64> {
+    fds.iter()
+        .filter_map(|fd| fd.get_captured_len().map(|len| (get_fd_name(fd.get_fd()), len)))
+        .collect()
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn runner(json: &str) -> Runner {
+        Runner::new(serde_json::from_str(json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn progress() {
+        let mut runner = runner(r#"{"procs": [
+            {"argv": ["/bin/true"]},
+            {"argv": ["/nonexistent"]}
+        ]}"#);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_ref = events.clone();
+        runner.on_progress(move |event| {
+            events_ref.borrow_mut().push(match event {
+                Event::Started { index, .. } => format!("started {}", index),
+                Event::Terminated { index, .. } => format!("terminated {}", index),
+                Event::Error(err) => format!("error {}", err.proc),
+                Event::Retry { index, .. } => format!("retry {}", index),
+                Event::Repeat { index, .. } => format!("repeat {}", index),
+                Event::Finished { index, .. } => format!("finished {}", index),
+            });
+        });
+        let res = runner.run().unwrap();
+
+        let mut events = events.borrow().clone();
+        // Each proc finishes after its other events, with a complete result.
+        for index in 0 .. 2 {
+            let pos = |prefix: &str| {
+                let event = format!("{} {}", prefix, index);
+                events.iter().position(|e| *e == event).unwrap()
+            };
+            assert!(pos("terminated") < pos("finished"));
+        }
+        assert!(
+            events.iter().position(|e| e == "error 1")
+            < events.iter().position(|e| e == "finished 1"));
+        events.sort();
+        assert_eq!(events, vec![
+            "error 1", "finished 0", "finished 1", "started 0", "started 1",
+            "terminated 0", "terminated 1"]);
+        assert_eq!(res.procs[0].state, res::ProcState::Terminated);
+        assert_eq!(res.procs[1].state, res::ProcState::ExecFailed);
+        assert_eq!(res.procs[1].errors.len(), 1);
+    }
+
+    #[test]
+    fn many_exec_failures() {
+        // More failures, with long messages, than the err pipe holds.
+        let path = "/nonexistent".repeat(32);
+        let procs = vec![format!(r#"{{"argv": ["{}"]}}"#, path); 300].join(",");
+        let json = format!(r#"{{"procs": [{}]}}"#, procs);
+        for backend in &[spawn::Backend::Fork, spawn::Backend::default()] {
+            let mut runner = runner(&json);
+            runner.set_backend(*backend);
+            let res = runner.run().unwrap();
+            for proc in &res.procs {
+                assert_eq!(proc.state, res::ProcState::ExecFailed);
+                assert_eq!(proc.errors.len(), 1);
+                assert_eq!(proc.errors[0].errno, Some(libc::ENOENT));
+            }
+        }
+    }
+
+    #[test]
+    fn kill_after_timeout() {
+        // The proc ignores SIGTERM, so is killed after the grace period.
+        let runner = runner(r#"{"procs": [
+            {
+                "argv": ["/bin/sh", "-c", "trap '' TERM; exec /bin/sleep 10"],
+                "timeout": 0.1,
+                "kill_after": 0.1
+            }
+        ]}"#);
+        let start = Instant::now();
+        let res = runner.run().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(res.procs[0].timed_out);
+        assert_eq!(res.procs[0].signum, Some(libc::SIGKILL));
+    }
+
+    #[test]
+    fn skip_reasons() {
+        let res = runner(r#"{"procs": [
+            {"name": "build", "argv": ["/bin/false"]},
+            {"name": "test", "argv": ["/bin/true"], "after": ["build"]},
+            {"argv": ["/bin/true"], "after": [{"proc": "test", "condition": "started"}]}
+        ]}"#).run().unwrap();
+        assert_eq!(res.procs[0].skip_reason, None);
+        assert_eq!(
+            res.procs[1].skip_reason.as_deref(), Some("dependency build didn't succeed"));
+        assert_eq!(res.procs[2].skip_reason.as_deref(), Some("dependency test didn't start"));
+    }
+
+    #[test]
+    fn env_expanded_once() {
+        let res = runner(r#"{
+            "vars": {"literal": "$${X}"},
+            "procs": [{
+                "argv": ["/bin/sh", "-c", "echo \"$A $B\""],
+                "env": {"vars": {"X": "x", "A": "${literal}", "B": "$${X}"}},
+                "fds": [["stdout", {"capture": {"mode": "memory"}}]]
+            }]
+        }"#).run().unwrap();
+        assert_eq!(res.procs[0].get_text("stdout").unwrap(), "${X} ${X}\n");
+    }
+
+    #[test]
+    fn env_prepend_inherited() {
+        let home = std::env::var("HOME").unwrap();
+        let res = runner(r#"{"procs": [{
+            "argv": ["/bin/sh", "-c", "echo \"$PATH\""],
+            "env": {"prepend": {"PATH": ["${HOME}/bin"]}},
+            "fds": [["stdout", {"capture": {"mode": "memory"}}]]
+        }]}"#).run().unwrap();
+        let path = res.procs[0].get_text("stdout").unwrap();
+        assert!(path.starts_with(&format!("{}/bin:", home)), "{}", path);
+    }
+
+    #[test]
+    fn retry_delay() {
+        let start = Instant::now();
+        let res = runner(r#"{"procs": [
+            {"argv": ["/bin/sh", "-c", "exit 1"], "retry": {"attempts": 3, "delay": 0.1}}
+        ]}"#).run().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(res.procs[0].attempts.len(), 2);
+        assert_eq!(res.procs[0].exit_code, Some(1));
+    }
+
+    #[test]
+    fn state_file_error() {
+        // The proc removes the state file's directory, so later writes fail.
+        let dir = std::env::temp_dir().join(format!("ir-state-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let json = format!(
+            r#"{{"procs": [{{"argv": ["/bin/rm", "-r", "{}"]}}]}}"#, dir.display());
+        let mut runner = runner(&json);
+        runner.set_state_file(&dir.join("state.json"), Duration::from_secs(60));
+        let res = runner.run().unwrap();
+        assert_eq!(res.procs[0].exit_code, Some(0));
+        // Repeated failures are reported once.
+        assert_eq!(res.errors.len(), 1);
+        assert!(res.errors[0].message.starts_with("failed to write state file"));
+        assert_eq!(res.errors[0].errno_name, Some("ENOENT"));
+        assert!(res.has_errors());
+    }
+
+    #[test]
+    fn cancel_queued() {
+        // The first two procs run once, then are queued behind the
+        // higher-priority sleeps when the run is cancelled.
+        let runner = runner(r#"{"max_parallel": 3, "procs": [
+            {"argv": ["/bin/sh", "-c", "exit 3"], "retry": {"attempts": 3}},
+            {"argv": ["/bin/true"], "repeat": 3},
+            {"name": "sleep", "argv": ["/bin/sleep", "10"]},
+            {
+                "argv": ["/bin/sleep", "10"], "priority": 1,
+                "after": [{"proc": "sleep", "condition": "started"}]
+            },
+            {
+                "argv": ["/bin/sleep", "10"], "priority": 1,
+                "after": [{"proc": "sleep", "condition": "started"}]
+            }
+        ]}"#);
+        let cancel = runner.get_cancel();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            cancel.cancel();
+        });
+        let res = runner.run().unwrap();
+        thread.join().unwrap();
+
+        let retried = &res.procs[0];
+        assert_eq!(retried.state, res::ProcState::Terminated);
+        assert_eq!(retried.exit_code, Some(3));
+        assert!(retried.cancelled);
+        let repeated = &res.procs[1];
+        assert_eq!(repeated.state, res::ProcState::Terminated);
+        assert_eq!(repeated.exit_code, Some(0));
+        assert!(repeated.cancelled);
+        assert!(repeated.runs.is_empty());
+        assert_eq!(repeated.stats.as_ref().unwrap().count, 1);
+        for proc in &res.procs[2 ..] {
+            assert_eq!(proc.signum, Some(libc::SIGTERM));
+            assert!(!proc.cancelled);
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let runner = runner(r#"{"procs": [
+            {"name": "a", "argv": ["/bin/sleep", "10"]},
+            {"argv": ["/bin/sleep", "10"]},
+            {"argv": ["/bin/true"], "after": ["a"]}
+        ]}"#);
+        let cancel = runner.get_cancel();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            cancel.cancel();
+        });
+        let res = runner.run().unwrap();
+        thread.join().unwrap();
+
+        for proc in &res.procs[.. 2] {
+            assert_eq!(proc.signum, Some(libc::SIGTERM));
+        }
+        assert_eq!(res.procs[2].state, res::ProcState::Skipped);
+        assert_eq!(res.procs[2].skip_reason.as_deref(), Some("run cancelled"));
+    }
+}
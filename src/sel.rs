@@ -1,6 +1,8 @@
-use crate::sys::{FdSet, fd_t, select};
-use std::collections::{BTreeMap, HashSet};
+use crate::sys;
+use crate::sys::{FdSet, fd_t};
+use std::collections::{BTreeMap, BTreeSet};
 use std::io;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 //------------------------------------------------------------------------------
@@ -13,63 +15,462 @@ pub trait Read {
     fn read(&mut self) -> bool;
 }
 
-pub struct Select<'a> {
-    // We use a hash set rather than maintaining an FdSet directly because
+pub trait Write {
+    fn get_fd(&self) -> fd_t;
+
+    /// Writes to `fd`, when a write is ready.  Returns true if the fd is
+    /// complete and should no longer be selected.
+    fn write(&mut self) -> bool;
+}
+
+//------------------------------------------------------------------------------
+
+/// The kinds of readiness to wait for on an fd.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Interest {
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Interest {
+    pub const READ: Interest = Interest { read: true, write: false };
+    pub const WRITE: Interest = Interest { read: false, write: true };
+
+    fn union(self, other: Interest) -> Interest {
+        Interest { read: self.read || other.read, write: self.write || other.write }
+    }
+
+    fn is_empty(self) -> bool {
+        !self.read && !self.write
+    }
+}
+
+/// The readiness of an fd, as reported by the backend.
+///
+/// The `select` backend can't distinguish hangup from readability, and
+/// doesn't report errors; it only sets `readable` and `writable`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Ready {
+    pub readable: bool,
+    pub writable: bool,
+    pub hangup: bool,
+    pub error: bool,
+}
+
+pub type TimerId = u64;
+
+/// An event not handled by a registered reader or writer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// An fd inserted with `insert()` is ready.
+    Fd(fd_t, Ready),
+    /// A timer's deadline has passed.  The timer is removed.
+    Timer(TimerId),
+}
+
+//------------------------------------------------------------------------------
+// Backends
+//------------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// `select(2)`.  Portable, but limited to fds below `FD_SETSIZE`.
+    Select,
+    /// `epoll(7)`.  No limit on fd numbers; Linux only.
+    #[cfg(target_os = "linux")]
+    Epoll,
+}
+
+impl Default for Backend {
+    #[cfg(target_os = "linux")]
+    fn default() -> Self { Self::Epoll }
+
+    #[cfg(not(target_os = "linux"))]
+    fn default() -> Self { Self::Select }
+}
+
+/// Operations a backend provides.  `Select` tracks the interest for each fd,
+/// and tells the backend only about changes.
+trait Poll {
+    fn insert(&mut self, fd: fd_t, interest: Interest) -> io::Result<()>;
+    fn modify(&mut self, fd: fd_t, interest: Interest) -> io::Result<()>;
+    fn remove(&mut self, fd: fd_t) -> io::Result<()>;
+
+    /// Blocks until an fd is ready or `timeout` elapses.
+    fn poll(&mut self, timeout: Option<Duration>) -> io::Result<Vec<(fd_t, Ready)>>;
+}
+
+struct SelectPoll {
+    // We use a map rather than maintaining an FdSet directly because
     // different OSes have different semantics for how select() modifies its
     // fd_set and whether an fd_set can be copied.
-    read_fds: HashSet<fd_t>,
+    fds: BTreeMap<fd_t, Interest>,
+}
+
+impl Poll for SelectPoll {
+    fn insert(&mut self, fd: fd_t, interest: Interest) -> io::Result<()> {
+        if fd < 0 || fd as usize >= libc::FD_SETSIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fd {} exceeds FD_SETSIZE", fd)));
+        }
+        self.fds.insert(fd, interest);
+        Ok(())
+    }
+
+    fn modify(&mut self, fd: fd_t, interest: Interest) -> io::Result<()> {
+        self.fds.insert(fd, interest);
+        Ok(())
+    }
+
+    fn remove(&mut self, fd: fd_t) -> io::Result<()> {
+        self.fds.remove(&fd);
+        Ok(())
+    }
+
+    fn poll(&mut self, timeout: Option<Duration>) -> io::Result<Vec<(fd_t, Ready)>> {
+        let mut read_set = FdSet::from_fds(
+            self.fds.iter().filter(|(_, i)| i.read).map(|(fd, _)| *fd));
+        let mut write_set = FdSet::from_fds(
+            self.fds.iter().filter(|(_, i)| i.write).map(|(fd, _)| *fd));
+        let mut error_set = FdSet::new();
+        sys::select(
+            &mut read_set, &mut write_set, &mut error_set,
+            timeout.map(|t| t.as_secs_f64()))?;
+
+        Ok(self.fds.keys().filter_map(|&fd| {
+            let ready = Ready {
+                readable: read_set.is_set(fd),
+                writable: write_set.is_set(fd),
+                ..Default::default()
+            };
+            if ready.readable || ready.writable { Some((fd, ready)) } else { None }
+        }).collect())
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct EpollPoll {
+    epfd: fd_t,
+    events: Vec<libc::epoll_event>,
+}
+
+#[cfg(target_os = "linux")]
+impl EpollPoll {
+    /// Max number of events to retrieve in a single wait.
+    const MAX_EVENTS: usize = 256;
+
+    fn new() -> io::Result<Self> {
+        let epfd = sys::epoll_create()?;
+        let events = vec![libc::epoll_event { events: 0, u64: 0 }; Self::MAX_EVENTS];
+        Ok(Self { epfd, events })
+    }
+
+    fn get_events(interest: Interest) -> u32 {
+        let mut events = libc::EPOLLRDHUP;
+        if interest.read {
+            events |= libc::EPOLLIN;
+        }
+        if interest.write {
+            events |= libc::EPOLLOUT;
+        }
+        events as u32
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for EpollPoll {
+    fn drop(&mut self) {
+        let _ = sys::close(self.epfd);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Poll for EpollPoll {
+    fn insert(&mut self, fd: fd_t, interest: Interest) -> io::Result<()> {
+        sys::epoll_ctl(
+            self.epfd, libc::EPOLL_CTL_ADD, fd, Self::get_events(interest))
+    }
+
+    fn modify(&mut self, fd: fd_t, interest: Interest) -> io::Result<()> {
+        sys::epoll_ctl(
+            self.epfd, libc::EPOLL_CTL_MOD, fd, Self::get_events(interest))
+    }
+
+    fn remove(&mut self, fd: fd_t) -> io::Result<()> {
+        sys::epoll_ctl(self.epfd, libc::EPOLL_CTL_DEL, fd, 0)
+    }
+
+    fn poll(&mut self, timeout: Option<Duration>) -> io::Result<Vec<(fd_t, Ready)>> {
+        // Round up to whole ms, so we don't wake up before a deadline.
+        let timeout = match timeout {
+            Some(t) => std::cmp::min(
+                t.as_micros().div_ceil(1000), libc::c_int::MAX as u128
+            ) as libc::c_int,
+            None => -1,
+        };
+        let n = sys::epoll_wait(self.epfd, &mut self.events, timeout)?;
+
+        Ok(self.events[.. n].iter().map(|event| {
+            // Copy out of the (possibly packed) struct before using fields.
+            let (flags, fd) = (event.events as libc::c_int, event.u64 as fd_t);
+            (fd, Ready {
+                readable: flags & libc::EPOLLIN != 0,
+                writable: flags & libc::EPOLLOUT != 0,
+                hangup: flags & (libc::EPOLLHUP | libc::EPOLLRDHUP) != 0,
+                error: flags & libc::EPOLLERR != 0,
+            })
+        }).collect())
+    }
+}
+
+//------------------------------------------------------------------------------
+
+pub struct Select<'a> {
+    poll: Box<dyn Poll>,
+
+    /// Interest currently registered with the backend, for each fd.
+    registered: BTreeMap<fd_t, Interest>,
 
     readers: BTreeMap<fd_t, &'a mut dyn Read>,
+    writers: BTreeMap<fd_t, &'a mut dyn Write>,
+    /// Fds whose events are returned to the caller.
+    others: BTreeMap<fd_t, Interest>,
+
+    timers: BTreeSet<(Instant, TimerId)>,
+    next_timer_id: TimerId,
 }
 
-/// FIXME: Write, error not implememented.
 impl<'a> Select<'a> {
+    /// Creates a selector with the default backend for this OS.  Falls back to
+    /// `select` if the default backend can't be set up.
     pub fn new() -> Self {
-        Select {
+        Self::with_backend(Backend::default()).unwrap_or_else(|_| {
+            Self::with_backend(Backend::Select).unwrap()
+        })
+    }
+
+    pub fn with_backend(backend: Backend) -> io::Result<Self> {
+        let poll: Box<dyn Poll> = match backend {
+            Backend::Select => Box::new(SelectPoll { fds: BTreeMap::new() }),
+            #[cfg(target_os = "linux")]
+            Backend::Epoll => Box::new(EpollPoll::new()?),
+        };
+        Ok(Select {
+            poll,
+            registered: BTreeMap::new(),
             readers: BTreeMap::new(),
-            read_fds: HashSet::new(),
-        }
+            writers: BTreeMap::new(),
+            others: BTreeMap::new(),
+            timers: BTreeSet::new(),
+            next_timer_id: 0,
+        })
     }
 
+    /// True if any readers or writers remain.
     pub fn any(&self) -> bool {
-        ! self.read_fds.is_empty()
+        ! (self.readers.is_empty() && self.writers.is_empty())
+    }
+
+    /// Brings the backend's interest in `fd` in line with what's registered.
+    fn update(&mut self, fd: fd_t) -> io::Result<()> {
+        let mut interest = self.others.get(&fd).copied().unwrap_or_default();
+        if self.readers.contains_key(&fd) {
+            interest = interest.union(Interest::READ);
+        }
+        if self.writers.contains_key(&fd) {
+            interest = interest.union(Interest::WRITE);
+        }
+
+        match self.registered.get(&fd).copied() {
+            None if interest.is_empty() => Ok(()),
+            None => {
+                self.poll.insert(fd, interest)?;
+                self.registered.insert(fd, interest);
+                Ok(())
+            },
+            Some(_) if interest.is_empty() => {
+                self.registered.remove(&fd);
+                self.poll.remove(fd)
+            },
+            Some(old) if old == interest => Ok(()),
+            Some(_) => {
+                self.registered.insert(fd, interest);
+                self.poll.modify(fd, interest)
+            },
+        }
     }
 
-    pub fn insert_reader(&mut self, read: &'a mut dyn Read) {
+    pub fn insert_reader(&mut self, read: &'a mut dyn Read) -> io::Result<()> {
         let fd = read.get_fd();
-        self.read_fds.insert(fd);
         self.readers.insert(fd, read);
+        self.update(fd).inspect_err(|_| {
+            self.readers.remove(&fd);
+        })
     }
 
     pub fn remove_reader(&mut self, fd: fd_t) -> &'a mut dyn Read {
-        self.read_fds.remove(&fd);
-        self.readers.remove(&fd).unwrap()
+        let read = self.readers.remove(&fd).unwrap();
+        // Removing an fd the backend knows about can't reasonably fail.
+        self.update(fd).unwrap();
+        read
     }
 
-    /// Blocks until a file descriptor is ready, and processes any ready file
-    /// descriptors.
-    pub fn select(&mut self, timeout: Option<f64>) -> io::Result<()> {
-        let mut read_set  = FdSet::from_fds(self.read_fds.iter().copied());
-        let mut write_set = FdSet::new();
-        let mut error_set = FdSet::new();
-        select(&mut read_set, &mut write_set, &mut error_set, timeout)?;
-
-        // Process read-ready fds.  Collect those that are done, collect them to
-        // avoid inalidating the iter, then remove them.
-        self.readers.iter_mut().filter_map(
-            |(fd, reader)| {
-                if read_set.is_set(*fd) && reader.read() {
-                    Some(*fd)
+    pub fn insert_writer(&mut self, write: &'a mut dyn Write) -> io::Result<()> {
+        let fd = write.get_fd();
+        self.writers.insert(fd, write);
+        self.update(fd).inspect_err(|_| {
+            self.writers.remove(&fd);
+        })
+    }
+
+    pub fn remove_writer(&mut self, fd: fd_t) -> &'a mut dyn Write {
+        let write = self.writers.remove(&fd).unwrap();
+        self.update(fd).unwrap();
+        write
+    }
+
+    /// Inserts an fd with no handler.  Its readiness is returned as an event
+    /// from `select()`.
+    pub fn insert(&mut self, fd: fd_t, interest: Interest) -> io::Result<()> {
+        let old = self.others.insert(fd, interest);
+        self.update(fd).inspect_err(|_| {
+            match old {
+                Some(old) => self.others.insert(fd, old),
+                None => self.others.remove(&fd),
+            };
+        })
+    }
+
+    pub fn remove(&mut self, fd: fd_t) {
+        if self.others.remove(&fd).is_some() {
+            self.update(fd).unwrap();
+        }
+    }
+
+    /// Adds a timer, which expires at `deadline`.
+    pub fn insert_timer(&mut self, deadline: Instant) -> TimerId {
+        let id = self.next_timer_id;
+        self.next_timer_id += 1;
+        self.timers.insert((deadline, id));
+        id
+    }
+
+    /// Removes a timer that hasn't yet expired.  Returns true if it was found.
+    pub fn remove_timer(&mut self, id: TimerId) -> bool {
+        match self.timers.iter().find(|(_, i)| *i == id).copied() {
+            Some(timer) => self.timers.remove(&timer),
+            None => false,
+        }
+    }
+
+    /// Blocks until a file descriptor is ready, a timer expires, or `timeout`
+    /// elapses.  Processes any ready readers and writers, and returns other
+    /// events.
+    pub fn select(&mut self, timeout: Option<f64>) -> io::Result<Vec<Event>> {
+        // Don't block past the next timer deadline.
+        let now = Instant::now();
+        let timeout = timeout.map(Duration::from_secs_f64);
+        let timeout = match self.timers.iter().next() {
+            Some((deadline, _)) => {
+                let remaining = deadline.saturating_duration_since(now);
+                Some(timeout.map_or(remaining, |t| std::cmp::min(t, remaining)))
+            },
+            None => timeout,
+        };
+
+        let ready = self.poll.poll(timeout)?;
+
+        let mut events = Vec::new();
+        for (fd, ready) in ready {
+            let closed = ready.hangup || ready.error;
+            // Process ready fds.  Remove those that are done.
+            if ready.readable || closed {
+                if let Some(reader) = self.readers.get_mut(&fd) {
+                    if reader.read() {
+                        self.remove_reader(fd);
+                    }
                 }
-                else {
-                    None
+            }
+            if ready.writable || closed {
+                if let Some(writer) = self.writers.get_mut(&fd) {
+                    if writer.write() {
+                        self.remove_writer(fd);
+                    }
                 }
             }
-        ).collect::<Vec<_>>().into_iter().for_each(
-            |fd| { self.remove_reader(fd); }
-        );
+            if self.others.contains_key(&fd) {
+                events.push(Event::Fd(fd, ready));
+            }
+        }
+
+        // Collect expired timers.
+        let now = Instant::now();
+        while let Some(&(deadline, id)) = self.timers.iter().next() {
+            if deadline > now {
+                break;
+            }
+            self.timers.remove(&(deadline, id));
+            events.push(Event::Timer(id));
+        }
 
-        Ok(())
+        Ok(events)
     }
 }
 
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends() -> Vec<Backend> {
+        vec![
+            Backend::Select,
+            #[cfg(target_os = "linux")]
+            Backend::Epoll,
+        ]
+    }
+
+    #[test]
+    fn pipe_events() {
+        for backend in backends() {
+            let (read_fd, write_fd) = sys::pipe().unwrap();
+            let mut select = Select::with_backend(backend).unwrap();
+            select.insert(read_fd, Interest::READ).unwrap();
+
+            // Nothing to read yet.
+            assert_eq!(select.select(Some(0.)).unwrap(), vec![]);
+
+            sys::write(write_fd, b"x").unwrap();
+            match select.select(Some(1.)).unwrap()[..] {
+                [Event::Fd(fd, ready)] => {
+                    assert_eq!(fd, read_fd);
+                    assert!(ready.readable);
+                },
+                ref events => panic!("unexpected events: {:?}", events),
+            };
+
+            select.remove(read_fd);
+            sys::close(read_fd).unwrap();
+            sys::close(write_fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn timers() {
+        for backend in backends() {
+            let mut select = Select::with_backend(backend).unwrap();
+            let now = Instant::now();
+            let t0 = select.insert_timer(now + Duration::from_millis(20));
+            let t1 = select.insert_timer(now + Duration::from_millis(10));
+            let t2 = select.insert_timer(now + Duration::from_millis(30));
+            assert!(select.remove_timer(t2));
+
+            assert_eq!(select.select(None).unwrap(), vec![Event::Timer(t1)]);
+            assert_eq!(select.select(None).unwrap(), vec![Event::Timer(t0)]);
+            assert!(Instant::now() >= now + Duration::from_millis(20));
+            assert!(! select.remove_timer(t0));
+        }
+    }
+}
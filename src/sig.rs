@@ -1,5 +1,7 @@
 extern crate libc;
 
+use crate::sys;
+use crate::sys::fd_t;
 use libc::{c_int, sigset_t};
 use std::io;
 
@@ -115,51 +117,233 @@ pub fn sigaction(signum: c_int, sigaction: Option<Sigaction>) -> io::Result<Siga
     }
 }
 
+
+/// Changes the signal mask of the calling thread, as `pthread_sigmask()`, and
+/// returns the previous mask.
+pub fn sigprocmask(how: c_int, mask: &sigset_t) -> io::Result<sigset_t> {
+    let mut old = empty_sigset();
+    match unsafe { libc::pthread_sigmask(how, mask, &mut old) } {
+        0 => Ok(old),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+/// One more than the highest signal number.
+#[cfg(target_os = "linux")]
+const NSIG: c_int = 65;
+#[cfg(not(target_os = "linux"))]
+const NSIG: c_int = 32;
+
+/// Resets each signal that has a handler to its default action, as exec does.
+/// Makes only raw syscalls, so this is safe after fork or vfork.
+pub fn reset_handlers() {
+    let default = empty_sigaction();
+    for signum in 1 .. NSIG {
+        let mut old = empty_sigaction();
+        unsafe {
+            if libc::sigaction(signum, std::ptr::null(), &mut old) == 0
+                && old.sa_sigaction != libc::SIG_DFL
+                && old.sa_sigaction != libc::SIG_IGN {
+                libc::sigaction(signum, &default, std::ptr::null_mut());
+            }
+        }
+    }
+}
+
+/// Parses a signal number, or name with or without "SIG", like "TERM" or
+/// "SIGTERM".
+pub fn parse_signum(signal: &str) -> Option<c_int> {
+    if let Ok(signum) = signal.parse::<c_int>() {
+        return Some(signum);
+    }
+    let name = signal.strip_prefix("SIG").unwrap_or(signal);
+    Some(match name {
+        "ABRT" => libc::SIGABRT,
+        "ALRM" => libc::SIGALRM,
+        "CHLD" => libc::SIGCHLD,
+        "CONT" => libc::SIGCONT,
+        "HUP"  => libc::SIGHUP,
+        "INT"  => libc::SIGINT,
+        "KILL" => libc::SIGKILL,
+        "PIPE" => libc::SIGPIPE,
+        "QUIT" => libc::SIGQUIT,
+        "STOP" => libc::SIGSTOP,
+        "TERM" => libc::SIGTERM,
+        "TSTP" => libc::SIGTSTP,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        _ => return None,
+    })
+}
+
+/// Returns a signal set containing only `signum`.
+pub fn sigset_of(signum: c_int) -> sigset_t {
+    let mut set = empty_sigset();
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, signum);
+    }
+    set
+}
+
 //------------------------------------------------------------------------------
 
-// FIXME: NSIG is not reliably available in libc.  I hope this is enough.
-const NSIG: usize = 256;
+/// Delivers a signal as a readable fd, so that it can be selected along with
+/// other fds.
+///
+/// On Linux, blocks the signal and receives it from a signalfd.  Elsewhere,
+/// installs a handler that writes to a self-pipe.  Either way, the fd stays
+/// readable from the time the signal arrives until `take()`, so a signal that
+/// arrives just before a select is not missed.
+///
+/// On Linux, only the calling thread's signal mask is changed.  Other threads
+/// must also block the signal, or it may be delivered to them instead.
+pub struct SignalFd {
+    signum: c_int,
+
+    /// Readable when the signal has been received.
+    fd: fd_t,
+
+    /// The signal mask before we blocked the signal.
+    #[cfg(target_os = "linux")]
+    old_mask: sigset_t,
+
+    /// Write end of the self-pipe.
+    #[cfg(not(target_os = "linux"))]
+    write_fd: fd_t,
+
+    /// The signal action before we installed our handler.
+    #[cfg(not(target_os = "linux"))]
+    old_action: Option<Sigaction>,
+}
+
+#[cfg(target_os = "linux")]
+impl SignalFd {
+    pub fn new(signum: c_int) -> io::Result<Self> {
+        let mask = sigset_of(signum);
+        let old_mask = sigprocmask(libc::SIG_BLOCK, &mask)?;
+        let fd = unsafe {
+            libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)
+        };
+        if fd == -1 {
+            let err = io::Error::last_os_error();
+            let _ = sigprocmask(libc::SIG_SETMASK, &old_mask);
+            return Err(err);
+        }
+        Ok(Self { signum, fd, old_mask })
+    }
+
+    /// Consumes all pending signals.  Returns true if any were received.
+    pub fn take(&mut self) -> bool {
+        const SIZE: usize = std::mem::size_of::<libc::signalfd_siginfo>();
+        let mut buf = [0u8; SIZE];
+        let mut received = false;
+        // The fd is nonblocking, so read until there's nothing left.
+        while let Ok(SIZE) = sys::read(self.fd, &mut buf) {
+            received = true;
+        }
+        received
+    }
 
-pub struct SignalFlag {
-    signum: usize,
+    /// Returns the signal mask a child process should have before exec.  The
+    /// signal mask is inherited across exec, so otherwise the child would run
+    /// with the signal blocked.
+    pub fn get_child_mask(&self) -> Option<sigset_t> {
+        Some(self.old_mask)
+    }
 }
 
-static mut SIGNAL_FLAGS: [bool; NSIG] = [false; NSIG];
+#[cfg(target_os = "linux")]
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        let _ = sys::close(self.fd);
+        // Unblock the signal, unless it was blocked before we started.
+        if unsafe { libc::sigismember(&self.old_mask, self.signum) } == 0 {
+            let _ = sigprocmask(libc::SIG_UNBLOCK, &sigset_of(self.signum));
+        }
+    }
+}
 
-/// Hacky unsafe boolean flag for a signal.  Installs a signal handler that sets
-/// the flag when the signal is received.
-impl SignalFlag {
-    pub fn new(signum: c_int) -> Self {
+// FIXME: NSIG is not reliably available in libc.  I hope this is enough.
+#[cfg(not(target_os = "linux"))]
+const NSIG: usize = 256;
+
+/// Write ends of self-pipes, indexed by signum, or -1.
+#[cfg(not(target_os = "linux"))]
+static SIGNAL_PIPES: [std::sync::atomic::AtomicI32; NSIG] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NO_PIPE: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+    [NO_PIPE; NSIG]
+};
+
+#[cfg(not(target_os = "linux"))]
+impl SignalFd {
+    pub fn new(signum: c_int) -> io::Result<Self> {
         assert!(signum > 0);
         assert!(signum < NSIG as c_int);
-        
+
         extern "system" fn handler(signum: c_int) {
-            // Accessing a static global is in general not threadsafe, but this
-            // signal handler will only ever be called on the main thread.
-            unsafe { SIGNAL_FLAGS[signum as usize] = true; }
+            // Only async-signal-safe operations here: an atomic load and a
+            // write.  If the pipe is full, a wakeup is already pending.
+            let fd = SIGNAL_PIPES[signum as usize].load(
+                std::sync::atomic::Ordering::SeqCst);
+            if fd >= 0 {
+                let _ = sys::write(fd, &[signum as u8]);
+            }
         }
 
-        // Set up the handler.
+        let (fd, write_fd) = sys::pipe()?;
+        for f in &[fd, write_fd] {
+            sys::set_nonblocking(*f)?;
+            sys::set_cloexec(*f)?;
+        }
+        let old = SIGNAL_PIPES[signum as usize].swap(
+            write_fd, std::sync::atomic::Ordering::SeqCst);
+        assert!(old == -1, "signal {} already has a SignalFd", signum);
+
         // FIXME: Check that we're not colliding with an existing handler.
-        sigaction(signum, Some(Sigaction {
+        let old_action = sigaction(signum, Some(Sigaction {
             disposition: Sigdisposition::Handler(handler),
             mask: empty_sigset(),
             flags: libc::SA_NOCLDSTOP,
-        })).unwrap_or_else(|err| {
-            eprintln!("sigaction failed: {}", err);
-            std::process::exit(exitcode::OSERR);
-        });
+        }))?;
 
-        Self { signum: signum as usize }
+        Ok(Self { signum, fd, write_fd, old_action: Some(old_action) })
     }
 
-    /// Retrieves the flag value, and clears it.
-    pub fn get(&self) -> bool {
-        unsafe {
-            let val = SIGNAL_FLAGS[self.signum];
-            SIGNAL_FLAGS[self.signum] = false;
-            val
+    /// Consumes all pending signals.  Returns true if any were received.
+    pub fn take(&mut self) -> bool {
+        let mut buf = [0u8; 64];
+        let mut received = false;
+        while let Ok(n) = sys::read(self.fd, &mut buf) {
+            if n == 0 {
+                break;
+            }
+            received = true;
         }
+        received
+    }
+
+    /// Handlers are reset to default on exec, and the signal isn't blocked, so
+    /// a child process can inherit our signal mask.
+    pub fn get_child_mask(&self) -> Option<sigset_t> {
+        None
     }
 }
 
+#[cfg(not(target_os = "linux"))]
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        let _ = sigaction(self.signum, self.old_action.take());
+        SIGNAL_PIPES[self.signum as usize].store(
+            -1, std::sync::atomic::Ordering::SeqCst);
+        let _ = sys::close(self.fd);
+        let _ = sys::close(self.write_fd);
+    }
+}
+
+impl SignalFd {
+    pub fn get_fd(&self) -> fd_t {
+        self.fd
+    }
+}
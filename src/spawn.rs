@@ -0,0 +1,359 @@
+//! Starting procs.
+//!
+//! Everything a proc needs (its executable, argv, envp, and fd actions) is
+//! prepared in the parent as a `Plan`.  The child then makes only raw
+//! syscalls: no allocation, no locks, no formatting, and no panics.  Errors
+//! are sent to the parent as preformatted messages with errno codes.
+//!
+//! This makes the child safe even if the parent is multithreaded, and also
+//! makes it safe to start the child with `vfork`-style `clone()`, which shares
+//! our memory and so avoids copying our page tables, however large our capture
+//! buffers.
+
+use crate::environ::Env;
+use crate::err::Stage;
+use crate::err_pipe::ErrPipeWrite;
+use crate::sig;
+use crate::sys;
+use crate::sys::fd_t;
+use libc::{c_int, pid_t, sigset_t};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+//------------------------------------------------------------------------------
+
+/// An operation on fds to perform in the child, before exec.
+pub enum Action {
+    /// Closes an fd.
+    Close(fd_t),
+
+    /// Opens a file, moves it to `fd`.
+    Open { fd: fd_t, path: CString, oflags: c_int, mode: c_int },
+
+    /// Duplicates `from` to `fd`.
+    Dup2 { from: fd_t, fd: fd_t },
+}
+
+/// Returns the errno of the last failed syscall.
+fn get_errno() -> c_int {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+/// Checks the return value of a raw syscall; on failure, returns errno.
+fn check(ret: c_int) -> Result<c_int, c_int> {
+    match ret {
+        -1 => Err(get_errno()),
+        ret => Ok(ret),
+    }
+}
+
+impl Action {
+    /// Performs the action.  On failure, returns errno.
+    ///
+    /// Calls libc directly, rather than the `sys` wrappers, which may panic.
+    fn run(&self) -> Result<(), c_int> {
+        unsafe {
+            match self {
+                Action::Close(fd) => {
+                    check(libc::close(*fd))?;
+                },
+                Action::Open { fd, path, oflags, mode } => {
+                    let file_fd = check(libc::open(path.as_ptr(), *oflags, *mode))?;
+                    check(libc::dup2(file_fd, *fd))?;
+                    check(libc::close(file_fd))?;
+                },
+                Action::Dup2 { from, fd } if from == fd => {
+                    // dup2() does nothing, so the fd remains close-on-exec,
+                    // if it was.
+                    let flags = check(libc::fcntl(*fd, libc::F_GETFD))?;
+                    check(libc::fcntl(*fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC))?;
+                },
+                Action::Dup2 { from, fd } => {
+                    check(libc::dup2(*from, *fd))?;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Actions to set up a single fd in the child.
+struct FdPlan {
+    fd: fd_t,
+    actions: Vec<Action>,
+    /// Error message to send if the actions fail.
+    err_msg: Vec<u8>,
+}
+
+/// Everything needed to start a proc, prepared before forking.
+pub struct Plan {
+    /// Index of the proc, for reporting errors.
+    index: usize,
+    exe: CString,
+    argv: sys::CStringVec,
+    envp: sys::CStringVec,
+    fds: Vec<FdPlan>,
+
+    /// Working directory to change to, after setting up fds.
+    cwd: Option<CString>,
+    /// Error message to send if changing the working directory fails.
+    cwd_err_msg: Vec<u8>,
+
+    /// Error message to send if exec fails.
+    exec_err_msg: Vec<u8>,
+
+    /// Signal mask for the proc.  If None, the proc inherits ours.
+    pub sigmask: Option<sigset_t>,
+}
+
+impl Plan {
+    pub fn new(index: usize, argv: &[String], env: &Env) -> io::Result<Self> {
+        let exe = match argv.first() {
+            Some(exe) => exe,
+            None => return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "empty argv")),
+        };
+        Ok(Self {
+            index,
+            exe: CString::new(exe.as_str())?,
+            argv: sys::CStringVec::new(argv.iter().cloned())?,
+            envp: sys::CStringVec::new(
+                env.iter().map(|(n, v)| format!("{}={}", n, v)))?,
+            fds: Vec::new(),
+            cwd: None,
+            cwd_err_msg: Vec::new(),
+            exec_err_msg: format!("exec: {}", exe).into_bytes(),
+            sigmask: None,
+        })
+    }
+
+    /// Removes all fds added with `add_fd()`, to reuse the plan with others.
+    pub fn clear_fds(&mut self) {
+        self.fds.clear();
+    }
+
+    /// Adds actions to set up `fd`.  Actions run in the order added.
+    pub fn add_fd(&mut self, fd: fd_t, actions: Vec<Action>) {
+        let err_msg = format!("failed to set up fd {}", fd).into_bytes();
+        self.fds.push(FdPlan { fd, actions, err_msg });
+    }
+
+    /// Sets the working directory.  The proc changes to it after setting up
+    /// fds, so relative paths for fds are relative to our working directory,
+    /// but a relative executable path is relative to the new one.
+    pub fn set_cwd(&mut self, cwd: &Path) -> io::Result<()> {
+        self.cwd = Some(CString::new(cwd.as_os_str().as_bytes())?);
+        self.cwd_err_msg = format!("chdir: {}", cwd.display()).into_bytes();
+        Ok(())
+    }
+
+    /// Carries out the plan in the child process: resets signal handlers,
+    /// sets the signal mask, sets up fds, and execs.  Errors are sent to
+    /// `err`.  Never returns.
+    ///
+    /// Makes only raw syscalls, so this is safe after fork or vfork.
+    fn exec_in_child(&self, err: &ErrPipeWrite, sigmask: &sigset_t) -> ! {
+        // Our handlers mustn't run in the child, which after vfork shares our
+        // memory, once its mask allows signals.  Exec would reset them anyway.
+        sig::reset_handlers();
+        unsafe {
+            libc::pthread_sigmask(libc::SIG_SETMASK, sigmask, std::ptr::null_mut());
+        }
+
+        let mut ok = true;
+        for fd in &self.fds {
+            if let Err(errno) = fd.actions.iter().try_for_each(Action::run) {
+                err.send(self.index, Stage::FdSetup, Some(fd.fd), errno, &fd.err_msg);
+                ok = false;
+            }
+        }
+
+        if let (true, Some(cwd)) = (ok, &self.cwd) {
+            if unsafe { libc::chdir(cwd.as_ptr()) } == -1 {
+                err.send(self.index, Stage::Cwd, None, get_errno(), &self.cwd_err_msg);
+                ok = false;
+            }
+        }
+
+        if ok {
+            // Only returns if exec failed.
+            let exec_err = sys::execve(&self.exe, &self.argv, &self.envp);
+            err.send(
+                self.index, Stage::Exec, None, exec_err.raw_os_error().unwrap_or(0),
+                &self.exec_err_msg);
+        }
+        unsafe { libc::_exit(exitcode::OSERR) }
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// How to start the child process.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// Ordinary fork, which copies the parent's address space.
+    Fork,
+
+    /// `clone(CLONE_VM | CLONE_VFORK)`: the child borrows the parent's memory,
+    /// and the parent is suspended until the child execs or exits.
+    #[cfg(target_os = "linux")]
+    Vfork,
+}
+
+impl Default for Backend {
+    #[cfg(target_os = "linux")]
+    fn default() -> Self { Backend::Vfork }
+
+    #[cfg(not(target_os = "linux"))]
+    fn default() -> Self { Backend::Fork }
+}
+
+/// Starts a proc according to `plan`.  Returns its pid.  Errors in the child
+/// are sent to `err`.
+pub fn spawn(backend: Backend, plan: &Plan, err: &ErrPipeWrite) -> io::Result<pid_t> {
+    match backend {
+        Backend::Fork => fork(plan, err),
+        #[cfg(target_os = "linux")]
+        Backend::Vfork => vfork(plan, err),
+    }
+}
+
+fn fork(plan: &Plan, err: &ErrPipeWrite) -> io::Result<pid_t> {
+    // Get the mask before forking, so the child doesn't have to.
+    let sigmask = match plan.sigmask {
+        Some(sigmask) => sigmask,
+        None => sig::sigprocmask(libc::SIG_BLOCK, &sig::empty_sigset())?,
+    };
+    let pid = sys::fork()?;
+    if pid == 0 {
+        plan.exec_in_child(err, &sigmask);
+    }
+    Ok(pid)
+}
+
+/// Stack size for the vfork child.  It only runs `Plan::exec_in_child()`.
+#[cfg(target_os = "linux")]
+const VFORK_STACK_SIZE: usize = 64 * 1024;
+
+#[cfg(target_os = "linux")]
+fn vfork(plan: &Plan, err: &ErrPipeWrite) -> io::Result<pid_t> {
+    struct Args<'a> {
+        plan: &'a Plan,
+        err: &'a ErrPipeWrite,
+        sigmask: sigset_t,
+    }
+
+    extern "C" fn child(arg: *mut libc::c_void) -> c_int {
+        let args = unsafe { &*(arg as *const Args) };
+        args.plan.exec_in_child(args.err, &args.sigmask)
+    }
+
+    // The child shares our memory, so it can't use our stack.
+    let mut stack = vec![0u8; VFORK_STACK_SIZE];
+    // The stack grows down; align its top.
+    let stack_top = (stack.as_mut_ptr() as usize + VFORK_STACK_SIZE) & !15;
+
+    // Block all signals, so that none of our handlers runs in the child, which
+    // shares our memory, before it resets them to their defaults.  The child
+    // then sets its own mask.
+    let mut all = sig::empty_sigset();
+    unsafe { libc::sigfillset(&mut all) };
+    let old_mask = sig::sigprocmask(libc::SIG_SETMASK, &all)?;
+
+    let args = Args { plan, err, sigmask: plan.sigmask.unwrap_or(old_mask) };
+    // Returns once the child has exec'ed or exited.
+    let pid = unsafe {
+        libc::clone(
+            child, stack_top as *mut libc::c_void,
+            libc::CLONE_VM | libc::CLONE_VFORK | libc::SIGCHLD,
+            &args as *const Args as *mut libc::c_void)
+    };
+    let clone_err = io::Error::last_os_error();
+
+    sig::sigprocmask(libc::SIG_SETMASK, &old_mask)?;
+    match pid {
+        -1 => Err(clone_err),
+        pid if pid > 0 => Ok(pid),
+        ret => panic!("clone returned {}", ret),
+    }
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::err_pipe::new_err_pipe;
+    use crate::sel::Read;
+
+    fn backends() -> Vec<Backend> {
+        let mut backends = vec![Backend::Fork];
+        #[cfg(target_os = "linux")]
+        backends.push(Backend::Vfork);
+        backends
+    }
+
+    /// Spawns `plan` and waits for it.  Returns its status and errors.
+    fn run(backend: Backend, plan: &Plan) -> (c_int, Vec<crate::err::ProcError>) {
+        let (mut err_read, err_write) = new_err_pipe().unwrap();
+        let pid = spawn(backend, plan, &err_write).unwrap();
+        err_write.close().unwrap();
+        let (_, status, _) = sys::wait4(pid, true).unwrap().unwrap();
+        while !err_read.read() {}
+        err_read.close().unwrap();
+        (status, err_read.take_errors())
+    }
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn exit_status() {
+        for backend in backends() {
+            let plan = Plan::new(0, &argv(&["/bin/sh", "-c", "exit 7"]), &Env::new()).unwrap();
+            let (status, errors) = run(backend, &plan);
+            assert_eq!(unsafe { libc::WEXITSTATUS(status) }, 7);
+            assert!(errors.is_empty());
+        }
+    }
+
+    #[test]
+    fn exec_error() {
+        for backend in backends() {
+            let plan = Plan::new(0, &argv(&["/nonexistent"]), &Env::new()).unwrap();
+            let (status, errors) = run(backend, &plan);
+            assert_eq!(unsafe { libc::WEXITSTATUS(status) }, exitcode::OSERR);
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].stage, Stage::Exec);
+            assert_eq!(errors[0].errno_name, Some("ENOENT"));
+            assert!(errors[0].message.starts_with("exec: /nonexistent: No such file"));
+        }
+    }
+
+    #[test]
+    fn fd_error() {
+        for backend in backends() {
+            let mut plan = Plan::new(0, &argv(&["/bin/true"]), &Env::new()).unwrap();
+            plan.add_fd(1, vec![Action::Open {
+                fd: 1,
+                path: CString::new("/nonexistent/file").unwrap(),
+                oflags: libc::O_WRONLY | libc::O_CREAT,
+                mode: 0o666,
+            }]);
+            plan.add_fd(2, vec![Action::Dup2 { from: 1, fd: 2 }]);
+            let (status, errors) = run(backend, &plan);
+            // The proc isn't exec'ed.
+            assert_eq!(unsafe { libc::WEXITSTATUS(status) }, exitcode::OSERR);
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].stage, Stage::FdSetup);
+            assert_eq!(errors[0].fd, Some(1));
+            assert_eq!(errors[0].errno, Some(libc::ENOENT));
+            assert!(errors[0].message.starts_with("failed to set up fd 1: No such file"));
+        }
+    }
+}
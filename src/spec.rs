@@ -1,10 +1,8 @@
 use crate::sys::fd_t;
 use libc::c_int;
-use serde::{Serialize, Deserialize, Deserializer};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
 use std::collections::BTreeMap;
 use std::fmt;
-use std::fs::File;
-use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::string::String;
 use std::vec::Vec;
@@ -72,6 +70,10 @@ where
 pub enum Error {
     Io(std::io::Error),
     Json(serde_json::error::Error),
+    Yaml(serde_yaml::Error),
+    Toml(toml::de::Error),
+    /// The spec is well-formed but invalid.
+    Invalid(String),
 }
 
 impl std::fmt::Display for Error {
@@ -79,6 +81,9 @@ impl std::fmt::Display for Error {
         match *self {
             Error::Io(ref err) => err.fmt(f),
             Error::Json(ref err) => err.fmt(f),
+            Error::Yaml(ref err) => err.fmt(f),
+            Error::Toml(ref err) => err.fmt(f),
+            Error::Invalid(ref msg) => f.write_str(msg),
         }
     }
 }
@@ -88,6 +93,9 @@ impl std::error::Error for Error {
         match *self {
             Error::Io(ref err) => err.description(),
             Error::Json(ref err) => err.description(),
+            Error::Yaml(_) => "invalid YAML",
+            Error::Toml(_) => "invalid TOML",
+            Error::Invalid(ref msg) => msg,
         }
     }
 }
@@ -104,14 +112,42 @@ impl From<serde_json::error::Error> for Error {
     }
 }
 
-type Result<T> = std::result::Result<T, Error>;
+impl From<serde_yaml::Error> for Error {
+    fn from(err: serde_yaml::Error) -> Error {
+        Error::Yaml(err)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Error {
+        Error::Toml(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Checks that a string can be passed to the OS.
+fn check_no_nul(what: &str, s: &str) -> Result<()> {
+    if s.contains('\0') {
+        Err(Error::Invalid(format!("{} contains nul: {:?}", what, s)))
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks that a string is a valid env var name.
+fn check_env_var_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('=') {
+        return Err(Error::Invalid(format!("bad env var name: {:?}", name)));
+    }
+    check_no_nul("env var name", name)
+}
 
 //------------------------------------------------------------------------------
 // Env spec
 //------------------------------------------------------------------------------
 
-#[derive(Serialize, Debug, PartialEq)]
-#[serde(deny_unknown_fields)]
+#[derive(Debug, PartialEq)]
 pub enum EnvInherit {
     None,
     All,
@@ -155,18 +191,64 @@ impl<'de> Deserialize<'de> for EnvInherit {
     }
 }
 
+/// Serializes as the deserializer accepts, so that specs round-trip.
+impl Serialize for EnvInherit {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer
+    {
+        match self {
+            EnvInherit::None => serializer.serialize_bool(false),
+            EnvInherit::All => serializer.serialize_bool(true),
+            EnvInherit::Vars(vars) => vars.serialize(serializer),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
 #[serde(deny_unknown_fields, default)]
 pub struct Env {
+    /// Env vars to inherit; names may be patterns.
     pub inherit: EnvInherit,
-    pub vars: BTreeMap<String, String>,
+    /// Env vars to set, or if None, to remove.  Values may refer to inherited
+    /// env vars.
+    pub vars: BTreeMap<String, Option<String>>,
+    /// Patterns of names of env vars not to inherit.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Entries to add to the start of colon-separated list env vars, like
+    /// PATH.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub prepend: BTreeMap<String, Vec<String>>,
+    /// Entries to add to the end of colon-separated list env vars.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub append: BTreeMap<String, Vec<String>>,
+}
+
+impl Env {
+    fn validate(&self) -> Result<()> {
+        let patterns = match &self.inherit {
+            EnvInherit::Vars(vars) => vars.as_slice(),
+            _ => &[],
+        };
+        for pattern in patterns.iter().chain(&self.exclude) {
+            crate::environ::compile_pattern(pattern).map_err(Error::Invalid)?;
+        }
+        for (name, entries) in self.prepend.iter().chain(&self.append) {
+            check_env_var_name(name)?;
+            for entry in entries {
+                check_no_nul("env var value", entry)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 //------------------------------------------------------------------------------
 // Fd spec
 //------------------------------------------------------------------------------
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub enum OpenFlag {
     // FIXME: Generalize.
@@ -195,7 +277,7 @@ impl Default for OpenFlag {
     fn default() -> Self { Self::Default }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 #[serde(rename_all = "lowercase")]
 pub enum CaptureMode {
@@ -207,7 +289,7 @@ impl Default for CaptureMode {
     fn default() -> Self { Self::TempFile }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 #[serde(deny_unknown_fields)]
 #[serde(rename_all = "lowercase")]
 pub enum CaptureFormat {
@@ -223,7 +305,7 @@ fn get_default_mode() -> c_int {
     0o666
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 #[serde(rename_all = "lowercase")]
 pub enum Fd {
@@ -269,39 +351,1252 @@ impl Default for Fd {
     fn default() -> Self { Self::Inherit }
 }
 
+impl Fd {
+    /// Opens the fd to /dev/null.
+    pub fn null() -> Self {
+        Fd::Null { flags: OpenFlag::Default }
+    }
+
+    /// Opens the fd to the file at `path`, with default flags and mode.
+    pub fn file<P: Into<PathBuf>>(path: P) -> Self {
+        Fd::File {
+            path: path.into(),
+            flags: OpenFlag::Default,
+            mode: get_default_mode(),
+        }
+    }
+
+    /// Duplicates `fd` to the fd.
+    pub fn dup(fd: fd_t) -> Self {
+        Fd::Dup { fd }
+    }
+
+    /// Captures output from the fd as text, via a temporary file.
+    pub fn capture() -> Self {
+        Fd::Capture { mode: CaptureMode::default(), format: CaptureFormat::default() }
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self {
+            Fd::File { path, .. } => match path.to_str() {
+                Some(path) => check_no_nul("path", path),
+                None => Err(Error::Invalid(format!("path not UTF-8: {:?}", path))),
+            },
+            Fd::Dup { fd } if *fd < 0 => Err(Error::Invalid(format!("bad fd: {}", fd))),
+            _ => Ok(()),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+// Signal spec
+//------------------------------------------------------------------------------
+
+/// A signal, by number or by name.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Signal {
+    Num(c_int),
+    Name(String),
+}
+
+impl Signal {
+    pub fn to_signum(&self) -> Option<c_int> {
+        match self {
+            Signal::Num(signum) => Some(*signum),
+            Signal::Name(name) => crate::sig::parse_signum(name),
+        }
+    }
+}
+
+impl Default for Signal {
+    fn default() -> Self {
+        Signal::Num(libc::SIGTERM)
+    }
+}
+
+//------------------------------------------------------------------------------
+// Retry spec
+//------------------------------------------------------------------------------
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_backoff() -> f64 {
+    1.
+}
+
+/// When and how to retry a proc that fails.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Retry {
+    /// Maximum number of attempts, including the first.
+    pub attempts: usize,
+    /// Exit codes that are retried.  If None, any nonzero exit code is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_codes: Option<Vec<i32>>,
+    /// Signals that are retried, if the proc is terminated by one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signals: Vec<Signal>,
+    /// Whether a proc that times out is retried.
+    #[serde(default = "default_true")]
+    pub timeout: bool,
+    /// Delay before the first retry, in s.
+    #[serde(default)]
+    pub delay: f64,
+    /// Factor by which the delay grows for each later retry.
+    #[serde(default = "default_backoff")]
+    pub backoff: f64,
+}
+
+impl Retry {
+    /// Retries up to `attempts` attempts in all, on any nonzero exit code or
+    /// timeout, without delay.
+    pub fn new(attempts: usize) -> Self {
+        Retry {
+            attempts,
+            exit_codes: None,
+            signals: Vec::new(),
+            timeout: true,
+            delay: 0.,
+            backoff: 1.,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.attempts == 0 {
+            return Err(Error::Invalid("retry attempts must be positive".to_string()));
+        }
+        if !self.delay.is_finite() || self.delay < 0. {
+            return Err(Error::Invalid(format!("bad retry delay: {}", self.delay)));
+        }
+        if !self.backoff.is_finite() || self.backoff < 1. {
+            return Err(Error::Invalid(format!("bad retry backoff: {}", self.backoff)));
+        }
+        for signal in &self.signals {
+            if signal.to_signum().is_none() {
+                return Err(Error::Invalid(format!("bad signal: {:?}", signal)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the delay before retry number `retry`, counting from 1.
+    pub fn get_delay(&self, retry: usize) -> f64 {
+        self.delay * self.backoff.powi(retry as i32 - 1)
+    }
+}
+
+//------------------------------------------------------------------------------
+// Repeat spec
+//------------------------------------------------------------------------------
+
+/// How many times to run a proc, for instance to benchmark it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RepeatSpec")]
+pub struct Repeat {
+    /// Number of runs, not counting warmup runs.
+    pub count: usize,
+    /// Number of runs before those, whose results are discarded.
+    pub warmup: usize,
+}
+
+/// A repeat is given either as a count alone, or with warmup runs.
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum RepeatSpec {
+    Count(usize),
+    Full {
+        count: usize,
+        #[serde(default)]
+        warmup: usize,
+    },
+}
+
+impl From<RepeatSpec> for Repeat {
+    fn from(spec: RepeatSpec) -> Self {
+        match spec {
+            RepeatSpec::Count(count) => Repeat { count, warmup: 0 },
+            RepeatSpec::Full { count, warmup } => Repeat { count, warmup },
+        }
+    }
+}
+
+impl Repeat {
+    pub fn new(count: usize) -> Self {
+        Repeat { count, warmup: 0 }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.count == 0 {
+            return Err(Error::Invalid("repeat count must be positive".to_string()));
+        }
+        Ok(())
+    }
+}
+
+//------------------------------------------------------------------------------
+// Expect spec
+//------------------------------------------------------------------------------
+
+/// Expected output on an fd.  Each condition given must hold.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputExpect {
+    /// The output is exactly this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub equals: Option<String>,
+    /// The output contains this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contains: Option<String>,
+    /// A regex that matches somewhere in the output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matches: Option<String>,
+}
+
+impl OutputExpect {
+    fn validate(&self) -> Result<()> {
+        if let Some(matches) = &self.matches {
+            regex::Regex::new(matches).map_err(|err| Error::Invalid(
+                format!("bad regex {:?}: {}", matches, err)))?;
+        }
+        Ok(())
+    }
+}
+
+/// The expected outcome of a proc, checked once it finishes.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expect {
+    /// The exit code.  If neither this nor `signal` is given, 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// The signal that terminates the proc.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<Signal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<OutputExpect>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<OutputExpect>,
+    /// Maximum wall time, in s.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_elapsed: Option<f64>,
+    /// Maximum resident set size, in KiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rss: Option<u64>,
+}
+
+impl Expect {
+    fn validate(&self) -> Result<()> {
+        if self.exit_code.is_some() && self.signal.is_some() {
+            return Err(Error::Invalid("expect both exit_code and signal".to_string()));
+        }
+        if let Some(signal) = &self.signal {
+            if signal.to_signum().is_none() {
+                return Err(Error::Invalid(format!("bad signal: {:?}", signal)));
+            }
+        }
+        for output in self.stdout.iter().chain(self.stderr.iter()) {
+            output.validate()?;
+        }
+        if let Some(max_elapsed) = self.max_elapsed {
+            if !max_elapsed.is_finite() || max_elapsed < 0. {
+                return Err(Error::Invalid(format!("bad max_elapsed: {}", max_elapsed)));
+            }
+        }
+        Ok(())
+    }
+}
+
+//------------------------------------------------------------------------------
+// Dependency spec
+//------------------------------------------------------------------------------
+
+/// When a dependency is satisfied.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Condition {
+    /// The other proc ran and exited with code 0.
+    #[default]
+    Success,
+    /// The other proc finished, however it went.
+    Completion,
+    /// The other proc was started.
+    Started,
+}
+
+/// A dependency on another proc, by name.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "AfterSpec")]
+pub struct After {
+    pub proc: String,
+    pub condition: Condition,
+}
+
+/// A dependency is given either as a name alone, or with a condition.
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum AfterSpec {
+    Name(String),
+    Full {
+        proc: String,
+        #[serde(default)]
+        condition: Condition,
+    },
+}
+
+impl From<AfterSpec> for After {
+    fn from(spec: AfterSpec) -> Self {
+        match spec {
+            AfterSpec::Name(proc) => After { proc, condition: Condition::default() },
+            AfterSpec::Full { proc, condition } => After { proc, condition },
+        }
+    }
+}
+
 //------------------------------------------------------------------------------
 // Process spec
 //------------------------------------------------------------------------------
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
 #[serde(deny_unknown_fields)]
 #[serde(default)]
 pub struct Proc {
+    /// Optional name, to identify the proc in results.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub argv: Vec<String>,
+    /// Working directory for the proc; if None, inherited.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
     pub env: Env,
     pub fds: Vec<(String, Fd)>,
+    /// Other procs that must reach some condition before this one starts.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<After>,
+    /// When procs are queued, those with higher priority start first.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub priority: i32,
+    /// Time limit in s, after which the proc is sent SIGTERM.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<f64>,
+    /// Time in s after a timeout's SIGTERM, after which the proc is sent
+    /// SIGKILL.  If None, it isn't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kill_after: Option<f64>,
+    /// How to retry the proc, if it fails.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<Retry>,
+    /// How many times to run the proc.  If None, once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<Repeat>,
+    /// The expected outcome.  If None, any outcome passes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect: Option<Expect>,
+}
+
+fn is_zero(val: &i32) -> bool {
+    *val == 0
+}
+
+/// Builder methods, which validate as they go.  For example,
+///
+/// ```
+/// # use ir::spec::{Fd, Proc};
+/// let proc = Proc::new(vec!["/bin/echo", "hello"])?
+///     .cwd("/tmp")?
+///     .env_var("LANG", "C")?
+///     .stdout(Fd::capture())?;
+/// # Ok::<(), ir::spec::Error>(())
+/// ```
+impl Proc {
+    /// Starts a spec for a proc that runs `argv`.  The first element is also
+    /// the executable.
+    pub fn new<I, S>(argv: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let argv = argv.into_iter().map(Into::into).collect::<Vec<String>>();
+        if argv.is_empty() {
+            return Err(Error::Invalid("empty argv".to_string()));
+        }
+        for arg in &argv {
+            check_no_nul("arg", arg)?;
+        }
+        Ok(Proc { argv, ..Default::default() })
+    }
+
+    pub fn name<S: Into<String>>(mut self, name: S) -> Result<Self> {
+        let name = name.into();
+        if name.is_empty() {
+            return Err(Error::Invalid("empty name".to_string()));
+        }
+        self.name = Some(name);
+        Ok(self)
+    }
+
+    pub fn cwd<P: Into<PathBuf>>(mut self, cwd: P) -> Result<Self> {
+        let cwd = cwd.into();
+        match cwd.to_str() {
+            Some(s) => check_no_nul("cwd", s)?,
+            None => return Err(Error::Invalid(format!("cwd not UTF-8: {:?}", cwd))),
+        }
+        self.cwd = Some(cwd);
+        Ok(self)
+    }
+
+    /// Sets which env vars to inherit.
+    pub fn env_inherit(mut self, inherit: EnvInherit) -> Result<Self> {
+        if let EnvInherit::Vars(vars) = &inherit {
+            for var in vars {
+                check_no_nul("env var name", var)?;
+            }
+        }
+        self.env.inherit = inherit;
+        Ok(self)
+    }
+
+    /// Sets an env var.
+    pub fn env_var<N, V>(mut self, name: N, value: V) -> Result<Self>
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        let (name, value) = (name.into(), value.into());
+        check_env_var_name(&name)?;
+        check_no_nul("env var value", &value)?;
+        self.env.vars.insert(name, Some(value));
+        Ok(self)
+    }
+
+    /// Removes an env var, if it's inherited.
+    pub fn env_unset<N: Into<String>>(mut self, name: N) -> Result<Self> {
+        let name = name.into();
+        check_no_nul("env var name", &name)?;
+        self.env.vars.insert(name, None);
+        Ok(self)
+    }
+
+    /// Adds an entry to the start of a colon-separated list env var.
+    pub fn env_prepend<N, V>(mut self, name: N, entry: V) -> Result<Self>
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        let (name, entry) = (name.into(), entry.into());
+        check_env_var_name(&name)?;
+        check_no_nul("env var value", &entry)?;
+        self.env.prepend.entry(name).or_default().push(entry);
+        Ok(self)
+    }
+
+    /// Adds an entry to the end of a colon-separated list env var.
+    pub fn env_append<N, V>(mut self, name: N, entry: V) -> Result<Self>
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        let (name, entry) = (name.into(), entry.into());
+        check_env_var_name(&name)?;
+        check_no_nul("env var value", &entry)?;
+        self.env.append.entry(name).or_default().push(entry);
+        Ok(self)
+    }
+
+    /// Excludes env vars matching a pattern from those inherited.
+    pub fn env_exclude<P: Into<String>>(mut self, pattern: P) -> Result<Self> {
+        let pattern = pattern.into();
+        crate::environ::compile_pattern(&pattern).map_err(Error::Invalid)?;
+        self.env.exclude.push(pattern);
+        Ok(self)
+    }
+
+    /// Sets up `fd`.  Each fd may be given only once.
+    pub fn fd(mut self, fd: fd_t, spec: Fd) -> Result<Self> {
+        if fd < 0 {
+            return Err(Error::Invalid(format!("bad fd: {}", fd)));
+        }
+        if self.fds.iter().any(|(f, _)| crate::fd::parse_fd(f) == Ok(fd)) {
+            return Err(Error::Invalid(format!("fd {} given more than once", fd)));
+        }
+        spec.validate()?;
+        self.fds.push((crate::fd::get_fd_name(fd), spec));
+        Ok(self)
+    }
+
+    /// Starts the proc only once the proc named `proc` satisfies `condition`.
+    pub fn after<S: Into<String>>(mut self, proc: S, condition: Condition) -> Result<Self> {
+        let proc = proc.into();
+        if proc.is_empty() {
+            return Err(Error::Invalid("empty name in after".to_string()));
+        }
+        self.after.push(After { proc, condition });
+        Ok(self)
+    }
+
+    pub fn priority(mut self, priority: i32) -> Result<Self> {
+        self.priority = priority;
+        Ok(self)
+    }
+
+    pub fn timeout(mut self, timeout: f64) -> Result<Self> {
+        self.timeout = Some(timeout);
+        self.validate()?;
+        Ok(self)
+    }
+
+    pub fn kill_after(mut self, kill_after: f64) -> Result<Self> {
+        self.kill_after = Some(kill_after);
+        self.validate()?;
+        Ok(self)
+    }
+
+    pub fn retry(mut self, retry: Retry) -> Result<Self> {
+        retry.validate()?;
+        self.retry = Some(retry);
+        Ok(self)
+    }
+
+    pub fn repeat(mut self, repeat: Repeat) -> Result<Self> {
+        repeat.validate()?;
+        self.repeat = Some(repeat);
+        Ok(self)
+    }
+
+    pub fn expect(mut self, expect: Expect) -> Result<Self> {
+        expect.validate()?;
+        self.expect = Some(expect);
+        Ok(self)
+    }
+
+    /// Checks settings that serde doesn't.
+    fn validate(&self) -> Result<()> {
+        self.env.validate()?;
+        if let Some(timeout) = self.timeout {
+            if !timeout.is_finite() || timeout <= 0. {
+                return Err(Error::Invalid(format!("bad timeout: {}", timeout)));
+            }
+        }
+        if let Some(kill_after) = self.kill_after {
+            if !kill_after.is_finite() || kill_after < 0. {
+                return Err(Error::Invalid(format!("bad kill_after: {}", kill_after)));
+            }
+        }
+        if let Some(retry) = &self.retry {
+            retry.validate()?;
+        }
+        if let Some(repeat) = &self.repeat {
+            repeat.validate()?;
+        }
+        if let Some(expect) = &self.expect {
+            expect.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn stdin(self, spec: Fd) -> Result<Self> {
+        self.fd(0, spec)
+    }
+
+    pub fn stdout(self, spec: Fd) -> Result<Self> {
+        self.fd(1, spec)
+    }
+
+    pub fn stderr(self, spec: Fd) -> Result<Self> {
+        self.fd(2, spec)
+    }
 }
 
 //------------------------------------------------------------------------------
 // Input spec
 //------------------------------------------------------------------------------
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Input {
     #[serde(deserialize_with = "one_or_many")]
     pub procs: Vec<Proc>,
+    /// Maximum number of procs to run at once; others are queued.  If None,
+    /// all start at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_parallel: Option<usize>,
+    /// Variables to substitute into procs.  See `vars`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, String>,
 }
 
-pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Input> {
-    // Open the file in read-only mode with buffer.
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+impl Input {
+    /// Returns the index of the proc named `name`.
+    pub fn find_proc(&self, name: &str) -> Option<usize> {
+        self.procs.iter().position(|p| p.name.as_deref() == Some(name))
+    }
+
+    /// Substitutes variables into each proc's argv, env var values, cwd, and
+    /// file paths.
+    pub fn expand(&mut self, vars: &crate::vars::Vars) -> Result<()> {
+        for (index, proc) in self.procs.iter_mut().enumerate() {
+            let name = proc.name.as_deref();
+            let expand = |text: &str| vars.expand(text, index, name).map_err(Error::Invalid);
+            let expand_path = |path: &Path| match path.to_str() {
+                Some(text) => expand(text).map(PathBuf::from),
+                // Not UTF-8, so no variables.
+                None => Ok(path.to_path_buf()),
+            };
+            for arg in proc.argv.iter_mut() {
+                *arg = expand(arg)?;
+            }
+            for value in proc.env.vars.values_mut().flatten() {
+                *value = vars.expand_env(value, index, name).map_err(Error::Invalid)?;
+            }
+            for entry in proc.env.prepend.values_mut().chain(proc.env.append.values_mut())
+                .flatten() {
+                *entry = vars.expand_env(entry, index, name).map_err(Error::Invalid)?;
+            }
+            if let Some(cwd) = &proc.cwd {
+                proc.cwd = Some(expand_path(cwd)?);
+            }
+            for (_, fd) in proc.fds.iter_mut() {
+                if let Fd::File { path, .. } = fd {
+                    *path = expand_path(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks relations between procs: names are unique, and dependencies
+    /// name other procs and don't form cycles.  Also checks settings that serde
+    /// doesn't.
+    pub fn validate(&self) -> Result<()> {
+        if self.max_parallel == Some(0) {
+            return Err(Error::Invalid("max_parallel must be positive".to_string()));
+        }
+        for (i, proc) in self.procs.iter().enumerate() {
+            proc.validate()?;
+            if let Some(name) = &proc.name {
+                if self.find_proc(name) != Some(i) {
+                    return Err(Error::Invalid(format!("duplicate proc name: {}", name)));
+                }
+            }
+            for after in &proc.after {
+                match self.find_proc(&after.proc) {
+                    Some(j) if j == i => return Err(Error::Invalid(
+                        format!("proc {} is after itself", after.proc))),
+                    Some(_) => (),
+                    None => return Err(Error::Invalid(
+                        format!("after unknown proc: {}", after.proc))),
+                }
+            }
+        }
+
+        // Look for cycles by depth-first search from each proc.  `path` holds
+        // procs on the way to the current one; `done` procs are acyclic.
+        fn visit(input: &Input, i: usize, path: &mut Vec<usize>, done: &mut Vec<bool>)
+                 -> Result<()> {
+            if done[i] {
+                return Ok(());
+            }
+            if let Some(pos) = path.iter().position(|&j| j == i) {
+                let names = path[pos ..].iter().chain(std::iter::once(&i))
+                    .map(|&j| input.procs[j].name.clone().unwrap_or_default())
+                    .collect::<Vec<_>>();
+                return Err(Error::Invalid(
+                    format!("dependency cycle: {}", names.join(" -> "))));
+            }
+            path.push(i);
+            for after in &input.procs[i].after {
+                visit(input, input.find_proc(&after.proc).unwrap(), path, done)?;
+            }
+            path.pop();
+            done[i] = true;
+            Ok(())
+        }
+
+        let mut done = vec![false; self.procs.len()];
+        for i in 0 .. self.procs.len() {
+            visit(self, i, &mut Vec::new(), &mut done)?;
+        }
+        Ok(())
+    }
+}
+
+//------------------------------------------------------------------------------
+// Loading specs
+//------------------------------------------------------------------------------
+
+/// A format in which a spec is written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    /// Returns the format with a name, such as "yaml".
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "toml" => Some(Format::Toml),
+            _ => None,
+        }
+    }
+
+    /// Returns the format indicated by a path's extension, or JSON if none is.
+    pub fn from_path(path: &Path) -> Format {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| Format::from_name(&ext.to_ascii_lowercase()))
+            .unwrap_or(Format::Json)
+    }
+}
+
+fn parse_value(text: &str, format: Format) -> Result<serde_json::Value> {
+    Ok(match format {
+        Format::Json => serde_json::from_str(text)?,
+        Format::Yaml => serde_yaml::from_str(text)?,
+        Format::Toml => toml::from_str(text)?,
+    })
+}
+
+/// Replaces a spec value with its combination with the specs it includes.
+/// Their paths are relative to `dir`.  `stack` holds the paths of the specs
+/// being included, to detect cycles.
+fn include(value: &mut serde_json::Value, dir: &Path, stack: &mut Vec<PathBuf>) -> Result<()> {
+    let paths = match value.as_object_mut().and_then(|v| v.remove("include")) {
+        None => return Ok(()),
+        Some(serde_json::Value::Array(paths)) => paths,
+        Some(path) => vec![path],
+    };
+    let mut specs = Vec::new();
+    for path in paths {
+        let path = match path.as_str() {
+            Some(path) => dir.join(path),
+            None => return Err(Error::Invalid(format!("bad include: {}", path))),
+        };
+        specs.push(read_value(&path, Format::from_path(&path), stack)
+            .map_err(|err| Error::Invalid(format!("{}: {}", path.display(), err)))?);
+    }
+    specs.push(value.take());
+    *value = crate::template::combine(specs)?;
+    Ok(())
+}
+
+/// Reads a spec file to a value, including the specs it includes.
+fn read_value(path: &Path, format: Format, stack: &mut Vec<PathBuf>) -> Result<serde_json::Value> {
+    let canonical = path.canonicalize()?;
+    if stack.contains(&canonical) {
+        return Err(Error::Invalid("include cycle".to_string()));
+    }
+    let text = std::fs::read_to_string(path)?;
+    check_text(&text, format)?;
+    let mut value = parse_value(&text, format)?;
+    stack.push(canonical);
+    include(&mut value, path.parent().unwrap_or_else(|| Path::new("")), stack)?;
+    stack.pop();
+    Ok(value)
+}
+
+/// A proc, defaults, or template, as written in a spec that uses templates.
+/// Deserializing one checks the settings it gives as a proc's, with positions
+/// in errors, but they needn't be complete, and may include `extends` and
+/// `args`.
+struct RawProc;
+
+impl<'de> Deserialize<'de> for RawProc {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<RawProc, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RawProcVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for RawProcVisitor {
+            type Value = RawProc;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map")
+            }
 
-    // Read the JSON contents of the file as an instance of `Proc`.
-    let spec = serde_json::from_reader(reader)?;
+            fn visit_map<A>(self, map: A) -> std::result::Result<RawProc, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let map = SkipTemplateKeys(map);
+                Proc::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok(RawProc)
+            }
+        }
 
-    // Return the spec.
+        deserializer.deserialize_map(RawProcVisitor)
+    }
+}
+
+/// Passes through the entries of a map, except those only templates use.
+struct SkipTemplateKeys<A>(A);
+
+impl<'de, A> serde::de::MapAccess<'de> for SkipTemplateKeys<A>
+where
+    A: serde::de::MapAccess<'de>,
+{
+    type Error = A::Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> std::result::Result<Option<K::Value>, A::Error>
+    where
+        K: serde::de::DeserializeSeed<'de>,
+    {
+        // Deserialize each key with `seed` from inside the map's own key
+        // deserializer, so that errors give the key's position.
+        struct KeySeed<'a, K>(&'a mut Option<K>);
+
+        impl<'de, 'a, K> serde::de::DeserializeSeed<'de> for KeySeed<'a, K>
+        where
+            K: serde::de::DeserializeSeed<'de>,
+        {
+            /// None for a key that's skipped.
+            type Value = Option<K::Value>;
+
+            fn deserialize<D>(self, deserializer: D) -> std::result::Result<Self::Value, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                deserializer.deserialize_str(self)
+            }
+        }
+
+        impl<'de, 'a, K> serde::de::Visitor<'de> for KeySeed<'a, K>
+        where
+            K: serde::de::DeserializeSeed<'de>,
+        {
+            type Value = Option<K::Value>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a string")
+            }
+
+            fn visit_str<E>(self, key: &str) -> std::result::Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                use serde::de::IntoDeserializer;
+                if crate::template::PROC_TEMPLATE_KEYS.contains(&key) {
+                    Ok(None)
+                } else {
+                    self.0.take().unwrap().deserialize(key.into_deserializer()).map(Some)
+                }
+            }
+        }
+
+        let mut seed = Some(seed);
+        while let Some(key) = self.0.next_key_seed(KeySeed(&mut seed))? {
+            match key {
+                Some(key) => return Ok(Some(key)),
+                None => { self.0.next_value::<serde::de::IgnoredAny>()?; },
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> std::result::Result<V::Value, A::Error>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        self.0.next_value_seed(seed)
+    }
+}
+
+/// A spec as written, before includes and templates are applied.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct RawInput {
+    #[serde(default, deserialize_with = "one_or_many")]
+    procs: Vec<RawProc>,
+    #[serde(default)]
+    max_parallel: Option<usize>,
+    #[serde(default)]
+    vars: BTreeMap<String, String>,
+    #[serde(default)]
+    defaults: Option<RawProc>,
+    #[serde(default)]
+    templates: BTreeMap<String, RawProc>,
+    #[serde(default)]
+    include: Option<serde::de::IgnoredAny>,
+}
+
+/// Checks a spec's text as written, so that errors give positions.  Once
+/// includes and templates are applied to its value, there are none.
+fn check_text(text: &str, format: Format) -> Result<()> {
+    match format {
+        Format::Json => { serde_json::from_str::<RawInput>(text)?; },
+        Format::Yaml => { serde_yaml::from_str::<RawInput>(text)?; },
+        Format::Toml => { toml::from_str::<RawInput>(text)?; },
+    }
+    Ok(())
+}
+
+/// Deserializes and validates a spec value, applying its defaults and
+/// templates.  `text` is the spec's text, if the value is parsed from it.
+fn from_value(mut value: serde_json::Value, text: Option<(&str, Format)>) -> Result<Input> {
+    let spec: Input = match text {
+        // Deserialize from the text, so that errors give positions.
+        Some((text, Format::Json)) if !crate::template::uses_templates(&value) =>
+            serde_json::from_str(text)?,
+        Some((text, Format::Yaml)) if !crate::template::uses_templates(&value) =>
+            serde_yaml::from_str(text)?,
+        Some((text, Format::Toml)) if !crate::template::uses_templates(&value) =>
+            toml::from_str(text)?,
+        _ => {
+            if let Some((text, format)) = text {
+                check_text(text, format)?;
+            }
+            crate::template::apply(&mut value)?;
+            serde_json::from_value(value)?
+        },
+    };
+    spec.validate()?;
     Ok(spec)
 }
 
+/// Parses and validates a spec, applying its defaults and templates.  Specs it
+/// includes are relative to the current directory.
+pub fn parse(text: &str, format: Format) -> Result<Input> {
+    let mut value = parse_value(text, format)?;
+    if value.get("include").is_some() {
+        check_text(text, format)?;
+        include(&mut value, Path::new(""), &mut Vec::new())?;
+        from_value(value, None)
+    } else {
+        from_value(value, Some((text, format)))
+    }
+}
+
+/// Loads a spec from a file, in the format indicated by its extension.
+pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Input> {
+    let path = path.as_ref();
+    load_file_format(path, Format::from_path(path))
+}
+
+/// Loads a spec from a file in `format`.  Specs it includes are relative to
+/// its directory.
+pub fn load_file_format<P: AsRef<Path>>(path: P, format: Format) -> Result<Input> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)?;
+    let mut value = parse_value(&text, format)?;
+    if value.get("include").is_some() {
+        check_text(&text, format)?;
+        let canonical = path.canonicalize()?;
+        include(&mut value, path.parent().unwrap_or_else(|| Path::new("")), &mut vec![canonical])?;
+        from_value(value, None)
+    } else {
+        from_value(value, Some((&text, format)))
+    }
+}
+
+/// Loads specs from files, combined into one.  If `format` is None, each is in
+/// the format indicated by its extension.
+pub fn load_files<P: AsRef<Path>>(paths: &[P], format: Option<Format>) -> Result<Input> {
+    if let [path] = paths {
+        let path = path.as_ref();
+        return load_file_format(path, format.unwrap_or_else(|| Format::from_path(path)));
+    }
+    let specs = paths.iter()
+        .map(|path| {
+            let path = path.as_ref();
+            let format = format.unwrap_or_else(|| Format::from_path(path));
+            read_value(path, format, &mut Vec::new())
+                .map_err(|err| Error::Invalid(format!("{}: {}", path.display(), err)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    from_value(crate::template::combine(specs)?, None)
+}
+
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder() {
+        let proc = Proc::new(vec!["/bin/echo", "hello"]).unwrap()
+            .name("echo").unwrap()
+            .cwd("/tmp").unwrap()
+            .env_inherit(EnvInherit::Vars(vec!["HOME".to_string()])).unwrap()
+            .env_var("LANG", "C").unwrap()
+            .stdout(Fd::capture()).unwrap()
+            .stderr(Fd::dup(1)).unwrap();
+        assert_eq!(proc.argv, vec!["/bin/echo", "hello"]);
+        assert_eq!(proc.cwd, Some(PathBuf::from("/tmp")));
+        assert_eq!(proc.env.vars["LANG"].as_deref(), Some("C"));
+        assert_eq!(proc.fds, vec![
+            ("stdout".to_string(), Fd::capture()),
+            ("stderr".to_string(), Fd::Dup { fd: 1 }),
+        ]);
+    }
+
+    #[test]
+    fn builder_invalid() {
+        assert!(Proc::new(Vec::<String>::new()).is_err());
+        assert!(Proc::new(vec!["/bin/echo", "a\0b"]).is_err());
+
+        let proc = || Proc::new(vec!["/bin/true"]).unwrap();
+        assert!(proc().env_var("A=B", "C").is_err());
+        assert!(proc().env_var("", "C").is_err());
+        assert!(proc().fd(-1, Fd::Close).is_err());
+        assert!(proc().fd(1, Fd::dup(-2)).is_err());
+        assert!(proc().stdout(Fd::null()).unwrap().fd(1, Fd::Close).is_err());
+    }
+
+    #[test]
+    fn after() {
+        let input: Input = serde_json::from_str(r#"{"procs": [
+            {"name": "a", "argv": ["/bin/true"]},
+            {"name": "b", "argv": ["/bin/true"], "after": ["a"]},
+            {"argv": ["/bin/true"], "after": [
+                "a", {"proc": "b", "condition": "completion"}]}
+        ]}"#).unwrap();
+        assert_eq!(input.procs[2].after, vec![
+            After { proc: "a".to_string(), condition: Condition::Success },
+            After { proc: "b".to_string(), condition: Condition::Completion },
+        ]);
+        assert!(input.validate().is_ok());
+
+        let invalid = |json| {
+            let input: Input = serde_json::from_str(json).unwrap();
+            input.validate().unwrap_err().to_string()
+        };
+        assert_eq!(
+            invalid(r#"{"procs": [{"argv": ["/bin/true"], "after": ["x"]}]}"#),
+            "after unknown proc: x");
+        assert_eq!(
+            invalid(r#"{"procs": [
+                {"name": "a", "argv": ["/bin/true"]},
+                {"name": "a", "argv": ["/bin/true"]}
+            ]}"#),
+            "duplicate proc name: a");
+        assert_eq!(
+            invalid(r#"{"procs": [
+                {"name": "a", "argv": ["/bin/true"], "after": ["c"]},
+                {"name": "b", "argv": ["/bin/true"], "after": ["a"]},
+                {"name": "c", "argv": ["/bin/true"], "after": ["b"]}
+            ]}"#),
+            "dependency cycle: a -> c -> b -> a");
+    }
+
+    #[test]
+    fn signals() {
+        assert_eq!(Signal::Num(9).to_signum(), Some(9));
+        assert_eq!(Signal::Name("SIGUSR1".to_string()).to_signum(), Some(libc::SIGUSR1));
+        assert_eq!(Signal::Name("USR1".to_string()).to_signum(), Some(libc::SIGUSR1));
+        assert_eq!(Signal::Name("15".to_string()).to_signum(), Some(15));
+        assert_eq!(Signal::Name("FOO".to_string()).to_signum(), None);
+    }
+
+    #[test]
+    fn retry() {
+        let proc: Proc = serde_json::from_str(r#"{
+            "argv": ["/bin/false"],
+            "timeout": 10,
+            "retry": {"attempts": 3, "signals": ["SIGKILL"], "delay": 0.5, "backoff": 2}
+        }"#).unwrap();
+        let retry = proc.retry.unwrap();
+        assert_eq!(retry.exit_codes, None);
+        assert!(retry.timeout);
+        assert_eq!(retry.get_delay(1), 0.5);
+        assert_eq!(retry.get_delay(2), 1.);
+
+        let proc = || Proc::new(vec!["/bin/true"]).unwrap();
+        assert!(proc().timeout(0.).is_err());
+        assert!(proc().kill_after(0.).is_ok());
+        assert!(proc().kill_after(-1.).is_err());
+        assert!(proc().retry(Retry::new(0)).is_err());
+        assert!(proc().retry(Retry { delay: -1., ..Retry::new(2) }).is_err());
+        assert!(proc().retry(Retry {
+            signals: vec![Signal::Name("SIGFOO".to_string())], ..Retry::new(2)
+        }).is_err());
+    }
+
+    #[test]
+    fn repeat() {
+        let parse = |json| serde_json::from_str::<Proc>(json).unwrap().repeat.unwrap();
+        assert_eq!(parse(r#"{"argv": ["/bin/true"], "repeat": 5}"#), Repeat::new(5));
+        assert_eq!(
+            parse(r#"{"argv": ["/bin/true"], "repeat": {"count": 5, "warmup": 2}}"#),
+            Repeat { count: 5, warmup: 2 });
+        assert!(Proc::new(vec!["/bin/true"]).unwrap().repeat(Repeat::new(0)).is_err());
+    }
+
+    #[test]
+    fn expect() {
+        let proc: Proc = serde_json::from_str(r#"{
+            "argv": ["/bin/echo", "hello"],
+            "expect": {"exit_code": 0, "stdout": {"matches": "^hel+o$"}, "max_rss": 4096}
+        }"#).unwrap();
+        let expect = proc.expect.unwrap();
+        assert_eq!(expect.stdout.unwrap().matches.unwrap(), "^hel+o$");
+        assert_eq!(expect.stderr, None);
+
+        let proc = || Proc::new(vec!["/bin/true"]).unwrap();
+        assert!(proc().expect(Expect {
+            stdout: Some(OutputExpect { matches: Some("(".to_string()), ..Default::default() }),
+            ..Default::default()
+        }).is_err());
+        assert!(proc().expect(Expect {
+            exit_code: Some(0), signal: Some(Signal::Num(9)), ..Default::default()
+        }).is_err());
+    }
+
+    #[test]
+    fn formats() {
+        let json = parse(r#"{
+            "max_parallel": 2,
+            "procs": [
+                {"name": "echo", "argv": ["/bin/echo", "hello"], "fds": [["stdout", {"capture": {}}]]},
+                {"argv": ["/bin/true"], "after": ["echo"], "timeout": 5}
+            ]
+        }"#, Format::Json).unwrap();
+        let yaml = parse("
+# Comments are allowed.
+max_parallel: 2
+procs:
+  - name: echo
+    argv: [/bin/echo, hello]
+    fds:
+      - [stdout, {capture: {}}]
+  - argv: [/bin/true]
+    after: [echo]
+    timeout: 5
+", Format::Yaml).unwrap();
+        let toml = parse(r#"
+# Comments are allowed.
+max_parallel = 2
+
+[[procs]]
+name = "echo"
+argv = ["/bin/echo", "hello"]
+fds = [["stdout", {capture = {}}]]
+
+[[procs]]
+argv = ["/bin/true"]
+after = ["echo"]
+timeout = 5
+"#, Format::Toml).unwrap();
+        assert_eq!(yaml, json);
+        assert_eq!(toml, json);
+
+        // Errors give the position.
+        let err = parse("procs:\n  - argv: [/bin/true]\n    argh: 1\n", Format::Yaml);
+        assert!(err.unwrap_err().to_string().contains("line 3"));
+        let err = parse("[[procs]]\nargv = [\"/bin/true\"]\nargh = 1\n", Format::Toml);
+        assert!(err.unwrap_err().to_string().contains("line 3"));
+
+        // So do errors in specs with templates, in procs and templates.
+        let err = parse("\
+templates:
+  t: {timeout: 5}
+procs:
+  - argv: [/bin/true]
+    extends: t
+    argh: 1
+", Format::Yaml).unwrap_err().to_string();
+        assert!(err.contains("procs[0]: unknown field `argh`"), "{}", err);
+        assert!(err.contains("line 6"), "{}", err);
+        let err = parse("\
+templates:
+  t:
+    argh: 1
+procs:
+  - {argv: [/bin/true], extends: t}
+", Format::Yaml).unwrap_err().to_string();
+        assert!(err.contains("templates.t: unknown field `argh`"), "{}", err);
+        assert!(err.contains("line 3"), "{}", err);
+        let err = parse(r#"{
+            "defaults": {"timeout": 5},
+            "procs": [{"argv": ["/bin/true"], "argh": 1}]
+        }"#, Format::Json).unwrap_err().to_string();
+        assert!(err.contains("line 3"), "{}", err);
+        let err = parse(r#"
+[templates.t]
+timeout = "soon"
+
+[[procs]]
+argv = ["/bin/true"]
+extends = "t"
+"#, Format::Toml).unwrap_err().to_string();
+        assert!(err.contains("line 3"), "{}", err);
+
+        assert_eq!(Format::from_path(Path::new("spec.YML")), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("spec.toml")), Format::Toml);
+        assert_eq!(Format::from_path(Path::new("spec")), Format::Json);
+    }
+
+    #[test]
+    fn include_errors() {
+        let dir = std::env::temp_dir().join(format!("ir-spec-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("main.json"),
+            r#"{"include": "common.yaml", "procs": [{"argv": ["/bin/true"]}]}"#).unwrap();
+        std::fs::write(
+            dir.join("common.yaml"),
+            "templates:\n  t:\n    timeout: soon\n").unwrap();
+        std::fs::write(
+            dir.join("other.toml"),
+            "[[procs]]\nargv = [\"/bin/true\"]\nargh = 1\n").unwrap();
+
+        // Errors in included files give the file and position.
+        let err = load_file(dir.join("main.json")).unwrap_err().to_string();
+        assert!(err.contains("common.yaml: templates.t.timeout: invalid type"), "{}", err);
+        assert!(err.contains("line 3"), "{}", err);
+        let err = load_files(&[dir.join("main.json"), dir.join("other.toml")], None)
+            .unwrap_err().to_string();
+        assert!(err.contains("common.yaml"), "{}", err);
+        let err = load_files(&[dir.join("other.toml"), dir.join("other.toml")], None)
+            .unwrap_err().to_string();
+        assert!(err.contains("other.toml: TOML parse error at line 3"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let input = Input {
+            max_parallel: Some(2),
+            vars: btreemap! {"dir".to_string() => "/tmp/${run.id}".to_string()},
+            procs: vec![
+                Proc::new(vec!["/bin/cat"]).unwrap()
+                    .priority(-1).unwrap()
+                    .env_inherit(EnvInherit::None).unwrap()
+                    .stdin(Fd::file("/dev/zero")).unwrap(),
+                Proc::new(vec!["/bin/true"]).unwrap()
+                    .name("true").unwrap()
+                    .expect(Expect {
+                        signal: Some(Signal::Name("SIGTERM".to_string())),
+                        stderr: Some(OutputExpect {
+                            contains: Some("oops".to_string()), ..Default::default()
+                        }),
+                        max_elapsed: Some(2.),
+                        ..Default::default()
+                    }).unwrap()
+                    .timeout(5.).unwrap()
+                    .retry(Retry { exit_codes: Some(vec![1]), ..Retry::new(3) }).unwrap()
+                    .env_inherit(EnvInherit::Vars(vec!["PATH".to_string()])).unwrap()
+                    .fd(3, Fd::Close).unwrap(),
+                Proc::new(vec!["/bin/env"]).unwrap()
+                    .name("env").unwrap()
+                    .repeat(Repeat { count: 3, warmup: 1 }).unwrap()
+                    .after("true", Condition::Started).unwrap()
+                    .stdout(Fd::Capture {
+                        mode: CaptureMode::Memory,
+                        format: CaptureFormat::Base64,
+                    }).unwrap(),
+            ],
+        };
+        let json = serde_json::to_string(&input).unwrap();
+        assert_eq!(serde_json::from_str::<Input>(&json).unwrap(), input);
+    }
+}
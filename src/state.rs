@@ -0,0 +1,147 @@
+//! Snapshots of the state of procs while they run, for a state file.
+//!
+//! A state file lets other programs monitor a run without reading its output.
+//! It's rewritten atomically, so a reader always sees a complete snapshot.
+
+use crate::fdio;
+use crate::res;
+use libc::{c_int, pid_t};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+//------------------------------------------------------------------------------
+
+/// Where a proc is in its life.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Not started yet, or waiting to start again.
+    Pending,
+    /// Started, and not yet terminated.
+    Running,
+    /// Terminated; its output may still be read.
+    Exited,
+    /// Terminated, and its results are complete.
+    Finished,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Proc {
+    pub index: usize,
+    pub name: Option<String>,
+    pub phase: Phase,
+    /// Number of attempts started, including retries and repeats.
+    pub attempts: usize,
+    /// The pid, once started.
+    pub pid: Option<pid_t>,
+    /// Pid status, exit code, and signum, once exited.
+    pub status: Option<c_int>,
+    pub exit_code: Option<i32>,
+    pub signum: Option<i32>,
+    /// Once finished, whether the program ran.
+    pub result: Option<res::ProcState>,
+    /// Once finished, whether it met its expected outcome, if it has one.
+    pub passed: Option<bool>,
+    /// Number of bytes captured so far, by fd name.
+    pub captured: BTreeMap<String, u64>,
+    pub num_errors: usize,
+}
+
+impl Proc {
+    pub fn new(index: usize, name: Option<String>) -> Self {
+        Self {
+            index,
+            name,
+            phase: Phase::Pending,
+            attempts: 0,
+            pid: None,
+            status: None,
+            exit_code: None,
+            signum: None,
+            result: None,
+            passed: None,
+            captured: BTreeMap::new(),
+            num_errors: 0,
+        }
+    }
+
+    pub fn set_running(&mut self, pid: pid_t) {
+        self.phase = Phase::Running;
+        self.attempts += 1;
+        self.pid = Some(pid);
+        self.status = None;
+        self.exit_code = None;
+        self.signum = None;
+    }
+
+    /// The proc will start again, to retry or repeat it.
+    pub fn set_pending(&mut self) {
+        self.phase = Phase::Pending;
+    }
+
+    pub fn set_exited(&mut self, status: c_int) {
+        let (exit_code, signum, _) = res::split_status(status);
+        self.phase = Phase::Exited;
+        self.status = Some(status);
+        self.exit_code = exit_code;
+        self.signum = signum;
+    }
+
+    pub fn set_finished(&mut self, result: &res::ProcRes) {
+        self.phase = Phase::Finished;
+        self.exit_code = result.exit_code;
+        self.signum = result.signum;
+        self.result = Some(result.state);
+        self.passed = result.passed;
+        self.num_errors = result.errors.len();
+    }
+}
+
+/// A snapshot of a run.
+#[derive(Debug, Serialize)]
+pub struct State {
+    /// Pid of the process running the procs.
+    pub pid: pid_t,
+    /// When the snapshot was taken, in s since the epoch.
+    pub time: f64,
+    /// True once all procs have finished.
+    pub done: bool,
+    pub procs: Vec<Proc>,
+}
+
+impl State {
+    pub fn new(procs: Vec<Proc>, done: bool) -> Self {
+        let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0., |d| d.as_secs_f64());
+        Self { pid: crate::sys::getpid(), time, done, procs }
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// A file to which to write snapshots.
+pub struct StateFile {
+    path: PathBuf,
+    /// How often to write a snapshot, if nothing changes.
+    pub interval: Duration,
+}
+
+impl StateFile {
+    pub fn new(path: &Path, interval: Duration) -> Self {
+        Self { path: path.to_path_buf(), interval }
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes a snapshot, replacing the previous one atomically.
+    pub fn write(&self, state: &State) -> io::Result<()> {
+        let mut data = serde_json::to_vec(state)?;
+        data.push(b'\n');
+        fdio::write_file_atomic(&self.path, &data)
+    }
+}
@@ -1,15 +1,12 @@
 extern crate libc;
 
-use libc::{c_int, pid_t, rusage, ssize_t};
-use std::ffi::CString;
+use libc::{c_char, c_int, pid_t, rusage, ssize_t};
+use std::ffi::{CStr, CString};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::mem::MaybeUninit;
-use std::string::String;
 use std::vec::Vec;
 
-use crate::environ::Env;
-
 #[allow(non_camel_case_types)]
 pub type fd_t = c_int;
 
@@ -17,7 +14,7 @@ pub type fd_t = c_int;
 
 /// C-style char* array, containing a NULL-terminated array of pointers to
 /// nul-terminated strings.
-struct CStringVec {
+pub struct CStringVec {
     // Nul-terminated strings.
     // FIXME: We need to keep this around as it stores the actual strings
     // pointed to by `ptrs`, but Rust doesn't know this.  Should figure out how
@@ -26,40 +23,42 @@ struct CStringVec {
     strs: Vec<CString>,
 
     // NULL-terminated vector of char* pointers.
-    ptrs: Vec<*const i8>,
+    ptrs: Vec<*const c_char>,
 }
 
 impl CStringVec {
-    pub fn as_ptr(&self) -> *const *const i8 { self.ptrs.as_ptr() as *const *const i8 }
-}
-
-impl<T> From<T> for CStringVec
-where T: IntoIterator<Item = String>
-{
-    fn from(strings: T) -> Self {
+    /// Builds the array from `strings`.  Fails if any contains a nul.
+    pub fn new<T>(strings: T) -> io::Result<Self>
+    where T: IntoIterator<Item = String>
+    {
         // Build nul-terminated strings.
         let strs
             = strings.into_iter()
-            .map(|s| { CString::new(s).unwrap() })
-            .collect::<Vec<_>>();
+            .map(CString::new)
+            .collect::<Result<Vec<_>, _>>()?;
 
         // Grab their pointers into an array.
         let mut ptrs
             = strs.iter()
             .map(|s| {
-                s.as_ptr() as *const i8
+                s.as_ptr()
             })
             .collect::<Vec<_>>();
         // NULL-terminate the pointer array.
         ptrs.push(std::ptr::null());
 
-        Self { strs, ptrs }
+        Ok(Self { strs, ptrs })
     }
+
+    pub fn as_ptr(&self) -> *const *const c_char { self.ptrs.as_ptr() }
 }
 
 //------------------------------------------------------------------------------
 
-// FIXME: Boy does this need some docs.
+/// An `fd_set` for `select()`, along with the largest fd it contains (or -1).
+///
+/// `select()` can't handle fds at or above `FD_SETSIZE`; `set()` panics on
+/// these, so callers must check first.
 pub struct FdSet(libc::fd_set, fd_t);
 
 impl FdSet {
@@ -80,6 +79,7 @@ impl FdSet {
     }
 
     pub fn set(&mut self, fd: fd_t) {
+        assert!((fd as usize) < libc::FD_SETSIZE, "fd too large for select: {}", fd);
         unsafe {
             libc::FD_SET(fd, &mut self.0);
         };
@@ -117,32 +117,60 @@ pub fn dup2(fd: fd_t, fd2: fd_t) -> io::Result<()> {
     }
 }
 
-pub fn execv(exe: String, args: Vec<String>) -> io::Result<()> {
-    let res = unsafe {
-        libc::execv(
-            exe.as_ptr() as *const i8,
-            CStringVec::from(args).as_ptr())
-    };
-    // execv only returns on failure, with result -1.
-    assert!(res == -1);
-    Err(io::Error::last_os_error())
+/// Creates a new epoll instance, with close-on-exec set.
+#[cfg(target_os = "linux")]
+pub fn epoll_create() -> io::Result<fd_t> {
+    match unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd if fd >= 0 => Ok(fd),
+        ret => panic!("epoll_create1 returned {}", ret),
+    }
 }
 
-pub fn execve(exe: String, args: Vec<String>, env: Env) -> io::Result<()> {
-    // Construct NAME=val strings for env vars.
-    let env: Vec<String> = env.into_iter().map(|(n, v)| {
-        format!("{}={}", n, v)
-    }).collect();
-
-    let res = unsafe {
-        libc::execve(
-            CString::new(exe).unwrap().as_ptr() as *const i8,
-            CStringVec::from(args).as_ptr(), 
-            CStringVec::from(env).as_ptr())
-    };
-    // execve only returns on failure, with result -1.
-    assert!(res == -1);
-    Err(io::Error::last_os_error())
+/// Adds, modifies, or removes `fd` in the interest list of `epfd`.  The fd
+/// itself is stored as the event's user data.
+#[cfg(target_os = "linux")]
+pub fn epoll_ctl(epfd: fd_t, op: c_int, fd: fd_t, events: u32) -> io::Result<()> {
+    let mut event = libc::epoll_event { events, u64: fd as u64 };
+    match unsafe { libc::epoll_ctl(epfd, op, fd, &mut event) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        ret => panic!("epoll_ctl returned {}", ret),
+    }
+}
+
+/// Waits for events on `epfd`, filling `events`.  A negative `timeout` (in ms)
+/// blocks indefinitely.  Returns the number of events filled.
+#[cfg(target_os = "linux")]
+pub fn epoll_wait(
+    epfd: fd_t, events: &mut [libc::epoll_event], timeout: c_int)
+    -> io::Result<usize>
+{
+    match unsafe {
+        libc::epoll_wait(
+            epfd, events.as_mut_ptr(), events.len() as c_int, timeout)
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        n if n >= 0 => Ok(n as usize),
+        ret => panic!("epoll_wait returned {}", ret),
+    }
+}
+
+/// Executes `exe`.  Only returns on failure, with the error.
+///
+/// Doesn't allocate, so this is safe to call after fork or vfork.
+pub fn execve(exe: &CStr, argv: &CStringVec, envp: &CStringVec) -> io::Error {
+    unsafe {
+        libc::execve(exe.as_ptr(), argv.as_ptr(), envp.as_ptr());
+    }
+    io::Error::last_os_error()
+}
+
+fn fcntl(fd: fd_t, cmd: c_int, arg: c_int) -> io::Result<c_int> {
+    match unsafe { libc::fcntl(fd, cmd, arg) } {
+        -1 => Err(io::Error::last_os_error()),
+        ret => Ok(ret),
+    }
 }
 
 pub fn fork() -> io::Result<pid_t> {
@@ -155,19 +183,45 @@ pub fn fork() -> io::Result<pid_t> {
     }
 }
 
+pub fn fstat(fd: fd_t) -> io::Result<libc::stat> {
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    match unsafe { libc::fstat(fd, stat.as_mut_ptr()) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(unsafe { stat.assume_init() }),
+        ret => panic!("fstat returned {}", ret),
+    }
+}
+
 pub fn getpid() -> pid_t {
     unsafe { libc::getpid() }
 }
 
+pub fn kill(pid: pid_t, signum: c_int) -> io::Result<()> {
+    match unsafe { libc::kill(pid, signum) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        ret => panic!("kill returned {}", ret),
+    }
+}
+
+/// Creates and opens a unique temporary file.  The fd is close-on-exec.
 pub fn mkstemp(template: &str) -> io::Result<(PathBuf, fd_t)> {
     let path = CString::new(template)?;
     let (fd, path) = unsafe {
         let ptr = path.into_raw();
-        (libc::mkstemp(ptr), CString::from_raw(ptr))
+        #[cfg(target_os = "linux")]
+        let fd = libc::mkostemp(ptr, libc::O_CLOEXEC);
+        #[cfg(not(target_os = "linux"))]
+        let fd = libc::mkstemp(ptr);
+        (fd, CString::from_raw(ptr))
     };
     match fd {
         -1 => Err(io::Error::last_os_error()),
-        _ if fd >= 0 => Ok((PathBuf::from(path.into_string().unwrap()), fd)),
+        _ if fd >= 0 => {
+            #[cfg(not(target_os = "linux"))]
+            set_cloexec(fd)?;
+            Ok((PathBuf::from(path.into_string().unwrap()), fd))
+        },
         _ => panic!("mkstemp returned {}", fd),
     }
 }
@@ -185,6 +239,42 @@ pub fn open(path: &Path, oflag: c_int, mode: c_int) -> io::Result<fd_t> {
     }
 }
 
+// Not yet in libc.  These are the same on all Linux architectures, since
+// they're newer than the syscall table unification.
+#[cfg(target_os = "linux")]
+const SYS_PIDFD_SEND_SIGNAL: libc::c_long = 424;
+#[cfg(target_os = "linux")]
+const SYS_PIDFD_OPEN: libc::c_long = 434;
+#[cfg(target_os = "linux")]
+const P_PIDFD: libc::idtype_t = 3;
+
+/// Opens a pidfd for child process `pid`.  The pidfd is close-on-exec, and
+/// becomes readable when the process terminates.
+///
+/// Fails with `ENOSYS` on kernels before 5.3.
+#[cfg(target_os = "linux")]
+pub fn pidfd_open(pid: pid_t) -> io::Result<fd_t> {
+    match unsafe { libc::syscall(SYS_PIDFD_OPEN, pid, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd if fd >= 0 => Ok(fd as fd_t),
+        ret => panic!("pidfd_open returned {}", ret),
+    }
+}
+
+/// Sends a signal to the process referred to by `pidfd`.  Unlike `kill()`,
+/// this can't signal an unrelated process that has reused the pid.
+#[cfg(target_os = "linux")]
+pub fn pidfd_send_signal(pidfd: fd_t, signum: c_int) -> io::Result<()> {
+    let info: *const libc::siginfo_t = std::ptr::null();
+    match unsafe {
+        libc::syscall(SYS_PIDFD_SEND_SIGNAL, pidfd, signum, info, 0)
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        ret => panic!("pidfd_send_signal returned {}", ret),
+    }
+}
+
 /// Creates an anonymous pipe.
 ///
 /// Returns the read and write file descriptors of the ends of the pipe.
@@ -197,6 +287,26 @@ pub fn pipe() -> io::Result<(fd_t, fd_t)> {
     }
 }
 
+/// Creates an anonymous pipe, with both ends close-on-exec.
+#[cfg(target_os = "linux")]
+pub fn pipe_cloexec() -> io::Result<(fd_t, fd_t)> {
+    let mut fildes: [fd_t; 2] = [-1, -1];
+    match unsafe { libc::pipe2(fildes.as_mut_ptr(), libc::O_CLOEXEC) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok((fildes[0], fildes[1])),
+        ret => panic!("pipe2 returned {}", ret),
+    }
+}
+
+/// Creates an anonymous pipe, with both ends close-on-exec.
+#[cfg(not(target_os = "linux"))]
+pub fn pipe_cloexec() -> io::Result<(fd_t, fd_t)> {
+    let (read_fd, write_fd) = pipe()?;
+    set_cloexec(read_fd)?;
+    set_cloexec(write_fd)?;
+    Ok((read_fd, write_fd))
+}
+
 pub fn read(fd: fd_t, buf: &mut [u8]) -> io::Result<usize> {
     match unsafe {
         libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
@@ -237,6 +347,30 @@ pub fn select(
     }
 }
 
+/// Sets the close-on-exec flag on `fd`.
+pub fn set_cloexec(fd: fd_t) -> io::Result<()> {
+    let flags = fcntl(fd, libc::F_GETFD, 0)?;
+    fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC)?;
+    Ok(())
+}
+
+/// Sets `O_NONBLOCK` on `fd`.
+pub fn set_nonblocking(fd: fd_t) -> io::Result<()> {
+    let flags = fcntl(fd, libc::F_GETFL, 0)?;
+    fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK)?;
+    Ok(())
+}
+
+/// Creates a new session, with the calling process as its leader.  Returns the
+/// session ID.
+pub fn setsid() -> io::Result<pid_t> {
+    match unsafe { libc::setsid() } {
+        -1 => Err(io::Error::last_os_error()),
+        sid if sid > 0 => Ok(sid),
+        ret => panic!("setsid returned {}", ret),
+    }
+}
+
 pub type WaitInfo = (pid_t, c_int, rusage);
 
 /// Performs a (possibly) blocking wait if `block`; else returns immediately.
@@ -254,6 +388,64 @@ pub fn wait4(pid: pid_t, block: bool) -> io::Result<Option<WaitInfo>> {
     }
 }
 
+/// The leading fields of `siginfo_t` for `SIGCHLD`, which libc doesn't expose.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[allow(dead_code)]
+struct SigchldInfo {
+    si_signo: c_int,
+    si_errno: c_int,
+    si_code: c_int,
+    // The union that follows is pointer-aligned.
+    #[cfg(target_pointer_width = "64")]
+    _pad: c_int,
+    si_pid: pid_t,
+    si_uid: libc::uid_t,
+    si_status: c_int,
+}
+
+#[cfg(target_os = "linux")]
+const CLD_EXITED: c_int = 1;
+#[cfg(target_os = "linux")]
+const CLD_KILLED: c_int = 2;
+#[cfg(target_os = "linux")]
+const CLD_DUMPED: c_int = 3;
+
+/// Waits for the terminated process referred to by `pidfd`, with
+/// `waitid(P_PIDFD, ...)`.  Like `wait4()`, returns its pid, status, and
+/// resource usage, and `Ok(None)` only if a nonblocking call finds the process
+/// still running.
+#[cfg(target_os = "linux")]
+pub fn waitid_pidfd(pidfd: fd_t, block: bool) -> io::Result<Option<WaitInfo>> {
+    let mut info = MaybeUninit::<libc::siginfo_t>::zeroed();
+    let mut usage = MaybeUninit::<rusage>::zeroed();
+    let options = libc::WEXITED | if block { 0 } else { libc::WNOHANG };
+    // The raw syscall, unlike the libc wrapper, also returns resource usage.
+    match unsafe {
+        libc::syscall(
+            libc::SYS_waitid, P_PIDFD, pidfd, info.as_mut_ptr(), options,
+            usage.as_mut_ptr())
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            let info = unsafe { &*(info.as_ptr() as *const SigchldInfo) };
+            if info.si_pid == 0 {
+                // WNOHANG, and the process hasn't terminated.
+                return Ok(None);
+            }
+            // Reconstruct the status that wait4() would have returned.
+            let status = match info.si_code {
+                CLD_EXITED => (info.si_status & 0xff) << 8,
+                CLD_KILLED => info.si_status & 0x7f,
+                CLD_DUMPED => (info.si_status & 0x7f) | 0x80,
+                code => panic!("waitid returned si_code {}", code),
+            };
+            Ok(Some((info.si_pid, status, unsafe { usage.assume_init() })))
+        },
+        ret => panic!("waitid returned {}", ret),
+    }
+}
+
 pub fn write(fd: fd_t, data: &[u8]) -> io::Result<ssize_t> {
     match unsafe {
         libc::write(fd, data.as_ptr() as *const libc::c_void, data.len())
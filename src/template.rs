@@ -0,0 +1,360 @@
+//! Spec templates: `defaults` for every proc, and named `templates` that procs
+//! `extends`.  These are applied to a spec's value before it's deserialized.
+//!
+//! A proc's settings are merged from its defaults, then each template it
+//! extends, in order, then its own settings.  Each merges into the ones before
+//! it as follows:
+//! - `env`: `vars`, `prepend`, and `append` are merged by name; `inherit` and
+//!   `exclude` replace the earlier ones
+//! - `fds`: fds are merged by fd; each replaces an earlier one for the same fd
+//! - `argv` replaces the earlier argv, and `args` are appended to it
+//! - any other setting replaces the earlier one
+//!
+//! A template may itself extend other templates.  An entry in `extends` may
+//! also be a template itself, rather than a name.
+//!
+//! Specs may be combined, as when one includes others.  Their procs and
+//! templates are pooled, but each spec's defaults apply only to its own procs.
+
+use crate::spec::{Error, Result};
+use serde_json::{Map, Value};
+
+//------------------------------------------------------------------------------
+
+type Object = Map<String, Value>;
+
+/// Keys that only templates use.
+const TEMPLATE_KEYS: &[&str] = &["defaults", "templates"];
+pub const PROC_TEMPLATE_KEYS: &[&str] = &["extends", "args"];
+
+fn invalid<T>(msg: String) -> Result<T> {
+    Err(Error::Invalid(msg))
+}
+
+/// Returns the procs of a spec value, which may be a single proc.
+fn get_procs(spec: &Value) -> Vec<&Value> {
+    match spec.get("procs") {
+        Some(Value::Array(procs)) => procs.iter().collect(),
+        Some(proc) => vec![proc],
+        None => Vec::new(),
+    }
+}
+
+fn get_procs_mut(spec: &mut Object) -> Vec<&mut Value> {
+    match spec.get_mut("procs") {
+        Some(Value::Array(procs)) => procs.iter_mut().collect(),
+        Some(proc) => vec![proc],
+        None => Vec::new(),
+    }
+}
+
+/// True if a spec value uses templates, so they must be applied.
+pub fn uses_templates(spec: &Value) -> bool {
+    TEMPLATE_KEYS.iter().any(|&k| spec.get(k).is_some())
+        || get_procs(spec).iter().any(|proc| {
+            PROC_TEMPLATE_KEYS.iter().any(|&k| proc.get(k).is_some())
+        })
+}
+
+/// Returns the fd number of an fd name, for comparison.
+fn get_fd_key(fd: &Value) -> Value {
+    match fd.as_str().map(crate::fd::parse_fd) {
+        Some(Ok(num)) => Value::from(num),
+        _ => fd.clone(),
+    }
+}
+
+/// Merges proc settings `over` into `base`.
+fn merge(base: &mut Object, mut over: Object) -> Result<()> {
+    // Args append to the argv at the same level, so apply them last.
+    let args = over.remove("args");
+    for (key, value) in over {
+        match (key.as_str(), base.get_mut(&key), value) {
+            ("env", Some(Value::Object(base_env)), Value::Object(env)) => {
+                for (env_key, env_value) in env {
+                    match (env_key.as_str(), base_env.get_mut(&env_key), env_value) {
+                        (
+                            "vars" | "prepend" | "append",
+                            Some(Value::Object(base_vars)),
+                            Value::Object(vars),
+                        ) =>
+                            base_vars.extend(vars),
+                        (_, _, env_value) => { base_env.insert(env_key, env_value); },
+                    }
+                }
+            },
+            ("fds", Some(Value::Array(base_fds)), Value::Array(fds)) => {
+                for fd in fds {
+                    let fd_key = fd.get(0).map(get_fd_key);
+                    match base_fds.iter_mut().find(|f| f.get(0).map(get_fd_key) == fd_key) {
+                        Some(base_fd) => *base_fd = fd,
+                        None => base_fds.push(fd),
+                    }
+                }
+            },
+            (_, _, value) => { base.insert(key, value); },
+        }
+    }
+    match (args, base.get_mut("argv")) {
+        (None, _) => Ok(()),
+        (Some(Value::Array(args)), Some(Value::Array(argv))) => {
+            argv.extend(args);
+            Ok(())
+        },
+        (Some(Value::Array(_)), _) => invalid("args without argv".to_string()),
+        (Some(_), _) => invalid("args must be a list".to_string()),
+    }
+}
+
+/// Returns the templates that a proc or template extends.  Each is a
+/// template's name, or the template itself.
+fn get_extends(object: &mut Object) -> Result<Vec<Value>> {
+    match object.remove("extends") {
+        None => Ok(Vec::new()),
+        Some(Value::Array(extends)) => Ok(extends),
+        Some(extends) => Ok(vec![extends]),
+    }
+}
+
+struct Templates {
+    templates: Object,
+}
+
+impl Templates {
+    /// Merges a template, including those it extends, into `base`.  `stack`
+    /// holds the names of templates being merged, to detect cycles.
+    fn merge_into(&self, base: &mut Object, extends: &Value, stack: &mut Vec<String>)
+        -> Result<()>
+    {
+        let mut template = match extends {
+            Value::String(name) => {
+                stack.push(name.to_string());
+                if stack[.. stack.len() - 1].contains(name) {
+                    return invalid(format!("template cycle: {}", stack.join(" -> ")));
+                }
+                match self.templates.get(name) {
+                    Some(Value::Object(template)) => template.clone(),
+                    Some(_) => return invalid(format!("template {} is not an object", name)),
+                    None => return invalid(format!("unknown template: {}", name)),
+                }
+            },
+            Value::Object(template) => {
+                stack.push("{...}".to_string());
+                template.clone()
+            },
+            _ => return invalid(format!("bad extends: {}", extends)),
+        };
+        if template.contains_key("name") {
+            return invalid("defaults and templates can't give a name".to_string());
+        }
+        for extends in get_extends(&mut template)? {
+            self.merge_into(base, &extends, stack)?;
+        }
+        merge(base, template)?;
+        stack.pop();
+        Ok(())
+    }
+}
+
+/// Applies defaults and templates to each proc of a spec value, and removes
+/// them from it.
+pub fn apply(spec: &mut Value) -> Result<()> {
+    let spec = match spec {
+        Value::Object(spec) => spec,
+        _ => return Ok(()),
+    };
+    let defaults = match spec.remove("defaults") {
+        Some(Value::Object(defaults)) => defaults,
+        Some(_) => return invalid("defaults is not an object".to_string()),
+        None => Object::new(),
+    };
+    let templates = match spec.remove("templates") {
+        Some(Value::Object(templates)) => Templates { templates },
+        Some(_) => return invalid("templates is not an object".to_string()),
+        None => Templates { templates: Object::new() },
+    };
+    if defaults.contains_key("name") {
+        return invalid("defaults and templates can't give a name".to_string());
+    }
+
+    for proc in get_procs_mut(spec) {
+        if let Value::Object(object) = proc {
+            let mut merged = Object::new();
+            merge(&mut merged, defaults.clone())?;
+            for extends in get_extends(object)? {
+                templates.merge_into(&mut merged, &extends, &mut Vec::new())?;
+            }
+            merge(&mut merged, std::mem::take(object))?;
+            *object = merged;
+        }
+    }
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+// Combining specs
+//------------------------------------------------------------------------------
+
+/// Moves a spec's defaults into each of its procs, as the first template each
+/// extends, so that they apply only to its own procs.
+fn scope_defaults(spec: &mut Object) -> Result<()> {
+    let defaults = match spec.remove("defaults") {
+        Some(Value::Object(defaults)) => defaults,
+        Some(_) => return invalid("defaults is not an object".to_string()),
+        None => return Ok(()),
+    };
+    for proc in get_procs_mut(spec) {
+        if let Value::Object(proc) = proc {
+            let mut extends = get_extends(proc)?;
+            extends.insert(0, Value::Object(defaults.clone()));
+            proc.insert("extends".to_string(), Value::Array(extends));
+        }
+    }
+    Ok(())
+}
+
+/// Combines spec values into one.  Their procs are concatenated, and their
+/// templates and vars merged by name; for these and other keys, later specs
+/// take precedence.  Each spec's defaults apply only to its own procs.
+pub fn combine(specs: Vec<Value>) -> Result<Value> {
+    let mut combined = Object::new();
+    let mut procs = Vec::new();
+    for spec in specs {
+        let mut spec = match spec {
+            Value::Object(spec) => spec,
+            _ => return invalid("spec is not an object".to_string()),
+        };
+        scope_defaults(&mut spec)?;
+        for (key, value) in spec {
+            match (key.as_str(), combined.get_mut(&key), value) {
+                ("procs", _, Value::Array(p)) => procs.extend(p),
+                ("procs", _, proc) => procs.push(proc),
+                ("templates" | "vars", Some(Value::Object(base)), Value::Object(value)) =>
+                    base.extend(value),
+                (_, _, value) => { combined.insert(key, value); },
+            }
+        }
+    }
+    combined.insert("procs".to_string(), Value::Array(procs));
+    Ok(Value::Object(combined))
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn apply_json(mut spec: Value) -> Result<Value> {
+        apply(&mut spec).map(|()| spec)
+    }
+
+    #[test]
+    fn defaults() {
+        let spec = apply_json(json!({
+            "defaults": {
+                "env": {"inherit": false, "vars": {"A": "1", "B": "2"}},
+                "fds": [["stdout", {"capture": {}}], ["stderr", "null"]],
+                "timeout": 10
+            },
+            "procs": [
+                {"argv": ["/bin/true"]},
+                {
+                    "argv": ["/bin/false"],
+                    "env": {"vars": {"B": "3"}},
+                    "fds": [["2", {"capture": {}}]],
+                    "timeout": 5
+                }
+            ]
+        })).unwrap();
+        assert_eq!(spec, json!({"procs": [
+            {
+                "argv": ["/bin/true"],
+                "env": {"inherit": false, "vars": {"A": "1", "B": "2"}},
+                "fds": [["stdout", {"capture": {}}], ["stderr", "null"]],
+                "timeout": 10
+            },
+            {
+                "argv": ["/bin/false"],
+                "env": {"inherit": false, "vars": {"A": "1", "B": "3"}},
+                "fds": [["stdout", {"capture": {}}], ["2", {"capture": {}}]],
+                "timeout": 5
+            }
+        ]}));
+    }
+
+    #[test]
+    fn templates() {
+        let spec = apply_json(json!({
+            "templates": {
+                "python": {"argv": ["/usr/bin/python3"], "priority": 1},
+                "pytest": {"extends": "python", "args": ["-m", "pytest"]},
+                "quiet": {"fds": [["stdout", "null"]]}
+            },
+            "procs": [
+                {"extends": ["pytest", "quiet"], "args": ["test_a.py"]},
+                {"extends": "python", "argv": ["/usr/bin/python2"], "args": ["b.py"]}
+            ]
+        })).unwrap();
+        assert_eq!(spec, json!({"procs": [
+            {
+                "argv": ["/usr/bin/python3", "-m", "pytest", "test_a.py"],
+                "priority": 1,
+                "fds": [["stdout", "null"]]
+            },
+            {"argv": ["/usr/bin/python2", "b.py"], "priority": 1}
+        ]}));
+    }
+
+    #[test]
+    fn errors() {
+        let err = |spec| apply_json(spec).unwrap_err().to_string();
+        assert_eq!(
+            err(json!({"procs": [{"extends": "nope", "argv": ["/bin/true"]}]})),
+            "unknown template: nope");
+        assert_eq!(
+            err(json!({
+                "templates": {"a": {"extends": "b"}, "b": {"extends": "a"}},
+                "procs": [{"extends": "a", "argv": ["/bin/true"]}]
+            })),
+            "template cycle: a -> b -> a");
+        assert_eq!(err(json!({"procs": [{"args": ["x"]}]})), "args without argv");
+        assert_eq!(
+            err(json!({"defaults": {"name": "x"}, "procs": []})),
+            "defaults and templates can't give a name");
+    }
+
+    #[test]
+    fn combined() {
+        let a = json!({
+            "defaults": {"timeout": 10},
+            "templates": {"t": {"priority": 1}, "u": {"priority": 2}},
+            "vars": {"x": "a"},
+            "procs": [{"argv": ["/bin/true"]}]
+        });
+        let b = json!({
+            "defaults": {"env": {"inherit": false}},
+            "templates": {"u": {"priority": 3}},
+            "vars": {"y": "b"},
+            "max_parallel": 2,
+            "procs": {"extends": ["t", "u"], "argv": ["/bin/false"]}
+        });
+        let spec = apply_json(combine(vec![a, b]).unwrap()).unwrap();
+        assert_eq!(spec, json!({
+            "vars": {"x": "a", "y": "b"},
+            "max_parallel": 2,
+            "procs": [
+                {"argv": ["/bin/true"], "timeout": 10},
+                {"argv": ["/bin/false"], "env": {"inherit": false}, "priority": 3}
+            ]
+        }));
+    }
+
+    #[test]
+    fn uses() {
+        assert!(!uses_templates(&json!({"procs": [{"argv": ["/bin/true"]}]})));
+        assert!(uses_templates(&json!({"defaults": {}, "procs": []})));
+        assert!(uses_templates(&json!({"procs": {"extends": "a"}})));
+    }
+}
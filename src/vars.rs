@@ -0,0 +1,196 @@
+//! Variables in specs, and their substitution into strings.
+//!
+//! `${name}` in a string is replaced by the value of variable `name`, and
+//! `$${` by a literal `${`.  A `$` followed by anything else is left as is, so
+//! shell syntax such as `$HOME` and `$$` passes through.
+//!
+//! A name is one of,
+//! - a variable given in the spec's `vars`, or overriding it; its value may
+//!   itself refer to other variables
+//! - `env.NAME`, the environment variable `NAME`
+//! - `proc.index`, the proc's index in the spec
+//! - `proc.name`, the proc's name, or its index if it has none
+//! - `run.id`, an ID unique to the run
+//!
+//! In env var values, a name that is none of these is left for `environ` to
+//! substitute, from the proc's inherited environment.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+//------------------------------------------------------------------------------
+
+/// Returns `text` with variables substituted, using `lookup` to find each
+/// variable's value.
+pub fn substitute<F>(text: &str, mut lookup: F) -> Result<String, String>
+where F: FnMut(&str) -> Result<String, String>
+{
+    substitute_partial(text, |name| lookup(name).map(Some), false)
+}
+
+/// Substitutes variables into `text`.  If `lookup` returns None for a
+/// variable, it's left as is.  If `escaped`, so is `$${`, and `${` in values
+/// is escaped, so that the result may be substituted again without expanding
+/// values twice.
+fn substitute_partial<F>(text: &str, mut lookup: F, escaped: bool) -> Result<String, String>
+where F: FnMut(&str) -> Result<Option<String>, String>
+{
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[.. pos]);
+        rest = &rest[pos ..];
+        if rest.starts_with("$${") {
+            result.push_str(if escaped { "$${" } else { "${" });
+            rest = &rest[3 ..];
+        } else if let Some(body) = rest.strip_prefix("${") {
+            let end = body.find('}')
+                .ok_or_else(|| format!("unterminated variable in {:?}", text))?;
+            match lookup(&body[.. end])? {
+                Some(value) if escaped => result.push_str(&value.replace("${", "$${")),
+                Some(value) => result.push_str(&value),
+                None => result.push_str(&rest[.. end + 3]),
+            }
+            rest = &body[end + 1 ..];
+        } else {
+            result.push('$');
+            rest = &rest[1 ..];
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+//------------------------------------------------------------------------------
+
+/// Variables for substitution into a spec.
+pub struct Vars {
+    vars: BTreeMap<String, String>,
+    run_id: String,
+}
+
+impl Vars {
+    pub fn new(vars: BTreeMap<String, String>, run_id: &str) -> Self {
+        Self { vars, run_id: run_id.to_string() }
+    }
+
+    /// Substitutes variables into `text`, for the proc at `index` with `name`.
+    pub fn expand(&self, text: &str, index: usize, name: Option<&str>) -> Result<String, String> {
+        self.expand_in(text, index, name, &mut Vec::new())
+    }
+
+    /// Substitutes variables into an env var value.  Others, and `$${`, are
+    /// left for `environ` to substitute.
+    pub fn expand_env(&self, text: &str, index: usize, name: Option<&str>)
+        -> Result<String, String>
+    {
+        substitute_partial(text, |var| self.lookup(var, index, name, &mut Vec::new()), true)
+    }
+
+    /// `stack` holds the variables whose values are being expanded, to detect
+    /// cycles.
+    fn expand_in(
+        &self, text: &str, index: usize, name: Option<&str>, stack: &mut Vec<String>)
+        -> Result<String, String>
+    {
+        substitute(text, |var| {
+            self.lookup(var, index, name, stack)?
+                .ok_or_else(|| format!("undefined variable: {}", var))
+        })
+    }
+
+    /// Returns the value of a variable, or None if it's undefined.
+    fn lookup(&self, var: &str, index: usize, name: Option<&str>, stack: &mut Vec<String>)
+        -> Result<Option<String>, String>
+    {
+        match var {
+            "proc.index" => Ok(Some(index.to_string())),
+            "proc.name" => Ok(Some(name.map_or_else(|| index.to_string(), str::to_string))),
+            "run.id" => Ok(Some(self.run_id.clone())),
+            _ if var.starts_with("env.") => std::env::var(&var[4 ..]).map(Some)
+                .map_err(|_| format!("undefined environment variable: {}", &var[4 ..])),
+            _ => match self.vars.get(var) {
+                Some(value) => {
+                    if stack.iter().any(|v| v == var) {
+                        return Err(format!("variable refers to itself: {}", var));
+                    }
+                    stack.push(var.to_string());
+                    let value = self.expand_in(value, index, name, stack);
+                    stack.pop();
+                    value.map(Some)
+                },
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+/// Returns an ID for a run, unique on this host.  A process may start several
+/// runs, so the ID includes a count of them.
+pub fn get_run_id() -> String {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let secs = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    format!("{}-{}-{}", secs, crate::sys::getpid(), count)
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitute_vars() {
+        let lookup = |name: &str| match name {
+            "x" => Ok("42".to_string()),
+            _ => Err(format!("undefined variable: {}", name)),
+        };
+        assert_eq!(substitute("a${x}b${x}", lookup).unwrap(), "a42b42");
+        assert_eq!(substitute("$$ $HOME $${x} $", lookup).unwrap(), "$$ $HOME ${x} $");
+        assert_eq!(substitute("${y}", lookup).unwrap_err(), "undefined variable: y");
+        assert!(substitute("${x", lookup).is_err());
+    }
+
+    #[test]
+    fn expand() {
+        let vars = Vars::new(
+            btreemap! {
+                "dir".to_string() => "/tmp/${run.id}".to_string(),
+                "out".to_string() => "${dir}/${proc.name}.out".to_string(),
+                "a".to_string() => "${b}".to_string(),
+                "b".to_string() => "${a}".to_string(),
+            },
+            "123-45");
+        assert_eq!(vars.expand("${out}", 0, Some("test")).unwrap(), "/tmp/123-45/test.out");
+        assert_eq!(vars.expand("${out}", 3, None).unwrap(), "/tmp/123-45/3.out");
+        assert_eq!(vars.expand("${proc.index}", 3, None).unwrap(), "3");
+        assert_eq!(
+            vars.expand("${env.PATH}", 0, None).unwrap(), std::env::var("PATH").unwrap());
+        assert!(vars.expand("${env.IR_NO_SUCH_VAR}", 0, None).is_err());
+        assert!(vars.expand("${a}", 0, None).is_err());
+        assert!(vars.expand("${PATH}", 0, None).is_err());
+        assert_eq!(
+            vars.expand_env("${dir}/bin:${PATH}:$${x}", 0, None).unwrap(),
+            "/tmp/123-45/bin:${PATH}:$${x}");
+    }
+
+    #[test]
+    fn expand_env_once() {
+        let vars = Vars::new(
+            btreemap! {
+                "literal".to_string() => "$${x}".to_string(),
+                "nested".to_string() => "${literal}/y".to_string(),
+            },
+            "123-45");
+        // A value's `$${` becomes a `${`, which must stay escaped for `environ`.
+        assert_eq!(vars.expand_env("${literal}", 0, None).unwrap(), "$${x}");
+        assert_eq!(vars.expand_env("${nested}:$${z}", 0, None).unwrap(), "$${x}/y:$${z}");
+    }
+
+    #[test]
+    fn run_ids_unique() {
+        assert_ne!(get_run_id(), get_run_id());
+    }
+}
@@ -29,3 +29,86 @@ fn echo_hello() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+
+/// Runs ir on a spec with at most `max_fds` open fds, and returns its result.
+fn run_with_fd_limit(name: &str, spec: &serde_json::Value, max_fds: u32)
+    -> Result<serde_json::Value, Box<dyn std::error::Error>>
+{
+    let path = std::env::temp_dir().join(
+        format!("ir-test-{}-{}.json", std::process::id(), name));
+    std::fs::write(&path, spec.to_string())?;
+    let ir = Command::cargo_bin("ir")?;
+    let output = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("ulimit -n {} && exec \"$0\" \"$1\"", max_fds))
+        .arg(ir.get_program())
+        .arg(&path)
+        .output()?;
+    std::fs::remove_file(&path)?;
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+fn memory_capture_procs(num: usize) -> Vec<serde_json::Value> {
+    let proc = serde_json::json!({
+        "argv": ["/bin/echo", "hello"],
+        "fds": [
+            ["stdout", {"capture": {"mode": "memory"}}],
+            ["stderr", {"capture": {"mode": "memory"}}],
+        ],
+    });
+    vec![proc; num]
+}
+
+#[test]
+fn memory_capture_fds_closed() -> Result<(), Box<dyn std::error::Error>> {
+    // More procs than fds, so capture fds must be closed as procs finish.
+    let spec = serde_json::json!({
+        "procs": memory_capture_procs(200),
+        "max_parallel": 4,
+    });
+    let res = run_with_fd_limit("memory_capture_fds_closed", &spec, 64)?;
+    let procs = res["procs"].as_array().unwrap();
+    assert_eq!(procs.len(), 200);
+    for proc in procs {
+        assert_eq!(proc["state"], "terminated");
+        assert_eq!(proc["fds"]["stdout"]["text"], "hello\n");
+    }
+    Ok(())
+}
+
+#[test]
+fn fd_setup_errno() -> Result<(), Box<dyn std::error::Error>> {
+    // Too many procs at once for the fd limit.
+    let spec = serde_json::json!({"procs": memory_capture_procs(40)});
+    let res = run_with_fd_limit("fd_setup_errno", &spec, 32)?;
+    let failed = res["procs"].as_array().unwrap().iter()
+        .filter(|p| p["state"] == "setup_failed")
+        .collect::<Vec<_>>();
+    assert!(!failed.is_empty());
+    for proc in failed {
+        assert_eq!(proc["errors"][0]["errno"], libc::EMFILE);
+        assert_eq!(proc["errors"][0]["errno_name"], "EMFILE");
+    }
+    Ok(())
+}
+
+#[test]
+fn capture_fds_not_inherited() -> Result<(), Box<dyn std::error::Error>> {
+    // The second proc starts while the first's capture fds are open.
+    let fds = serde_json::json!([
+        ["stdout", {"capture": {"mode": "memory"}}],
+        ["stderr", {"capture": {}}],
+    ]);
+    let spec = serde_json::json!({"procs": [
+        {"name": "sleep", "argv": ["/bin/sleep", "0.2"], "fds": fds},
+        {
+            "argv": ["/bin/ls", "/proc/self/fd"],
+            "after": [{"proc": "sleep", "condition": "started"}],
+            "fds": fds,
+        },
+    ]});
+    let res = run_with_fd_limit("capture_fds_not_inherited", &spec, 1024)?;
+    // ls itself opens the directory.
+    assert_eq!(res["procs"][1]["fds"]["stdout"]["text"], "0\n1\n2\n3\n");
+    Ok(())
+}
@@ -1,3 +1,4 @@
+import contextlib
 import json
 import os
 from   pathlib import Path
@@ -12,35 +13,67 @@ TEST_EXE = Path(__file__).parent / "test.py"
 
 
 class Errors(Exception):
+    """
+    Errors running procs.  Each error is a dict with keys "proc", "name",
+    "stage", "fd", "errno", "errno_name", and "message".
+    """
 
     def __init__(self, errors):
-        super().__init__("\n".join(errors))
+        super().__init__("\n".join( e["message"] for e in errors ))
         self.errors = tuple(errors)
 
 
 
-def run(specs):
-    specs = list(specs)
-    with tempfile.NamedTemporaryFile(mode="w+") as tmp_file:
-        json.dump({"procs": specs}, tmp_file)
+@contextlib.contextmanager
+def spec_file(specs, **spec_kw_args):
+    """
+    Writes a spec for `specs` to a temporary file, and returns its path.  Other
+    top-level spec keys are given as keyword args.
+    """
+    with tempfile.NamedTemporaryFile(mode="w+", suffix=".json") as tmp_file:
+        json.dump({"procs": list(specs), **spec_kw_args}, tmp_file)
         tmp_file.flush()
+        yield tmp_file.name
+
+
+def run(specs, *, args=(), check=True, **spec_kw_args):
+    """
+    Runs procs, and returns their results.  `args` are additional command line
+    args.  If `check`, raises `Errors` if there were any errors.
+    """
+    with spec_file(specs, **spec_kw_args) as path:
         res = subprocess.run(
-            [str(IR_EXE), tmp_file.name],
+            [str(IR_EXE), *args, path],
             stdout=subprocess.PIPE,
             env={**os.environ, "RUST_BACKTRACE": "1"},
         )
     res = json.loads(res.stdout)
     # json.dump(res, sys.stderr, indent=2)
 
-    if len(res["errors"]) != 0:
-        raise Errors(res["errors"])
+    errors = res["errors"] + [ e for p in res["procs"] for e in p["errors"] ]
+    if check and len(errors) != 0:
+        raise Errors(errors)
 
     return res["procs"]
 
 
-def run1(spec):
+def stream(specs):
+    """
+    Runs procs with NDJSON output, and yields each event as it's printed.
+    """
+    with spec_file(specs) as path:
+        with subprocess.Popen(
+            [str(IR_EXE), "--format", "ndjson", path],
+            stdout=subprocess.PIPE,
+            env={**os.environ, "RUST_BACKTRACE": "1"},
+        ) as proc:
+            for line in proc.stdout:
+                yield json.loads(line)
+
+
+def run1(spec, **kw_args):
     # Return results for the single process only.
-    proc, = run([spec])
+    proc, = run([spec], **kw_args)
     return proc
 
 
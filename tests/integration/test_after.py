@@ -0,0 +1,66 @@
+import ir
+import json
+import subprocess
+
+#-------------------------------------------------------------------------------
+
+def sh(name, script, **kw_args):
+    return {
+        "name": name,
+        "argv": ["/bin/sh", "-c", script],
+        **kw_args,
+    }
+
+
+def test_sequence(tmp_path):
+    log = tmp_path / "log"
+    setup, test, teardown = ir.run([
+        sh("teardown", f"echo teardown >> {log}",
+           after=[{"proc": "test", "condition": "completion"}]),
+        sh("test", f"sleep 0.2; echo test >> {log}", after=["setup"]),
+        sh("setup", f"sleep 0.2; echo setup >> {log}"),
+    ])[:: -1]
+    assert log.read_text().split() == ["setup", "test", "teardown"]
+    for res in (setup, test, teardown):
+        assert res["state"] == "terminated"
+        assert res["exit_code"] == 0
+
+
+def test_skip():
+    setup, test, teardown, cleanup = ir.run([
+        sh("setup", "exit 1"),
+        sh("test", "true", after=["setup"]),
+        sh("teardown", "true", after=["test"]),
+        sh("cleanup", "true", after=[{"proc": "test", "condition": "completion"}]),
+    ])
+    assert setup["exit_code"] == 1
+    # Dependents of the failed proc, and theirs, are skipped.
+    for res in (test, teardown):
+        assert res["state"] == "skipped"
+        assert res["pid"] is None
+        assert res["exit_code"] is None
+    # Skipping counts as completion.
+    assert cleanup["state"] == "terminated"
+
+
+def test_started():
+    server, client = ir.run([
+        sh("server", "sleep 0.5"),
+        sh("client", "true", after=[{"proc": "server", "condition": "started"}]),
+    ])
+    assert server["state"] == client["state"] == "terminated"
+
+
+def test_invalid(tmp_path):
+    spec_path = tmp_path / "spec.json"
+    spec_path.write_text(json.dumps({"procs": [
+        sh("a", "true", after=["b"]),
+        sh("b", "true", after=["a"]),
+    ]}))
+    res = subprocess.run(
+        [str(ir.IR_EXE), str(spec_path)],
+        stdout=subprocess.PIPE,
+        stderr=subprocess.PIPE,
+    )
+    assert res.returncode != 0
+    assert b"dependency cycle: a -> b -> a" in res.stderr
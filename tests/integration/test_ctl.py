@@ -0,0 +1,95 @@
+import ir
+import json
+import socket
+import subprocess
+import time
+
+#-------------------------------------------------------------------------------
+
+class Client:
+
+    def __init__(self, path, timeout=5):
+        deadline = time.monotonic() + timeout
+        self.sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        while True:
+            try:
+                self.sock.connect(str(path))
+                break
+            except (FileNotFoundError, ConnectionRefusedError):
+                assert time.monotonic() < deadline, "timed out connecting"
+                time.sleep(0.05)
+        self.file = self.sock.makefile("rw")
+
+
+    def __call__(self, **request):
+        self.file.write(json.dumps(request) + "\n")
+        self.file.flush()
+        return json.loads(self.file.readline())
+
+
+
+def start(specs, tmp_path):
+    spec_path = tmp_path / "spec.json"
+    spec_path.write_text(json.dumps({"procs": specs}))
+    return subprocess.Popen(
+        [str(ir.IR_EXE), "--ctl-socket", str(tmp_path / "ctl.sock"), spec_path],
+        stdout=subprocess.PIPE,
+        stderr=subprocess.DEVNULL,
+    )
+
+
+def test_ctl(tmp_path):
+    sock_path = tmp_path / "ctl.sock"
+    proc = start([
+        {
+            "name": "talker",
+            "argv": ["/bin/sh", "-c", "echo hello; exec sleep 10"],
+            "fds": [["stdout", {"capture": {}}]],
+        },
+        {
+            "name": "sleeper",
+            "argv": ["/bin/sleep", "10"],
+        },
+    ], tmp_path)
+    client = Client(sock_path)
+
+    res = client(cmd="list")
+    assert res["ok"]
+    talker, sleeper = res["state"]["procs"]
+    assert talker["name"] == "talker"
+    assert talker["phase"] == "running"
+
+    # Partial output, while the talker is still running.
+    deadline = time.monotonic() + 5
+    while True:
+        res = client(cmd="output", proc="talker")
+        assert res["ok"]
+        if res["output"]["text"] == "hello\n":
+            break
+        assert time.monotonic() < deadline
+        time.sleep(0.05)
+
+    res = client(cmd="output", proc="sleeper")
+    assert not res["ok"]
+    assert "not captured" in res["error"]
+
+    res = client(cmd="signal", proc="nonexistent")
+    assert not res["ok"]
+    assert "no proc" in res["error"]
+
+    res = client(cmd="frobnicate")
+    assert not res["ok"]
+
+    res = client(cmd="signal", proc=1, signal="SIGUSR1")
+    assert res["ok"]
+
+    res = client(cmd="cancel", signal="KILL")
+    assert res["ok"]
+
+    out, _ = proc.communicate(timeout=5)
+    talker, sleeper = json.loads(out)["procs"]
+    assert talker["signum"] == 9
+    assert talker["fds"]["stdout"]["text"] == "hello\n"
+    assert sleeper["signum"] == 10
+    # The socket is removed when the run is done.
+    assert not sock_path.exists()
@@ -0,0 +1,22 @@
+import ir
+
+
+def test_cwd(tmp_path):
+    res = ir.run1({
+        "argv": ["/bin/pwd"],
+        "cwd": str(tmp_path),
+        "fds": [["stdout", {"capture": {}}]],
+    })
+    assert res["exit_code"] == 0
+    assert res["fds"]["stdout"]["text"] == str(tmp_path) + "\n"
+
+
+def test_bad_cwd(tmp_path):
+    res = ir.run1({
+        "argv": ["/bin/pwd"],
+        "cwd": str(tmp_path / "nonexistent"),
+    }, check=False)
+    assert res["state"] == "setup_failed"
+    err, = res["errors"]
+    assert err["stage"] == "cwd"
+    assert err["errno_name"] == "ENOENT"
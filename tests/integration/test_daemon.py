@@ -0,0 +1,77 @@
+import ir
+import json
+import os
+from   pathlib import Path
+import subprocess
+import time
+
+#-------------------------------------------------------------------------------
+
+def wait_for_file(path, timeout=5):
+    deadline = time.monotonic() + timeout
+    while not path.exists():
+        assert time.monotonic() < deadline, f"timed out waiting for {path}"
+        time.sleep(0.05)
+    return json.loads(path.read_text())
+
+
+def test_output(tmp_path):
+    out_path = tmp_path / "res.json"
+    with ir.spec_file([{"argv": ["/bin/true"]}]) as spec_path:
+        res = subprocess.run(
+            [str(ir.IR_EXE), "--output", str(out_path), spec_path],
+            stdout=subprocess.PIPE,
+            stderr=subprocess.DEVNULL,
+        )
+    assert res.returncode == 0
+    assert res.stdout == b""
+    proc, = json.loads(out_path.read_text())["procs"]
+    assert proc["exit_code"] == 0
+
+
+def test_daemon(tmp_path):
+    out_path = tmp_path / "res.json"
+    spec_path = tmp_path / "spec.json"
+    spec_path.write_text(json.dumps({"procs": [
+        {
+            "argv": ["/bin/sh", "-c", "sleep 0.5; echo $PPID"],
+            "fds": [["stdout", {"capture": {}}]],
+        },
+    ]}))
+
+    start = time.monotonic()
+    res = subprocess.run(
+        [str(ir.IR_EXE), "--daemon", "--output", str(out_path), str(spec_path)],
+        stdout=subprocess.PIPE,
+        stderr=subprocess.DEVNULL,
+    )
+    # Returns right away, without waiting for procs.
+    assert time.monotonic() - start < 0.5
+    assert res.returncode == 0
+    info = json.loads(res.stdout)
+    assert info["result"] == str(out_path)
+    assert info["pid"] > 0
+    assert not out_path.exists()
+
+    proc, = wait_for_file(out_path)["procs"]
+    assert proc["exit_code"] == 0
+    # The proc is a child of the daemon.
+    assert int(proc["fds"]["stdout"]["text"]) == info["pid"]
+
+
+def test_daemon_default_output(tmp_path):
+    with ir.spec_file([{"argv": ["/bin/true"]}]) as spec_path:
+        res = subprocess.run(
+            [str(ir.IR_EXE), "--daemon", spec_path],
+            stdout=subprocess.PIPE,
+            stderr=subprocess.DEVNULL,
+        )
+        assert res.returncode == 0
+        info = json.loads(res.stdout)
+        path = Path(info["result"])
+        try:
+            proc, = wait_for_file(path)["procs"]
+            assert proc["exit_code"] == 0
+        finally:
+            if path.exists():
+                os.unlink(path)
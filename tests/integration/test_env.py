@@ -0,0 +1,83 @@
+import contextlib
+import ir
+import os
+
+#-------------------------------------------------------------------------------
+
+def run_env(env, **spec_kw_args):
+    """
+    Runs `env` with `env` spec, and returns the resulting environment.
+    """
+    res, = ir.run([{
+        "argv": ["/usr/bin/env"],
+        "env": env,
+        "fds": [["stdout", {"capture": {}}]],
+    }], **spec_kw_args)
+    assert res["exit_code"] == 0
+    lines = res["fds"]["stdout"]["text"].splitlines()
+    return dict( l.split("=", 1) for l in lines )
+
+
+@contextlib.contextmanager
+def set_env(**vars):
+    """
+    Sets env vars in this process, and so for ir, temporarily.
+    """
+    old = { n: os.environ.get(n) for n in vars }
+    os.environ.update(vars)
+    try:
+        yield
+    finally:
+        for name, value in old.items():
+            if value is None:
+                del os.environ[name]
+            else:
+                os.environ[name] = value
+
+
+def test_unset():
+    env = run_env({"vars": {"PATH": None, "IR_TEST": "1"}})
+    assert "PATH" not in env
+    assert env["IR_TEST"] == "1"
+
+
+def test_exclude():
+    with set_env(AWS_SECRET_ACCESS_KEY="xyzzy", AWS_REGION="nowhere", IR_KEEP="1"):
+        env = run_env({"exclude": ["AWS_*"]})
+    assert not any( n.startswith("AWS_") for n in env )
+    assert env["IR_KEEP"] == "1"
+    assert env["PATH"] == os.environ["PATH"]
+
+
+def test_inherit_patterns():
+    with set_env(LC_ALL="C", LC_TIME="C", CI_JOB_ID="42", CI_JOB_TOKEN="xyzzy"):
+        env = run_env({
+            "inherit": ["LC_*", "/^CI_/", "PATH"],
+            "exclude": ["*TOKEN*"],
+        })
+    assert { n: v for n, v in env.items() if not n.startswith("LC_") } == {
+        "CI_JOB_ID": "42",
+        "PATH": os.environ["PATH"],
+    }
+    assert env["LC_ALL"] == env["LC_TIME"] == "C"
+
+
+def test_refer():
+    with set_env(IR_A="a", IR_LIST="/x:/y"):
+        env = run_env({
+            "inherit": ["IR_*", "PATH"],
+            "exclude": ["IR_LIST"],
+            "vars": {
+                "IR_A": "${IR_A}${IR_A}",
+                "IR_B": "[${IR_A}] [${IR_LIST}] $${IR_A}",
+                "IR_C": "${greeting}",
+            },
+            "prepend": {"PATH": ["/opt/tools/bin"]},
+            "append": {"IR_LIST": ["/y", "/z"], "PATH": ["/opt/tools/bin"]},
+        }, vars={"greeting": "hello"})
+    assert env["IR_A"] == "aa"
+    assert env["IR_B"] == "[a] [] ${IR_A}"
+    assert env["IR_C"] == "hello"
+    assert env["IR_LIST"] == "/y:/z"
+    path = ["/opt/tools/bin", *os.environ["PATH"].split(":")]
+    assert env["PATH"].split(":") == [ p for p in dict.fromkeys(path) if p ]
@@ -10,7 +10,12 @@ def test_bad_exe():
     """
     with pytest.raises(ir.Errors) as exc_info:
         ir.run1({"argv": ["/usr/bin/bogus"],})
-    assert any( "No such file or directory" in e for e in exc_info.value.errors )
+    err, = exc_info.value.errors
+    assert err["proc"] == 0
+    assert err["stage"] == "exec"
+    assert err["fd"] is None
+    assert err["errno_name"] == "ENOENT"
+    assert "No such file or directory" in err["message"]
 
 
 def test_bad_capture_path():
@@ -25,7 +30,60 @@ def test_bad_capture_path():
                 ["stderr", {"file": {"path": "/not/a/valid/path/either",}}],
             ]
         })
-    assert any( "failed to set up fd 1" in e for e in exc_info.value.errors )
-    assert any( "failed to set up fd 2" in e for e in exc_info.value.errors )
+    errors = exc_info.value.errors
+    assert all( e["stage"] == "fd_setup" for e in errors )
+    assert all( e["errno_name"] == "ENOENT" for e in errors )
+    assert sorted( e["fd"] for e in errors ) == [1, 2]
+    assert any( "failed to set up fd 1" in e["message"] for e in errors )
+    assert any( "failed to set up fd 2" in e["message"] for e in errors )
 
 
+def test_error_name():
+    """
+    Tests that errors identify the proc by name.
+    """
+    with pytest.raises(ir.Errors) as exc_info:
+        ir.run([
+            {"argv": ["/bin/true"]},
+            {"name": "bogus", "argv": ["/usr/bin/bogus"]},
+        ])
+    err, = exc_info.value.errors
+    assert err["proc"] == 1
+    assert err["name"] == "bogus"
+
+
+def test_exec_failed_state():
+    """
+    Tests that an exec failure is distinguishable from a program that exits
+    with the same code.
+    """
+    res = ir.run1({"argv": ["/usr/bin/bogus"]}, check=False)
+    assert res["state"] == "exec_failed"
+    assert res["exit_code"] is None
+    err, = res["errors"]
+    assert err["stage"] == "exec"
+
+    res = ir.run1({"argv": ["/bin/sh", "-c", "exit 71"]})
+    assert res["state"] == "terminated"
+    assert res["exit_code"] == 71
+    assert res["errors"] == []
+
+
+def test_setup_failed_state():
+    """
+    Tests that errors are attached to the proc that failed.
+    """
+    procs = ir.run([
+        {"argv": ["/bin/true"]},
+        {
+            "argv": ["/bin/true"],
+            "fds": [["stdout", {"file": {"path": "/not/a/valid/path"}}]],
+        },
+    ], check=False)
+    assert procs[0]["state"] == "terminated"
+    assert procs[0]["errors"] == []
+    assert procs[1]["state"] == "setup_failed"
+    assert procs[1]["exit_code"] is None
+    err, = procs[1]["errors"]
+    assert err["stage"] == "fd_setup"
+    assert err["fd"] == 1
@@ -0,0 +1,65 @@
+import ir
+import subprocess
+
+#-------------------------------------------------------------------------------
+
+def sh(script, **kw_args):
+    return {
+        "argv": ["/bin/sh", "-c", script],
+        "fds": [
+            ["stdout", {"capture": {}}],
+            ["stderr", {"capture": {}}],
+        ],
+        **kw_args
+    }
+
+
+def test_pass():
+    res = ir.run1(sh(
+        "echo hello, world; echo oops >&2; exit 3",
+        expect={
+            "exit_code": 3,
+            "stdout": {"equals": "hello, world\n", "matches": "^hel+o"},
+            "stderr": {"contains": "oops"},
+            "max_elapsed": 10,
+        },
+    ))
+    assert res["passed"]
+    assert "failures" not in res
+
+
+def test_fail():
+    res = ir.run1(sh(
+        "echo goodbye; sleep 0.2",
+        expect={"exit_code": 1, "stdout": {"contains": "hello"}, "max_elapsed": 0.1},
+    ))
+    assert not res["passed"]
+    failures = res["failures"]
+    assert len(failures) == 3
+    assert failures[0] == "exited with code 0, expected code 1"
+    assert failures[1] == 'stdout doesn\'t contain "hello"'
+    assert failures[2].startswith("elapsed ")
+
+
+def test_signal():
+    res = ir.run1(sh("kill -USR1 $$", expect={"signal": "SIGUSR1"}))
+    assert res["passed"]
+
+
+def test_default():
+    # Without a status, the proc should exit with code 0.
+    res0, res1, res2 = ir.run([
+        sh("true", expect={}),
+        sh("false", expect={}),
+        sh("false"),
+    ])
+    assert res0["passed"]
+    assert not res1["passed"]
+    assert "passed" not in res2
+
+
+def test_exit_status():
+    for script, returncode in (("true", 0), ("false", 1)):
+        with ir.spec_file([sh(script, expect={})]) as path:
+            res = subprocess.run([str(ir.IR_EXE), path], stdout=subprocess.DEVNULL)
+        assert res.returncode == returncode
@@ -0,0 +1,74 @@
+import ir
+import json
+import subprocess
+
+#-------------------------------------------------------------------------------
+
+def write_spec(path, spec):
+    path.write_text(json.dumps(spec))
+    return path
+
+
+def run_ir(*args):
+    res = subprocess.run(
+        [str(ir.IR_EXE), *args], stdout=subprocess.PIPE, stderr=subprocess.PIPE,
+        text=True)
+    return res
+
+
+def stdouts(res):
+    return [ p["fds"]["stdout"]["text"] for p in json.loads(res.stdout)["procs"] ]
+
+
+def test_include(tmp_path):
+    (tmp_path / "sub").mkdir()
+    write_spec(tmp_path / "sub/common.json", {
+        "defaults": {"env": {"vars": {"WHO": "common"}}},
+        "templates": {
+            "echo": {
+                "argv": ["/bin/sh", "-c", "echo $WHO $0"],
+                "fds": [["stdout", {"capture": {}}]],
+            },
+        },
+        "procs": [{"extends": "echo", "args": ["first"]}],
+    })
+    write_spec(tmp_path / "sub/more.json", {
+        "include": "common.json",
+        "procs": [{"extends": "echo", "args": ["second"]}],
+    })
+    path = write_spec(tmp_path / "main.json", {
+        "include": ["sub/more.json"],
+        "defaults": {"env": {"vars": {"WHO": "main"}}},
+        "procs": [{"extends": "echo", "args": ["third"]}],
+    })
+
+    res = run_ir(path)
+    assert res.returncode == 0
+    assert stdouts(res) == ["common first\n", "second\n", "main third\n"]
+
+
+def test_include_cycle(tmp_path):
+    write_spec(tmp_path / "a.json", {"include": "b.json", "procs": []})
+    path = write_spec(tmp_path / "b.json", {"include": "a.json", "procs": []})
+    res = run_ir(path)
+    assert res.returncode != 0
+    assert "include cycle" in res.stderr
+
+
+def test_several_specs(tmp_path):
+    echo = {"argv": ["/bin/echo"], "fds": [["stdout", {"capture": {}}]]}
+    a = write_spec(tmp_path / "a.json", {
+        "templates": {"echo": echo},
+        "procs": [{"name": "a", "extends": "echo", "args": ["a"]}],
+    })
+    b = tmp_path / "b.yaml"
+    b.write_text("procs:\n  - {name: b, extends: echo, args: [b], after: [a]}\n")
+
+    res = run_ir(a, b)
+    assert res.returncode == 0
+    assert stdouts(res) == ["a\n", "b\n"]
+
+    # Proc names must be unique across specs.
+    res = run_ir(a, a)
+    assert res.returncode != 0
+    assert "duplicate proc name: a" in res.stderr
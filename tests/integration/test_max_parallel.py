@@ -0,0 +1,45 @@
+import ir
+
+#-------------------------------------------------------------------------------
+
+def logged(log, name, duration=0.2):
+    """
+    Returns a proc spec that logs when it starts and ends.
+    """
+    return {
+        "name": name,
+        "argv": [
+            "/bin/sh", "-c",
+            f"echo start {name} >> {log}; sleep {duration}; echo end {name} >> {log}"
+        ],
+    }
+
+
+def test_max_parallel(tmp_path):
+    log = tmp_path / "log"
+    procs = ir.run(
+        ( logged(log, str(i)) for i in range(6) ),
+        max_parallel=2,
+    )
+    assert all( p["exit_code"] == 0 for p in procs )
+
+    running = max_running = 0
+    for line in log.read_text().splitlines():
+        running += 1 if line.startswith("start") else -1
+        max_running = max(running, max_running)
+    assert max_running == 2
+
+
+def test_priority(tmp_path):
+    log = tmp_path / "log"
+    ir.run(
+        [
+            logged(log, "low", 0),
+            {**logged(log, "high", 0), "priority": 10},
+            {**logged(log, "lowest", 0), "priority": -1},
+            logged(log, "low2", 0),
+        ],
+        max_parallel=1,
+    )
+    starts = [ l.split()[1] for l in log.read_text().splitlines() if l.startswith("start") ]
+    assert starts == ["high", "low", "low2", "lowest"]
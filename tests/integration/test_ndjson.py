@@ -0,0 +1,53 @@
+import ir
+import time
+
+#-------------------------------------------------------------------------------
+
+def test_events():
+    events = list(ir.stream([
+        {"name": "ok", "argv": ["/bin/echo", "hello"],
+         "fds": [["stdout", {"capture": {}}]]},
+        {"name": "bad", "argv": ["/nonexistent"]},
+    ]))
+
+    *events, summary = events
+    assert summary["event"] == "summary"
+    assert summary["num_procs"] == 2
+    assert summary["num_errors"] == 1
+
+    def proc_of(event):
+        return event["error"]["proc"] if event["event"] == "error" else event["proc"]
+
+    # Each proc's events start with started and end with finished.
+    for index in range(2):
+        kinds = [ e["event"] for e in events if proc_of(e) == index ]
+        assert kinds[0] == "started"
+        assert kinds[-1] == "finished"
+
+    ok, bad = ( e for e in events if e["event"] == "finished" )
+    if ok["proc"] == 1:
+        ok, bad = bad, ok
+    assert ok["name"] == "ok"
+    assert ok["result"]["exit_code"] == 0
+    assert ok["result"]["fds"]["stdout"]["text"] == "hello\n"
+    assert bad["name"] == "bad"
+    assert bad["result"]["state"] == "exec_failed"
+
+    error, = ( e for e in events if e["event"] == "error" )
+    assert error["error"]["name"] == "bad"
+    assert error["error"]["stage"] == "exec"
+
+
+def test_streaming():
+    """
+    Results for a proc are printed when it finishes, not when all do.
+    """
+    start = time.monotonic()
+    for event in ir.stream([
+        {"argv": ["/bin/true"]},
+        {"argv": ["/bin/sleep", "1"]},
+    ]):
+        if event["event"] == "finished" and event["proc"] == 0:
+            assert time.monotonic() - start < 0.5
+        if event["event"] == "finished" and event["proc"] == 1:
+            assert time.monotonic() - start >= 1
@@ -0,0 +1,60 @@
+import ir
+
+#-------------------------------------------------------------------------------
+
+STAT_KEYS = {"min", "max", "mean", "median", "stddev"}
+
+def counted(counter):
+    """
+    Returns argv for a proc that appends a line to `counter`, then sleeps a bit.
+    """
+    return ["/bin/sh", "-c", f"echo x >> {counter}; sleep 0.02"]
+
+
+def test_repeat(tmp_path):
+    counter = tmp_path / "counter"
+    res, = ir.run([{"argv": counted(counter), "repeat": 4}])
+    assert len(counter.read_text().splitlines()) == 4
+    assert res["exit_code"] == 0
+    assert len(res["runs"]) == 3
+    assert all( r["exit_code"] == 0 for r in res["runs"] )
+
+    stats = res["stats"]
+    assert stats["count"] == 4
+    for key in ("elapsed", "utime", "stime", "maxrss"):
+        assert set(stats[key]) == STAT_KEYS
+    elapsed = stats["elapsed"]
+    assert 0.02 <= elapsed["min"] <= elapsed["median"] <= elapsed["max"]
+    assert elapsed["min"] <= elapsed["mean"] <= elapsed["max"]
+
+
+def test_warmup(tmp_path):
+    counter = tmp_path / "counter"
+    res, = ir.run([{"argv": counted(counter), "repeat": {"count": 2, "warmup": 3}}])
+    assert len(counter.read_text().splitlines()) == 5
+    assert len(res["runs"]) == 1
+    assert res["stats"]["count"] == 2
+
+
+def test_repeat_arg(tmp_path):
+    counter0 = tmp_path / "counter0"
+    counter1 = tmp_path / "counter1"
+    res0, res1 = ir.run(
+        [
+            {"argv": counted(counter0)},
+            {"argv": counted(counter1), "repeat": 1},
+        ],
+        args=["--repeat", "3", "--warmup", "1"],
+    )
+    assert len(counter0.read_text().splitlines()) == 4
+    assert res0["stats"]["count"] == 3
+    # A repeat in the spec overrides the arg.
+    assert len(counter1.read_text().splitlines()) == 1
+    assert "runs" not in res1
+    assert res1["stats"]["count"] == 1
+
+
+def test_elapsed():
+    res = ir.run1({"argv": ["/bin/sleep", "0.1"]})
+    assert 0.1 <= res["elapsed"] < 1
+    assert "stats" not in res
@@ -0,0 +1,71 @@
+import ir
+import subprocess
+import xml.etree.ElementTree as ET
+
+#-------------------------------------------------------------------------------
+
+SPECS = [
+    {
+        "name": "pass",
+        "argv": ["/bin/sh", "-c", "echo hello; echo '<oops>' >&2"],
+        "fds": [
+            ["stdout", {"capture": {}}],
+            ["stderr", {"capture": {}}],
+        ],
+        "expect": {"stdout": {"equals": "hello\n"}},
+    },
+    {
+        "name": "fail",
+        "argv": ["/bin/false"],
+    },
+    {
+        "name": "skip",
+        "argv": ["/bin/true"],
+        "after": ["fail"],
+    },
+    {
+        "argv": ["/nonexistent"],
+    },
+]
+
+
+def run_report(tmp_path, format):
+    path = tmp_path / f"report.{format}"
+    with ir.spec_file(SPECS) as spec_path:
+        res = subprocess.run(
+            [str(ir.IR_EXE), "--format", format, f"--{format}", str(path), spec_path],
+            stdout=subprocess.PIPE,
+            text=True,
+        )
+    assert res.returncode == 1
+    # The report file and stdout have the same contents.
+    assert path.read_text() == res.stdout
+    return res.stdout
+
+
+def test_junit(tmp_path):
+    suite = ET.fromstring(run_report(tmp_path, "junit"))
+    assert suite.tag == "testsuite"
+    assert suite.attrib["tests"] == "4"
+    assert suite.attrib["failures"] == "1"
+    assert suite.attrib["errors"] == "1"
+    assert suite.attrib["skipped"] == "1"
+
+    passed, failed, skipped, error = suite.findall("testcase")
+    assert passed.attrib["name"] == "pass"
+    assert passed.find("failure") is None
+    assert passed.find("system-out").text == "hello\n"
+    assert passed.find("system-err").text == "<oops>\n"
+    assert failed.find("failure").attrib["message"] == "exited with code 1"
+    assert skipped.find("skipped") is not None
+    assert error.attrib["name"] == "proc 3"
+    assert "No such file" in error.find("error").attrib["message"]
+
+
+def test_tap(tmp_path):
+    lines = run_report(tmp_path, "tap").splitlines()
+    assert lines[: 2] == ["TAP version 13", "1..4"]
+    assert "ok 1 - pass" in lines
+    assert "not ok 2 - fail" in lines
+    assert "ok 3 - skip # SKIP dependency fail didn't succeed" in lines
+    assert "not ok 4 - proc 3" in lines
@@ -0,0 +1,83 @@
+import ir
+import time
+
+#-------------------------------------------------------------------------------
+
+def flaky(counter, failures, exit_code=1):
+    """
+    Returns argv for a proc that fails `failures` times, then succeeds.
+    """
+    return [
+        "/bin/sh", "-c",
+        f"echo x >> {counter}; "
+        f"[ $(wc -l < {counter}) -gt {failures} ] || exit {exit_code}"
+    ]
+
+
+def test_retry(tmp_path):
+    counter = tmp_path / "counter"
+    res, = ir.run([{
+        "argv": flaky(counter, 2),
+        "retry": {"attempts": 5},
+    }])
+    assert res["exit_code"] == 0
+    assert [ a["exit_code"] for a in res["attempts"] ] == [1, 1]
+
+
+def test_retry_exhausted(tmp_path):
+    counter = tmp_path / "counter"
+    res, = ir.run([{
+        "argv": flaky(counter, 5),
+        "retry": {"attempts": 3},
+    }])
+    assert res["exit_code"] == 1
+    assert len(res["attempts"]) == 2
+    assert len(counter.read_text().splitlines()) == 3
+
+
+def test_retry_exit_codes(tmp_path):
+    counter = tmp_path / "counter"
+    res, = ir.run([{
+        "argv": flaky(counter, 2, exit_code=3),
+        "retry": {"attempts": 5, "exit_codes": [1, 2]},
+    }])
+    # Exit code 3 isn't retried.
+    assert res["exit_code"] == 3
+    assert "attempts" not in res
+
+
+def test_timeout():
+    res, = ir.run([{"argv": ["/bin/sleep", "5"], "timeout": 0.1}])
+    assert res["timed_out"]
+    assert res["signum"] == 15
+
+
+def test_retry_timeout(tmp_path):
+    counter = tmp_path / "counter"
+    res, = ir.run([{
+        # Hangs the first time.
+        "argv": [
+            "/bin/sh", "-c",
+            f"echo x >> {counter}; "
+            f"[ $(wc -l < {counter}) -gt 1 ] || exec sleep 5"
+        ],
+        "timeout": 0.1,
+        "retry": {"attempts": 2},
+    }])
+    assert not res["timed_out"]
+    assert res["exit_code"] == 0
+    attempt, = res["attempts"]
+    assert attempt["timed_out"]
+
+
+def test_retry_delay(tmp_path):
+    counter = tmp_path / "counter"
+    start = time.monotonic()
+    res, = ir.run([{
+        "argv": flaky(counter, 2),
+        "retry": {"attempts": 3, "delay": 0.2, "backoff": 2},
+    }])
+    elapsed = time.monotonic() - start
+    assert res["exit_code"] == 0
+    # Delays of 0.2 s and 0.4 s.
+    assert elapsed > 0.6
@@ -0,0 +1,62 @@
+import ir
+import json
+import subprocess
+
+#-------------------------------------------------------------------------------
+
+YAML = """
+# Say hello.
+procs:
+  - name: hello
+    argv: [/bin/echo, hello]
+    fds:
+      - [stdout, {capture: {}}]
+"""
+
+TOML = """
+# Say hello.
+[[procs]]
+name = "hello"
+argv = ["/bin/echo", "hello"]
+fds = [["stdout", {capture = {}}]]
+"""
+
+def run_file(path, *args):
+    return subprocess.run(
+        [str(ir.IR_EXE), *args, str(path)],
+        stdout=subprocess.PIPE, stderr=subprocess.PIPE, text=True,
+    )
+
+
+def check_hello(res):
+    assert res.returncode == 0
+    proc, = json.loads(res.stdout)["procs"]
+    assert proc["fds"]["stdout"]["text"] == "hello\n"
+
+
+def test_yaml(tmp_path):
+    path = tmp_path / "spec.yaml"
+    path.write_text(YAML)
+    check_hello(run_file(path))
+
+
+def test_toml(tmp_path):
+    path = tmp_path / "spec.toml"
+    path.write_text(TOML)
+    check_hello(run_file(path))
+
+
+def test_spec_format_arg(tmp_path):
+    path = tmp_path / "spec.txt"
+    path.write_text(YAML)
+    check_hello(run_file(path, "--spec-format", "yaml"))
+    path.write_text(TOML)
+    check_hello(run_file(path, "--spec-format", "toml"))
+
+
+def test_error_position(tmp_path):
+    path = tmp_path / "spec.yaml"
+    path.write_text(YAML + "    argh: 1\n")
+    res = run_file(path)
+    assert res.returncode != 0
+    assert "line 8 column 5" in res.stderr
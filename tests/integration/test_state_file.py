@@ -0,0 +1,81 @@
+import ir
+import json
+import subprocess
+import time
+
+#-------------------------------------------------------------------------------
+
+def read_state(path, pred, timeout=5):
+    """
+    Polls the state file at `path` until `pred` is true of it.
+    """
+    deadline = time.monotonic() + timeout
+    while True:
+        try:
+            state = json.loads(path.read_text())
+        except FileNotFoundError:
+            state = None
+        if state is not None and pred(state):
+            return state
+        assert time.monotonic() < deadline, f"timed out; last state: {state}"
+        time.sleep(0.05)
+
+
+def test_state_file(tmp_path):
+    state_path = tmp_path / "state.json"
+    specs = [
+        {
+            "name": "talker",
+            "argv": ["/bin/sh", "-c", "echo hello; sleep 1"],
+            "fds": [["stdout", {"capture": {}}]],
+        },
+        {
+            "name": "quick",
+            "argv": ["/bin/true"],
+        },
+    ]
+    with ir.spec_file(specs) as spec_path:
+        with subprocess.Popen(
+            [str(ir.IR_EXE), "--state-file", str(state_path),
+             "--state-interval", "0.1", spec_path],
+            stdout=subprocess.PIPE,
+            stderr=subprocess.DEVNULL,
+        ) as proc:
+            # While the talker is sleeping, it has captured its output.
+            state = read_state(
+                state_path,
+                lambda s: s["procs"][0]["captured"].get("stdout") == 6
+                      and s["procs"][1]["phase"] == "finished",
+            )
+            assert state["pid"] == proc.pid
+            assert not state["done"]
+            talker, quick = state["procs"]
+            assert talker["name"] == "talker"
+            assert talker["phase"] == "running"
+            assert talker["pid"] > 0
+            assert quick["exit_code"] == 0
+            assert quick["result"] == "terminated"
+
+            proc.communicate()
+            assert proc.returncode == 0
+
+    state = json.loads(state_path.read_text())
+    assert state["done"]
+    for p in state["procs"]:
+        assert p["phase"] == "finished"
+        assert p["exit_code"] == 0
+    assert state["procs"][0]["captured"] == {"stdout": 6}
+    # The temporary file was renamed over the state file.
+    assert list(tmp_path.iterdir()) == [state_path]
+
+
+def test_bad_state_file(tmp_path):
+    with ir.spec_file([{"argv": ["/bin/true"]}]) as spec_path:
+        res = subprocess.run(
+            [str(ir.IR_EXE), "--state-file", str(tmp_path / "no" / "state.json"),
+             spec_path],
+            stdout=subprocess.PIPE,
+            stderr=subprocess.PIPE,
+        )
+    assert res.returncode != 0
+    assert b"state file" in res.stderr
@@ -0,0 +1,56 @@
+import ir
+import subprocess
+
+#-------------------------------------------------------------------------------
+
+def stdout(res):
+    return res["fds"]["stdout"]["text"]
+
+
+def test_defaults():
+    res0, res1 = ir.run(
+        [
+            {"argv": ["/bin/sh", "-c", "echo $A $B"]},
+            {
+                "argv": ["/bin/sh", "-c", "echo $A $B >&2"],
+                "env": {"vars": {"B": "3"}},
+                "fds": [["stderr", {"capture": {}}]],
+            },
+        ],
+        defaults={
+            "env": {"vars": {"A": "1", "B": "2"}},
+            "fds": [["stdout", {"capture": {}}]],
+        },
+    )
+    assert stdout(res0) == "1 2\n"
+    assert stdout(res1) == ""
+    assert res1["fds"]["stderr"]["text"] == "1 3\n"
+
+
+def test_extends():
+    res0, res1 = ir.run(
+        [
+            {"extends": "echo", "args": ["world"]},
+            {"extends": ["echo", "upper"], "args": ["there"]},
+        ],
+        templates={
+            "echo": {
+                "argv": ["/bin/echo", "hello"],
+                "fds": [["stdout", {"capture": {}}]],
+            },
+            "upper": {
+                "argv": ["/bin/sh", "-c", "echo HELLO $0"],
+            },
+        },
+    )
+    assert stdout(res0) == "hello world\n"
+    assert stdout(res1) == "HELLO there\n"
+
+
+def test_unknown_template():
+    with ir.spec_file([{"extends": "nope"}]) as path:
+        res = subprocess.run(
+            [str(ir.IR_EXE), path], stdout=subprocess.PIPE, stderr=subprocess.PIPE,
+            text=True)
+    assert res.returncode != 0
+    assert "unknown template: nope" in res.stderr
@@ -0,0 +1,70 @@
+import ir
+import json
+import os
+import subprocess
+
+#-------------------------------------------------------------------------------
+
+def echo(*args, **kw_args):
+    return {
+        "argv": ["/bin/echo", *args],
+        "fds": [["stdout", {"capture": {}}]],
+        **kw_args
+    }
+
+
+def stdout(res):
+    return res["fds"]["stdout"]["text"]
+
+
+def test_vars():
+    res, = ir.run(
+        [echo("${greeting}, ${who}!")],
+        vars={"greeting": "hello", "who": "${env.PATH}"},
+    )
+    assert stdout(res) == f"hello, {os.environ['PATH']}!\n"
+
+
+def test_builtins(tmp_path):
+    specs = [
+        {
+            "name": "first",
+            "argv": ["/bin/echo", "${proc.name} ${proc.index}"],
+            "fds": [["stdout", {"file": {"path": "${dir}/${proc.name}.out"}}]],
+        },
+        {
+            "argv": ["/bin/sh", "-c", "echo ${proc.name} ${proc.index} $RUN_ID; pwd"],
+            "cwd": "${dir}",
+            "env": {"vars": {"RUN_ID": "${run.id}"}},
+            "fds": [["stdout", {"file": {"path": "${dir}/${run.id}.out"}}]],
+        },
+    ]
+    with ir.spec_file(specs, vars={"dir": str(tmp_path)}) as path:
+        res = subprocess.run([str(ir.IR_EXE), path], stdout=subprocess.PIPE)
+    run_id = json.loads(res.stdout)["run_id"]
+
+    assert (tmp_path / "first.out").read_text() == "first 0\n"
+    assert (tmp_path / f"{run_id}.out").read_text() == f"1 1 {run_id}\n{tmp_path}\n"
+
+
+def test_escape():
+    res, = ir.run([echo("$${x} $HOME $$")])
+    assert stdout(res) == "${x} $HOME $$\n"
+
+
+def test_define():
+    res, = ir.run(
+        [echo("${x} ${y}")],
+        args=["-D", "x=1", "-D", "y=a=b"],
+        vars={"x": "0"},
+    )
+    assert stdout(res) == "1 a=b\n"
+
+
+def test_undefined():
+    with ir.spec_file([echo("${nope}")]) as path:
+        res = subprocess.run(
+            [str(ir.IR_EXE), path], stdout=subprocess.PIPE, stderr=subprocess.PIPE,
+            text=True)
+    assert res.returncode != 0
+    assert "undefined variable: nope" in res.stderr